@@ -0,0 +1,15 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The application starts after us - see `neotron-bmc-pico/memory.x` */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 2K
+  /* We only use the RAM the application keeps for its vector table, so we
+   * don't clobber anything it wants to keep over a reset (e.g. a crash
+   * record). */
+  RAM : ORIGIN = 0x20000000, LENGTH = 0xC0
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
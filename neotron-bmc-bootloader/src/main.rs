@@ -0,0 +1,248 @@
+//! # Neotron BMC Bootloader
+//!
+//! Lives in the first 2 KiB of flash, and runs before the BMC firmware. If
+//! the firmware has left a good image in the staging area, we copy it over
+//! the application, then erase the staging area so we only do it once.
+//! Either way, we then start the application. If that's not possible (the
+//! install failed, or there's no application), we start the ROM bootloader
+//! instead, so the BMC can be reflashed over the UART.
+//!
+//! This has to fit in 2 KiB, so we don't use the HAL or `defmt`, and we talk
+//! to the flash controller directly.
+
+#![no_main]
+#![no_std]
+
+use neotron_bmc_protocol::image;
+
+/// Where the application lives. Must match `neotron-bmc-pico/memory.x`.
+const APP_START: usize = 0x0800_0800;
+/// How big the application can be
+const APP_SIZE: usize = 14 * 1024;
+/// Where new images are received. Must match `neotron-bmc-pico/memory.x`.
+const STAGING_START: usize = 0x0800_4000;
+/// How big the staging area is (header included)
+const STAGING_SIZE: usize = 14 * 1024;
+/// The STM32F030x6 has 1 KiB flash pages
+const PAGE_SIZE: usize = 1024;
+/// Where the RAM is, so we can check the application's stack pointer
+const RAM_START: u32 = 0x2000_0000;
+/// The STM32F030x6 has 4 KiB of RAM
+const RAM_SIZE: u32 = 4 * 1024;
+/// How many times we try to install an image before giving up
+const INSTALL_ATTEMPTS: usize = 3;
+
+/// The flash controller registers
+mod flash {
+	const BASE: usize = 0x4002_2000;
+	pub const KEYR: *mut u32 = (BASE + 0x04) as *mut u32;
+	pub const SR: *mut u32 = (BASE + 0x0C) as *mut u32;
+	pub const CR: *mut u32 = (BASE + 0x10) as *mut u32;
+	pub const AR: *mut u32 = (BASE + 0x14) as *mut u32;
+
+	pub const KEY1: u32 = 0x4567_0123;
+	pub const KEY2: u32 = 0xCDEF_89AB;
+
+	pub const SR_BSY: u32 = 1 << 0;
+	pub const SR_PGERR: u32 = 1 << 2;
+	pub const SR_WRPRTERR: u32 = 1 << 4;
+	pub const SR_EOP: u32 = 1 << 5;
+
+	pub const CR_PG: u32 = 1 << 0;
+	pub const CR_PER: u32 = 1 << 1;
+	pub const CR_STRT: u32 = 1 << 6;
+	pub const CR_LOCK: u32 = 1 << 7;
+}
+
+/// Where the ROM bootloader lives on an STM32F030x6 (see ST's AN2606)
+const SYSTEM_MEMORY: usize = 0x1FFF_EC00;
+/// The RCC's APB2 Peripheral Clock Enable Register
+const RCC_APB2ENR: *mut u32 = 0x4002_1018 as *mut u32;
+/// Turns on the SYSCFG clock, in [`RCC_APB2ENR`]
+const RCC_APB2ENR_SYSCFGEN: u32 = 1 << 0;
+/// The SYSCFG Configuration Register 1
+const SYSCFG_CFGR1: *mut u32 = 0x4001_0000 as *mut u32;
+/// The memory mapped at address zero, in [`SYSCFG_CFGR1`]
+const SYSCFG_CFGR1_MEM_MODE: u32 = 0b11;
+/// Maps the System Memory (the ROM bootloader) at address zero
+const SYSCFG_CFGR1_MEM_MODE_SYSTEM: u32 = 0b01;
+
+/// The Independent Watchdog's Key Register
+const IWDG_KR: *mut u32 = 0x4000_3000 as *mut u32;
+/// Written to [`IWDG_KR`] to feed the watchdog
+const IWDG_FEED: u32 = 0xAAAA;
+
+/// Something went wrong writing to flash
+struct FlashError;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+	// The application may have left the watchdog running
+	feed_watchdog();
+
+	// Safety: this is the staging area, and only we are running
+	let staging = unsafe { read(STAGING_START, STAGING_SIZE) };
+	let mut installed = true;
+	if let Ok(header) = image::verify(staging) {
+		installed = false;
+		for _ in 0..INSTALL_ATTEMPTS {
+			feed_watchdog();
+			if install(&header).is_ok() {
+				// Don't install it again next time
+				let _ = erase_page(STAGING_START);
+				installed = true;
+				break;
+			}
+		}
+	}
+
+	// A failed install may have left a half-written application, which
+	// could still have a sensible vector table. The image is still in the
+	// staging area, so we try again after the next reset.
+	if installed && app_is_valid() {
+		// Safety: the application's vector table looks sensible
+		unsafe { cortex_m::asm::bootload(APP_START as *const u32) }
+	}
+
+	// Nothing safe to run - let the ROM bootloader reflash us
+	enter_rom_bootloader()
+}
+
+/// Start the ROM bootloader, which can reflash the BMC over USART1 (with a
+/// tool like `stm32flash`). It keeps the watchdog fed.
+fn enter_rom_bootloader() -> ! {
+	// Safety: we have only touched the flash controller and the watchdog,
+	// so the chip is as the ROM bootloader expects it. It also expects to
+	// be mapped at address zero.
+	unsafe {
+		RCC_APB2ENR.write_volatile(RCC_APB2ENR.read_volatile() | RCC_APB2ENR_SYSCFGEN);
+		let cfgr1 = SYSCFG_CFGR1.read_volatile() & !SYSCFG_CFGR1_MEM_MODE;
+		SYSCFG_CFGR1.write_volatile(cfgr1 | SYSCFG_CFGR1_MEM_MODE_SYSTEM);
+		cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32)
+	}
+}
+
+/// Copy the image in the staging area over the application, and check it.
+fn install(header: &image::Header) -> Result<(), FlashError> {
+	let length = header.length as usize;
+	if length > APP_SIZE {
+		return Err(FlashError);
+	}
+	for page in (APP_START..APP_START + length).step_by(PAGE_SIZE) {
+		feed_watchdog();
+		erase_page(page)?;
+	}
+	unlock();
+	// Safety: the flash is unlocked
+	unsafe { flash::CR.write_volatile(flash::CR_PG) };
+	let mut result = Ok(());
+	for offset in (0..length).step_by(2) {
+		let src = STAGING_START + image::HEADER_LEN + offset;
+		// Safety: this is in the staging area, and we checked the length
+		let low = unsafe { (src as *const u8).read_volatile() };
+		// Pad the last byte, as erased flash would be
+		let high = if offset + 1 < length {
+			unsafe { ((src + 1) as *const u8).read_volatile() }
+		} else {
+			0xFF
+		};
+		// Safety: this is in the application area, which we just erased, and
+		// the flash controller is in programming mode
+		unsafe {
+			((APP_START + offset) as *mut u16).write_volatile(u16::from_le_bytes([low, high]))
+		};
+		result = wait();
+		if result.is_err() {
+			break;
+		}
+	}
+	// Safety: turn off programming mode, and re-lock the flash
+	unsafe { flash::CR.write_volatile(flash::CR_LOCK) };
+	result?;
+	// Safety: we just wrote this
+	let installed = unsafe { read(APP_START, length) };
+	if image::crc32(installed) != header.crc {
+		return Err(FlashError);
+	}
+	Ok(())
+}
+
+/// Does the application's vector table look sensible?
+fn app_is_valid() -> bool {
+	// Safety: this is the start of the application area
+	let (sp, reset) = unsafe {
+		let table = APP_START as *const u32;
+		(table.read_volatile(), table.add(1).read_volatile())
+	};
+	let sp_ok = (RAM_START..=RAM_START + RAM_SIZE).contains(&sp);
+	let reset_ok = (APP_START as u32..(APP_START + APP_SIZE) as u32).contains(&reset);
+	sp_ok && reset_ok
+}
+
+/// Get some flash, as a slice.
+///
+/// # Safety
+///
+/// The given range must be in flash.
+unsafe fn read(addr: usize, len: usize) -> &'static [u8] {
+	core::slice::from_raw_parts(addr as *const u8, len)
+}
+
+/// Erase the page starting at the given address.
+fn erase_page(addr: usize) -> Result<(), FlashError> {
+	unlock();
+	// Safety: the flash is unlocked, and we're not running from this page
+	unsafe {
+		flash::CR.write_volatile(flash::CR_PER);
+		flash::AR.write_volatile(addr as u32);
+		flash::CR.write_volatile(flash::CR_PER | flash::CR_STRT);
+	}
+	let result = wait();
+	// Safety: turn off erase mode, and re-lock the flash
+	unsafe { flash::CR.write_volatile(flash::CR_LOCK) };
+	result
+}
+
+/// Unlock the flash controller.
+fn unlock() {
+	// Safety: this is the documented unlock sequence
+	unsafe {
+		if (flash::CR.read_volatile() & flash::CR_LOCK) != 0 {
+			flash::KEYR.write_volatile(flash::KEY1);
+			flash::KEYR.write_volatile(flash::KEY2);
+		}
+	}
+}
+
+/// Wait for the flash controller to finish, and check how it went.
+fn wait() -> Result<(), FlashError> {
+	// Safety: reading and clearing the flash status is harmless
+	let sr = unsafe {
+		while (flash::SR.read_volatile() & flash::SR_BSY) != 0 {}
+		let sr = flash::SR.read_volatile();
+		// These flags are cleared by writing a 1
+		flash::SR.write_volatile(flash::SR_EOP | flash::SR_PGERR | flash::SR_WRPRTERR);
+		sr
+	};
+	if (sr & (flash::SR_PGERR | flash::SR_WRPRTERR)) != 0 {
+		Err(FlashError)
+	} else {
+		Ok(())
+	}
+}
+
+/// Stop the Independent Watchdog from resetting us, if it's running.
+fn feed_watchdog() {
+	// Safety: writing the feed key does nothing else
+	unsafe { IWDG_KR.write_volatile(IWDG_FEED) };
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+	// Let the watchdog (if running) reset us, and try again
+	loop {
+		cortex_m::asm::nop();
+	}
+}
+
+// End of file
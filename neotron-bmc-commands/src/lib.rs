@@ -2,7 +2,7 @@
 //!
 //! Definitions of all the commands supported by the BMC.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 #[derive(Debug, Copy, Clone, num_enum::IntoPrimitive, num_enum::TryFromPrimitive)]
 #[repr(u8)]
@@ -53,10 +53,45 @@ pub enum Command {
 	/// * Mode: RO
 	SystemVoltage55 = 0x24,
 	/// # Power Control
-	/// Enable/disable the power supply
+	/// Turn the power supply off, or reset the system
 	/// * Length: 1
 	/// * Mode: R/W
 	PowerControl = 0x25,
+	/// # Power Policy
+	/// When the BMC turns the power on by itself
+	/// * Length: 1
+	/// * Mode: R/W
+	PowerPolicy = 0x26,
+	/// # Wake Key
+	/// The PS/2 Scan Code Set 2 make code which wakes the system
+	/// * Length: 1
+	/// * Mode: R/W
+	WakeKey = 0x27,
+	/// # Host Reset Reason
+	/// Why the system was last started, and last stopped
+	/// * Length: 2
+	/// * Mode: RO
+	HostResetReason = 0x28,
+	/// # BMC Reset Reason
+	/// Why the BMC itself last reset, as a bitmask
+	/// * Length: 1
+	/// * Mode: RO
+	BmcResetReason = 0x29,
+	/// # BMC Crash Record
+	/// Where the BMC firmware crashed, before it last reset
+	/// * Length: 12
+	/// * Mode: RO
+	BmcCrashRecord = 0x2A,
+	/// # Config Control
+	/// Save the current settings, or go back to the factory defaults
+	/// * Length: 1
+	/// * Mode: R/W
+	ConfigControl = 0x2B,
+	/// # Config Version
+	/// How many times the settings have been saved, as a `u16le`
+	/// * Length: 2
+	/// * Mode: RO
+	ConfigVersion = 0x2C,
 	/// # UART Receive/Transmit Buffer
 	/// Data received/to be sent over the UART
 	/// * Length: up to 64
@@ -82,6 +117,16 @@ pub enum Command {
 	/// * Length: 4
 	/// * Mode: R/W
 	UartBaudRate = 0x34,
+	/// # UART History Control
+	/// Selects which UART History to read, and how much is left to read
+	/// * Length: 1
+	/// * Mode: R/W
+	UartHistoryControl = 0x35,
+	/// # UART History
+	/// What the host recently sent over the UART
+	/// * Length: up to 64
+	/// * Mode: FIFO
+	UartHistory = 0x36,
 	/// # PS/2 Keyboard Receive/Transmit Buffer
 	/// Data received/to be sent over the PS/2 keyboard port
 	/// * Length: up to 16
@@ -97,6 +142,11 @@ pub enum Command {
 	/// * Length: 1
 	/// * Mode: R/W1C
 	Ps2KbStatus = 0x42,
+	/// # PS/2 Keyboard Shortcuts
+	/// Which keyboard shortcuts the BMC acts upon, as a bitmask
+	/// * Length: 1
+	/// * Mode: R/W
+	Ps2KbShortcuts = 0x43,
 	/// # PS/2 Mouse Receive/Transmit Buffer
 	/// Data received/to be sent over the PS/2 Mouse port
 	/// * Length: up to 16
@@ -118,13 +168,15 @@ pub enum Command {
 	/// * Mode: FIFO
 	I2cBuffer = 0x60,
 	/// # I²C FIFO Control
-	/// Settings for the I²C FIFO
+	/// Read for the number of bytes waiting to be written. Write bit 0 to
+	/// empty the TX FIFO and bit 1 to empty the RX FIFO.
 	/// * Length: 1
 	/// * Mode: R/W
 	I2cFifoControl = 0x61,
 	/// # I²C Control
-	/// Settings for the I²C Bus
-	/// * Length: 1
+	/// The target address, the operation and the number of bytes to read.
+	/// Writing this starts a transfer.
+	/// * Length: 3
 	/// * Mode: R/W
 	I2cControl = 0x62,
 	/// # I²C Status
@@ -138,23 +190,419 @@ pub enum Command {
 	/// * Mode: R/W
 	I2cBaudRate = 0x64,
 	/// # Speaker Duration
-	/// Duration of note, in milliseconds
-	/// * Length: 1
+	/// Duration of note, in milliseconds, as a `u16le`
+	/// * Length: 2
 	/// * Mode: R/W
 	SpeakerDuration = 0x70,
-	/// # Speaker Period (High byte)
-	/// High byte of period (in 48kHz ticks)
-	/// * Length: 1
-	/// * Mode: R/W
-	SpeakerPeriodHigh = 0x71,
-	/// # Speaker Period (High byte)
-	/// High byte of period (in 48kHz ticks)
-	/// * Length: 1
+	/// # Speaker Frequency
+	/// Frequency of note, in Hz, as a `u16le`
+	/// * Length: 2
 	/// * Mode: R/W
-	SpeakerPeriodLow = 0x72,
+	SpeakerFrequency = 0x71,
 	/// # Speaker Duty Cycle
 	/// Speaker Duty cycle, in 1/255
 	/// * Length: 1
 	/// * Mode: R/W
 	SpeakerDutyCycle = 0x73,
+	/// # Speaker Note Queue
+	/// Notes to play one after another, as `(frequency, duty cycle, duration)`
+	/// * Length: up to 60
+	/// * Mode: WO
+	SpeakerNoteQueue = 0x74,
+	/// # Speaker Status
+	/// Whether a note is playing, and whether more are queued
+	/// * Length: 1
+	/// * Mode: RO
+	SpeakerStatus = 0x75,
+	/// # RTC Date and Time
+	/// The current date and time, as `[year - 2000, month, day, hours, minutes, seconds]`
+	/// * Length: 6
+	/// * Mode: R/W
+	RtcDateTime = 0x80,
+	/// # RTC Alarm
+	/// When the alarm goes off, in the same format as [`Command::RtcDateTime`]
+	/// * Length: 6
+	/// * Mode: R/W
+	RtcAlarm = 0x81,
+	/// # RTC Alarm Control
+	/// What happens when the alarm goes off
+	/// * Length: 1
+	/// * Mode: R/W
+	RtcAlarmControl = 0x82,
+	/// # Host Watchdog Timeout
+	/// How long the host has between kicks, in seconds (0 = disabled)
+	/// * Length: 1
+	/// * Mode: R/W
+	HostWatchdogTimeout = 0x90,
+	/// # Host Watchdog Control
+	/// What happens when the host watchdog expires
+	/// * Length: 1
+	/// * Mode: R/W
+	HostWatchdogControl = 0x91,
+	/// # Host Watchdog Kick
+	/// Write any value to restart the host watchdog timer
+	/// * Length: 1
+	/// * Mode: WO
+	HostWatchdogKick = 0x92,
+	/// # Host Watchdog Reset Count
+	/// How many times the host watchdog has reset the system
+	/// * Length: 1
+	/// * Mode: RO
+	HostWatchdogResetCount = 0x93,
+	/// # Firmware Update Control
+	/// Start, verify or install a firmware update, and read its status
+	/// * Length: 1
+	/// * Mode: R/W
+	FirmwareUpdateControl = 0xA0,
+	/// # Firmware Update Data
+	/// The next chunk of the new firmware image
+	/// * Length: up to 64
+	/// * Mode: WO
+	FirmwareUpdateData = 0xA1,
+	/// # Firmware Update Progress
+	/// How many bytes of the image have been received, as `u32le`
+	/// * Length: 4
+	/// * Mode: RO
+	FirmwareUpdateProgress = 0xA2,
+	/// # ROM Bootloader Entry
+	/// Write the key `BOOT`, a byte at a time, to restart the BMC in the
+	/// STM32's ROM bootloader
+	/// * Length: 1
+	/// * Mode: WO
+	RomBootloaderEntry = 0xA3,
+	/// # Error Counters
+	/// How many times each kind of error has happened, as seven `u16le`s.
+	/// Write any value to clear them.
+	/// * Length: 14
+	/// * Mode: R/W
+	ErrorCounters = 0xB0,
+	/// # Statistics
+	/// The uptime in seconds, then thirteen counters, each as a `u32le`.
+	/// Write any value to clear the counters.
+	/// * Length: 56
+	/// * Mode: R/W
+	Statistics = 0xB1,
+	/// # Event Log
+	/// The number of entries waiting, then as many six-byte entries as fit
+	/// * Length: up to 64
+	/// * Mode: FIFO
+	EventLog = 0xB2,
+}
+
+/// The bits in the [`Command::InterruptStatus`] and
+/// [`Command::InterruptControl`] registers.
+pub mod interrupt {
+	/// PS/2 Keyboard RX Not Empty
+	pub const PS2_KB_RX_NOT_EMPTY: u16 = 1 << 0;
+	/// PS/2 Mouse RX Not Empty
+	pub const PS2_MOUSE_RX_NOT_EMPTY: u16 = 1 << 1;
+	/// I²C RX Not Empty
+	pub const I2C_RX_NOT_EMPTY: u16 = 1 << 2;
+	/// I²C TX Empty
+	pub const I2C_TX_EMPTY: u16 = 1 << 3;
+	/// UART RX Not Empty
+	pub const UART_RX_NOT_EMPTY: u16 = 1 << 4;
+	/// UART TX Empty
+	pub const UART_TX_EMPTY: u16 = 1 << 5;
+	/// Button State Change
+	pub const BUTTON_STATE_CHANGE: u16 = 1 << 6;
+	/// Voltage Alarm
+	pub const VOLTAGE_ALARM: u16 = 1 << 7;
+	/// RTC Alarm
+	pub const RTC_ALARM: u16 = 1 << 8;
+	/// Host Watchdog Expired
+	pub const HOST_WATCHDOG: u16 = 1 << 9;
+	/// I²C Transfer Complete
+	pub const I2C_COMPLETE: u16 = 1 << 10;
+	/// Speaker Melody Finished
+	pub const SPEAKER_DONE: u16 = 1 << 11;
+}
+
+/// The bits in the [`Command::PowerControl`] register.
+pub mod power_control {
+	/// The DC/DC supply is on. Writing 0 turns the system off.
+	pub const DC_ON: u8 = 1 << 0;
+	/// Write 1 (with [`DC_ON`]) to reset the system. Always reads as 0.
+	pub const RESET: u8 = 1 << 1;
+}
+
+/// The values of the [`Command::HostWatchdogControl`] register.
+pub mod host_watchdog {
+	/// Raise the Host Watchdog Expired interrupt
+	pub const INTERRUPT: u8 = 0;
+	/// Pulse the system reset line
+	pub const RESET: u8 = 1;
+	/// Turn the power off, then on again
+	pub const POWER_CYCLE: u8 = 2;
+}
+
+/// The values used by the [`Command::I2cControl`] and [`Command::I2cStatus`]
+/// registers.
+pub mod i2c {
+	/// How many bytes each I²C FIFO holds, and so the most one transfer can
+	/// write or read.
+	pub const FIFO_LEN: usize = 16;
+
+	/// The operations in byte 1 of [`Command::I2cControl`](crate::Command::I2cControl)
+	pub mod operation {
+		/// Write the TX FIFO to the target
+		pub const WRITE: u8 = 1;
+		/// Read from the target into the RX FIFO
+		pub const READ: u8 = 2;
+		/// Write the TX FIFO, then a repeated START, then read into the RX FIFO
+		pub const WRITE_READ: u8 = 3;
+		/// Probe every address, and put a bitmap of the targets that answer
+		/// into the RX FIFO
+		pub const SCAN: u8 = 4;
+	}
+
+	/// The bits in [`Command::I2cStatus`](crate::Command::I2cStatus)
+	pub mod status {
+		/// A transfer is waiting or running
+		pub const BUSY: u8 = 1 << 0;
+		/// The last transfer has finished (successfully or not)
+		pub const DONE: u8 = 1 << 1;
+		/// The target didn't acknowledge its address or a byte
+		pub const NACK: u8 = 1 << 2;
+		/// Another controller won the bus
+		pub const ARBITRATION_LOST: u8 = 1 << 3;
+		/// The transfer took too long
+		pub const TIMEOUT: u8 = 1 << 4;
+		/// A misplaced START or STOP was seen on the bus
+		pub const BUS_ERROR: u8 = 1 << 5;
+		/// SDA is held low, even after clocking the bus to free it
+		pub const BUS_STUCK: u8 = 1 << 6;
+		/// The bits the host can clear
+		pub const CLEARABLE: u8 = DONE | NACK | ARBITRATION_LOST | TIMEOUT | BUS_ERROR | BUS_STUCK;
+	}
+}
+
+/// The values used by the UART registers.
+pub mod uart {
+	/// How many bytes each UART FIFO holds.
+	pub const FIFO_LEN: usize = 64;
+
+	/// How many of the host's most recently sent bytes each UART History
+	/// holds.
+	pub const HISTORY_LEN: usize = 128;
+
+	/// The bits in [`Command::UartFifoControl`](crate::Command::UartFifoControl),
+	/// when writing it
+	pub mod fifo_control {
+		/// Empty the TX FIFO
+		pub const FLUSH_TX: u8 = 1 << 0;
+		/// Empty the RX FIFO
+		pub const FLUSH_RX: u8 = 1 << 1;
+	}
+
+	/// The bits in [`Command::UartControl`](crate::Command::UartControl)
+	pub mod control {
+		/// Pass bytes between the UART and the FIFOs
+		pub const ENABLE: u8 = 1 << 0;
+		/// Send and check an even parity bit
+		pub const PARITY_EVEN: u8 = 1 << 1;
+		/// Send and check an odd parity bit
+		pub const PARITY_ODD: u8 = 2 << 1;
+		/// The parity bits
+		pub const PARITY_MASK: u8 = 3 << 1;
+		/// Send two stop bits, instead of one
+		pub const TWO_STOP_BITS: u8 = 1 << 3;
+		/// The bits which mean something
+		pub const ALL: u8 = ENABLE | PARITY_MASK | TWO_STOP_BITS;
+	}
+
+	/// The bits in [`Command::UartStatus`](crate::Command::UartStatus)
+	pub mod status {
+		/// Bytes were dropped because the RX FIFO was full
+		pub const RX_OVERFLOW: u8 = 1 << 0;
+		/// Everything in the TX FIFO has been sent
+		pub const TX_EMPTY: u8 = 1 << 1;
+		/// The bits the host can clear
+		pub const CLEARABLE: u8 = RX_OVERFLOW;
+	}
+
+	/// The values written to
+	/// [`Command::UartHistoryControl`](crate::Command::UartHistoryControl)
+	pub mod history {
+		/// Read what the host has sent since it was last started
+		pub const CURRENT: u8 = 0;
+		/// Read what the host sent before it was last started
+		pub const PREVIOUS: u8 = 1;
+	}
+}
+
+/// The values used by the speaker registers, and how they become PWM
+/// settings.
+pub mod speaker {
+	/// The lowest frequency we play, in Hz
+	pub const MIN_FREQUENCY_HZ: u16 = 20;
+
+	/// The highest frequency we play, in Hz
+	pub const MAX_FREQUENCY_HZ: u16 = 20_000;
+
+	/// How many bytes each note takes in the queue
+	pub const NOTE_LEN: usize = 5;
+
+	/// How many notes can be waiting to play
+	pub const QUEUE_LEN: usize = 16;
+
+	/// The most notes one *Long Write* can carry
+	pub const MAX_WRITE_NOTES: usize = 12;
+
+	/// The bits in [`Command::SpeakerStatus`](crate::Command::SpeakerStatus)
+	pub mod status {
+		/// There are no notes waiting to play
+		pub const QUEUE_EMPTY: u8 = 1 << 0;
+		/// A note is playing
+		pub const PLAYING: u8 = 1 << 1;
+	}
+
+	/// Is this a frequency we can play? Zero is allowed too, and is silent.
+	pub fn is_valid_frequency(frequency_hz: u16) -> bool {
+		frequency_hz == 0 || (MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ).contains(&frequency_hz)
+	}
+
+	/// How to set up a 16-bit timer to play a note.
+	#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+	pub struct Pwm {
+		/// The timer counts at `timer_clock_hz / (prescaler + 1)`
+		pub prescaler: u16,
+		/// Counts per cycle of the note (so the auto-reload value is one less)
+		pub period: u16,
+		/// Counts per cycle that the output is high for
+		pub compare: u16,
+	}
+
+	impl Pwm {
+		/// Work out the timer settings for a note.
+		///
+		/// We use the smallest prescaler that lets the period fit in 16 bits,
+		/// so the frequency is as close as we can get. A duty cycle of 255 is
+		/// high for the whole period. Gives `None` if the frequency isn't one
+		/// we play (including zero), or the timer clock can't reach it.
+		pub fn new(timer_clock_hz: u32, frequency_hz: u16, duty_cycle: u8) -> Option<Pwm> {
+			if !(MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ).contains(&frequency_hz) {
+				return None;
+			}
+			let frequency_hz = u32::from(frequency_hz);
+			let counts = div_round(timer_clock_hz, frequency_hz);
+			let divider = counts.div_ceil(u32::from(u16::MAX));
+			let prescaler = u16::try_from(divider.checked_sub(1)?).ok()?;
+			let period = div_round(timer_clock_hz, divider * frequency_hz);
+			// Too few counts and the duty cycle means nothing
+			if period < 2 {
+				return None;
+			}
+			let period = u16::try_from(period).ok()?;
+			let compare = u32::from(period) * u32::from(duty_cycle) / 255;
+			Some(Pwm {
+				prescaler,
+				period,
+				compare: compare as u16,
+			})
+		}
+
+		/// The frequency these settings really play, in Hz.
+		pub fn frequency_hz(&self, timer_clock_hz: u32) -> f32 {
+			timer_clock_hz as f32
+				/ ((u32::from(self.prescaler) + 1) * u32::from(self.period)) as f32
+		}
+	}
+
+	/// Divide, rounding to the nearest whole number.
+	fn div_round(numerator: u32, denominator: u32) -> u32 {
+		(numerator + denominator / 2) / denominator
+	}
 }
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::speaker::*;
+
+	/// The clocks the NBMC might run TIM14 from: the PLL, and the HSI on its
+	/// own.
+	const CLOCKS: [u32; 2] = [48_000_000, 8_000_000];
+
+	#[test]
+	fn frequency_is_accurate() {
+		for clock in CLOCKS {
+			for frequency in MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ {
+				let pwm = Pwm::new(clock, frequency, 127).unwrap();
+				// The period is as close as it can be
+				let counts =
+					f64::from(clock) / (f64::from(pwm.prescaler) + 1.0) / f64::from(frequency);
+				assert!((counts - f64::from(pwm.period)).abs() <= 0.5, "{:?}", pwm);
+				if clock == 48_000_000 {
+					// Which from the PLL is very close indeed
+					let error = f64::from(pwm.frequency_hz(clock)) / f64::from(frequency) - 1.0;
+					assert!(error.abs() < 0.0005, "{} Hz is {:?}", frequency, pwm);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn known_notes() {
+		// A4, from the PLL: 48 MHz / 440 Hz doesn't fit in 16 bits
+		let pwm = Pwm::new(48_000_000, 440, 255).unwrap();
+		assert_eq!(
+			pwm,
+			Pwm {
+				prescaler: 1,
+				period: 54545,
+				compare: 54545
+			}
+		);
+		// 1 kHz fits without a prescaler
+		let pwm = Pwm::new(48_000_000, 1000, 127).unwrap();
+		assert_eq!(
+			pwm,
+			Pwm {
+				prescaler: 0,
+				period: 48000,
+				compare: 23905
+			}
+		);
+		// The lowest note needs the biggest prescaler
+		let pwm = Pwm::new(48_000_000, MIN_FREQUENCY_HZ, 0).unwrap();
+		assert_eq!(pwm.prescaler, 36);
+		assert_eq!(pwm.compare, 0);
+	}
+
+	#[test]
+	fn duty_cycle() {
+		for clock in CLOCKS {
+			for frequency in [MIN_FREQUENCY_HZ, 262, 440, 4186, MAX_FREQUENCY_HZ] {
+				let period = Pwm::new(clock, frequency, 0).unwrap().period;
+				for duty_cycle in 0..=255u8 {
+					let pwm = Pwm::new(clock, frequency, duty_cycle).unwrap();
+					assert_eq!(pwm.period, period);
+					let expected = f32::from(period) * f32::from(duty_cycle) / 255.0;
+					assert!((f32::from(pwm.compare) - expected).abs() < 1.0);
+				}
+				assert_eq!(Pwm::new(clock, frequency, 0).unwrap().compare, 0);
+				assert_eq!(Pwm::new(clock, frequency, 255).unwrap().compare, period);
+			}
+		}
+	}
+
+	#[test]
+	fn out_of_range() {
+		assert_eq!(Pwm::new(48_000_000, 0, 127), None);
+		assert_eq!(Pwm::new(48_000_000, MIN_FREQUENCY_HZ - 1, 127), None);
+		assert_eq!(Pwm::new(48_000_000, MAX_FREQUENCY_HZ + 1, 127), None);
+		// Too slow a clock for the highest notes
+		assert_eq!(Pwm::new(20_000, MAX_FREQUENCY_HZ, 127), None);
+		assert!(is_valid_frequency(0));
+		assert!(is_valid_frequency(440));
+		assert!(!is_valid_frequency(MAX_FREQUENCY_HZ + 1));
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,707 @@
+#![doc = include_str!("../README.md")]
+#![cfg_attr(not(test), no_std)]
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+use core::fmt::Write;
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use neotron_bmc_protocol::cobs;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The longest command line we accept
+pub const LINE_LEN: usize = 32;
+
+/// How many bytes of shell output we can hold before it is sent.
+pub const OUTPUT_LEN: usize = 160;
+
+/// The prompt we print in the shell
+pub const PROMPT: &str = "bmc> ";
+
+/// The longest frame we send or receive (before encoding) - a Response with
+/// 64 bytes of data
+pub const FRAME_LEN: usize = 66;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// Where received bytes are going
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Mode {
+	/// Received bytes go to the host
+	PassThrough,
+	/// Received bytes go to our shell
+	Shell,
+	/// Received bytes are protocol frames
+	Frames,
+}
+
+/// How far through the escape sequence we are
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Escape {
+	/// We're at the start of a line, so look out for a `~`
+	LineStart,
+	/// We've seen a `~` at the start of a line, and held it back
+	Tilde,
+	/// We're part way through a line
+	Normal,
+}
+
+/// What the caller should do with a received byte
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+	/// Nothing (yet)
+	None,
+	/// Pass these bytes to the host (the second is only there if the first
+	/// was a `~` we held back)
+	ToHost(u8, Option<u8>),
+	/// We've just switched to the shell
+	EnterShell,
+	/// We've just switched back to pass-through
+	LeaveShell,
+	/// A command line has been typed in the shell - see [`Console::line`]
+	Line,
+	/// A Request frame has arrived - see [`Console::frame`]
+	Request,
+	/// The Long Write Payload frame we asked for has arrived - see
+	/// [`Console::frame`]
+	Payload,
+	/// A frame arrived, but it couldn't be decoded
+	BadFrame(cobs::Error),
+}
+
+/// Splits the UART between the host and our shell.
+#[derive(Debug)]
+pub struct Console {
+	mode: Mode,
+	escape: Escape,
+	/// Is the host powered?
+	host_powered: bool,
+	/// Did we switch to the shell because the host was turned off?
+	auto_shell: bool,
+	/// The command line being typed
+	line: heapless::Vec<u8, LINE_LEN>,
+	/// Has the command line been finished (with Enter)?
+	line_done: bool,
+	/// Shell output, waiting to be sent
+	output: heapless::Deque<u8, OUTPUT_LEN>,
+	/// Collects frames, in frames mode
+	decoder: cobs::Decoder<{ cobs::max_encoded_len(FRAME_LEN) - 1 }>,
+	/// The length of the Long Write Payload we said OK to (not counting its
+	/// CRC)
+	expect_payload: Option<usize>,
+}
+
+// ============================================================================
+// Impls
+// ============================================================================
+
+impl Default for Console {
+	fn default() -> Self {
+		Console::new()
+	}
+}
+
+impl Console {
+	pub const fn new() -> Console {
+		Console {
+			mode: Mode::PassThrough,
+			escape: Escape::LineStart,
+			host_powered: true,
+			auto_shell: false,
+			line: heapless::Vec::new(),
+			line_done: false,
+			output: heapless::Deque::new(),
+			decoder: cobs::Decoder::new(),
+			expect_payload: None,
+		}
+	}
+
+	/// Where are received bytes going?
+	pub fn mode(&self) -> Mode {
+		self.mode
+	}
+
+	/// Handle a byte received on the UART.
+	pub fn handle_byte(&mut self, byte: u8) -> Action {
+		if self.mode == Mode::Frames {
+			return self.frame_byte(byte);
+		}
+		let at_line_start = self.escape == Escape::LineStart;
+		let after_tilde = self.escape == Escape::Tilde;
+		self.escape = if byte == b'\r' || byte == b'\n' {
+			Escape::LineStart
+		} else if at_line_start && byte == b'~' {
+			Escape::Tilde
+		} else {
+			Escape::Normal
+		};
+
+		if at_line_start && byte == b'~' {
+			// Hold it back, in case it's an escape
+			return Action::None;
+		}
+		if after_tilde && byte == b'.' {
+			self.escape = Escape::LineStart;
+			return self.toggle();
+		}
+
+		match self.mode {
+			Mode::PassThrough if after_tilde && byte != b'~' => Action::ToHost(b'~', Some(byte)),
+			Mode::PassThrough => Action::ToHost(byte, None),
+			Mode::Shell => self.edit(byte, after_tilde),
+			// Handled above
+			Mode::Frames => Action::None,
+		}
+	}
+
+	/// Handle a byte received in frames mode.
+	fn frame_byte(&mut self, byte: u8) -> Action {
+		// Only look for `~.` between frames. No frame we accept can start
+		// with a `~`, as the first byte says how far it is to the first zero.
+		if self.escape == Escape::Tilde {
+			self.escape = Escape::Normal;
+			if byte == b'.' {
+				return self.toggle();
+			}
+			let _ = self.decoder.push(b'~');
+		} else if byte == b'~' && self.decoder.is_empty() {
+			self.escape = Escape::Tilde;
+			return Action::None;
+		}
+		let frame_len = match self.decoder.push(byte) {
+			None => return Action::None,
+			Some(Ok(frame)) => frame.len(),
+			Some(Err(e)) => {
+				self.expect_payload = None;
+				return Action::BadFrame(e);
+			}
+		};
+		// Only the frame straight after our OK can be the payload. If it's
+		// the wrong length, the host must have given up on the Long Write.
+		let expected = self.expect_payload.take().map(|len| len + 1);
+		if frame_len == 0 {
+			Action::None
+		} else if expected == Some(frame_len) {
+			Action::Payload
+		} else {
+			Action::Request
+		}
+	}
+
+	/// Tell us whether the host is powered. We switch to the shell when it
+	/// goes off, and back when it comes on again.
+	pub fn set_host_powered(&mut self, powered: bool) -> Action {
+		if powered == self.host_powered {
+			return Action::None;
+		}
+		self.host_powered = powered;
+		match self.mode {
+			Mode::PassThrough if !powered => {
+				self.auto_shell = true;
+				self.toggle_mode()
+			}
+			Mode::Shell if powered && self.auto_shell => self.toggle(),
+			_ => Action::None,
+		}
+	}
+
+	/// Switch between the host and the shell, or from frames mode back to
+	/// the shell.
+	pub fn toggle(&mut self) -> Action {
+		self.auto_shell = false;
+		self.toggle_mode()
+	}
+
+	fn toggle_mode(&mut self) -> Action {
+		self.line.clear();
+		self.line_done = false;
+		match self.mode {
+			Mode::PassThrough => {
+				self.mode = Mode::Shell;
+				self.print("\r\nNeotron BMC shell - type 'help', or '~.' to leave\r\n");
+				self.print(PROMPT);
+				Action::EnterShell
+			}
+			Mode::Shell => {
+				self.mode = Mode::PassThrough;
+				self.print("\r\nBack to the host\r\n");
+				Action::LeaveShell
+			}
+			Mode::Frames => {
+				self.mode = Mode::Shell;
+				self.decoder.reset();
+				self.expect_payload = None;
+				self.print("\r\nBack to the shell\r\n");
+				self.print(PROMPT);
+				Action::EnterShell
+			}
+		}
+	}
+
+	/// Switch from the shell to frames mode.
+	pub fn enter_frames(&mut self) {
+		self.print("Frames mode - send '~.' between frames to leave\r\n");
+		// Ends the text above as a (bad) frame, so the host can skip it
+		self.print_byte(cobs::DELIMITER);
+		self.mode = Mode::Frames;
+		self.escape = Escape::Normal;
+		self.decoder.reset();
+		self.expect_payload = None;
+	}
+
+	/// Get the frame which was just received.
+	pub fn frame(&self) -> &[u8] {
+		self.decoder.frame()
+	}
+
+	/// Treat the next frame as a Long Write Payload, if it's `len` bytes
+	/// long (plus its CRC).
+	pub fn expect_payload(&mut self, len: usize) {
+		self.expect_payload = Some(len);
+	}
+
+	/// Encode and send a frame. If there's no room, it is lost (and we return
+	/// `false`), and the host will try again.
+	pub fn send_frame(&mut self, data: &[u8]) -> bool {
+		let mut buffer = [0u8; cobs::max_encoded_len(FRAME_LEN)];
+		match cobs::encode(data, &mut buffer) {
+			Ok(len) if len <= self.output_space() => {
+				for b in &buffer[0..len] {
+					self.print_byte(*b);
+				}
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Handle a byte typed into the shell.
+	fn edit(&mut self, byte: u8, after_tilde: bool) -> Action {
+		if after_tilde && byte != b'~' {
+			// The held back `~` was just a `~`
+			self.edit(b'~', false);
+		}
+		if self.line_done {
+			// Start a new line
+			self.line.clear();
+			self.line_done = false;
+		}
+		match byte {
+			b'\r' | b'\n' => {
+				self.print("\r\n");
+				if self.line.is_empty() {
+					self.print(PROMPT);
+					Action::None
+				} else {
+					self.line_done = true;
+					Action::Line
+				}
+			}
+			// Backspace or Delete
+			0x08 | 0x7F => {
+				if self.line.pop().is_some() {
+					self.print("\x08 \x08");
+				}
+				Action::None
+			}
+			b' '..=b'~' => {
+				if self.line.push(byte).is_ok() {
+					self.print_byte(byte);
+				}
+				Action::None
+			}
+			_ => Action::None,
+		}
+	}
+
+	/// Get the command line which was just typed.
+	pub fn line(&self) -> &str {
+		if !self.line_done {
+			return "";
+		}
+		// We only accept printable ASCII
+		core::str::from_utf8(&self.line).unwrap_or("")
+	}
+
+	/// Print the prompt, once a command has finished.
+	pub fn prompt(&mut self) {
+		if self.mode == Mode::Shell {
+			self.print(PROMPT);
+		}
+	}
+
+	/// Can the host's UART output go out? Not whilst we're in the shell, or
+	/// we have output of our own still to send.
+	pub fn host_can_send(&self) -> bool {
+		self.mode == Mode::PassThrough && self.output.is_empty()
+	}
+
+	/// How much room is there for more output?
+	pub fn output_space(&self) -> usize {
+		self.output.capacity() - self.output.len()
+	}
+
+	/// Take the next byte of output to send.
+	pub fn next_output(&mut self) -> Option<u8> {
+		self.output.pop_front()
+	}
+
+	/// Put the byte back, as it couldn't be sent.
+	pub fn unsend_output(&mut self, byte: u8) {
+		let _ = self.output.push_front(byte);
+	}
+
+	/// Print some text. If there's no room, some of it is lost.
+	pub fn print(&mut self, s: &str) {
+		let _ = self.write_str(s);
+	}
+
+	/// Print a byte, as it is. If there's no room, it is lost.
+	pub fn print_byte(&mut self, byte: u8) {
+		let _ = self.output.push_back(byte);
+	}
+}
+
+impl Write for Console {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		for b in s.bytes() {
+			self.output.push_back(b).map_err(|_| core::fmt::Error)?;
+		}
+		Ok(())
+	}
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Feed in some bytes, and collect what each one did.
+	fn type_bytes(console: &mut Console, bytes: &[u8]) -> Vec<Action> {
+		bytes.iter().map(|b| console.handle_byte(*b)).collect()
+	}
+
+	/// Take all the output waiting to be sent.
+	fn output(console: &mut Console) -> String {
+		let mut s = String::new();
+		while let Some(b) = console.next_output() {
+			s.push(char::from(b));
+		}
+		s
+	}
+
+	/// A console which has been switched to the shell (with no output
+	/// waiting).
+	fn shell() -> Console {
+		let mut console = Console::new();
+		assert_eq!(console.toggle(), Action::EnterShell);
+		output(&mut console);
+		console
+	}
+
+	/// A console in frames mode (with no output waiting).
+	fn frames() -> Console {
+		let mut console = shell();
+		console.enter_frames();
+		output(&mut console);
+		console
+	}
+
+	/// Encode a frame as the host would send it.
+	fn encode(data: &[u8]) -> Vec<u8> {
+		let mut buffer = [0u8; cobs::max_encoded_len(FRAME_LEN)];
+		let len = cobs::encode(data, &mut buffer).unwrap();
+		buffer[0..len].to_vec()
+	}
+
+	#[test]
+	fn pass_through() {
+		let mut console = Console::new();
+		assert_eq!(console.mode(), Mode::PassThrough);
+		assert_eq!(
+			type_bytes(&mut console, b"ls\r"),
+			[
+				Action::ToHost(b'l', None),
+				Action::ToHost(b's', None),
+				Action::ToHost(b'\r', None)
+			]
+		);
+		assert!(console.host_can_send());
+	}
+
+	#[test]
+	fn escape_enters_shell() {
+		let mut console = Console::new();
+		assert_eq!(
+			type_bytes(&mut console, b"~."),
+			[Action::None, Action::EnterShell]
+		);
+		assert_eq!(console.mode(), Mode::Shell);
+		assert!(!console.host_can_send());
+		assert!(output(&mut console).ends_with(PROMPT));
+	}
+
+	#[test]
+	fn escape_after_newline() {
+		let mut console = Console::new();
+		let actions = type_bytes(&mut console, b"a\n~.");
+		assert_eq!(actions[2], Action::None);
+		assert_eq!(actions[3], Action::EnterShell);
+		let mut console = Console::new();
+		let actions = type_bytes(&mut console, b"a\r~.");
+		assert_eq!(actions[3], Action::EnterShell);
+	}
+
+	#[test]
+	fn escape_only_at_line_start() {
+		let mut console = Console::new();
+		assert_eq!(
+			type_bytes(&mut console, b"a~."),
+			[
+				Action::ToHost(b'a', None),
+				Action::ToHost(b'~', None),
+				Action::ToHost(b'.', None)
+			]
+		);
+		assert_eq!(console.mode(), Mode::PassThrough);
+	}
+
+	#[test]
+	fn double_tilde_sends_one() {
+		let mut console = Console::new();
+		assert_eq!(
+			type_bytes(&mut console, b"~~."),
+			[
+				Action::None,
+				Action::ToHost(b'~', None),
+				Action::ToHost(b'.', None)
+			]
+		);
+		assert_eq!(console.mode(), Mode::PassThrough);
+	}
+
+	#[test]
+	fn tilde_then_other() {
+		let mut console = Console::new();
+		assert_eq!(
+			type_bytes(&mut console, b"~x~."),
+			[
+				Action::None,
+				Action::ToHost(b'~', Some(b'x')),
+				Action::ToHost(b'~', None),
+				Action::ToHost(b'.', None)
+			]
+		);
+		// A newline straight after the `~` starts a new line
+		let mut console = Console::new();
+		assert_eq!(
+			type_bytes(&mut console, b"~\r~."),
+			[
+				Action::None,
+				Action::ToHost(b'~', Some(b'\r')),
+				Action::None,
+				Action::EnterShell
+			]
+		);
+	}
+
+	#[test]
+	fn escape_leaves_shell() {
+		let mut console = shell();
+		assert_eq!(
+			type_bytes(&mut console, b"~."),
+			[Action::None, Action::LeaveShell]
+		);
+		assert_eq!(console.mode(), Mode::PassThrough);
+		output(&mut console);
+		assert!(console.host_can_send());
+	}
+
+	#[test]
+	fn shell_line() {
+		let mut console = shell();
+		assert_eq!(
+			type_bytes(&mut console, b"help\r"),
+			[
+				Action::None,
+				Action::None,
+				Action::None,
+				Action::None,
+				Action::Line
+			]
+		);
+		assert_eq!(console.line(), "help");
+		assert_eq!(output(&mut console), "help\r\n");
+		// The next byte starts a new line
+		type_bytes(&mut console, b"x");
+		assert_eq!(console.line(), "");
+	}
+
+	#[test]
+	fn shell_empty_line() {
+		let mut console = shell();
+		assert_eq!(console.handle_byte(b'\r'), Action::None);
+		assert_eq!(output(&mut console), format!("\r\n{}", PROMPT));
+	}
+
+	#[test]
+	fn shell_backspace() {
+		let mut console = shell();
+		type_bytes(&mut console, b"hx\x08elp\x7F\x7Fp\r");
+		assert_eq!(console.line(), "hep");
+		// Backspace on an empty line does nothing
+		let mut console = shell();
+		type_bytes(&mut console, b"\x08");
+		assert_eq!(output(&mut console), "");
+	}
+
+	#[test]
+	fn shell_tildes() {
+		let mut console = shell();
+		type_bytes(&mut console, b"~~a\r");
+		assert_eq!(console.line(), "~a");
+		type_bytes(&mut console, b"~a\r");
+		assert_eq!(console.line(), "~a");
+		type_bytes(&mut console, b"a~.\r");
+		assert_eq!(console.line(), "a~.");
+		assert_eq!(console.mode(), Mode::Shell);
+	}
+
+	#[test]
+	fn shell_line_too_long() {
+		let mut console = shell();
+		for _ in 0..LINE_LEN + 10 {
+			console.handle_byte(b'a');
+		}
+		console.handle_byte(b'\r');
+		assert_eq!(console.line().len(), LINE_LEN);
+	}
+
+	#[test]
+	fn host_power_switches_to_shell() {
+		let mut console = Console::new();
+		assert_eq!(console.set_host_powered(true), Action::None);
+		assert_eq!(console.set_host_powered(false), Action::EnterShell);
+		assert_eq!(console.mode(), Mode::Shell);
+		assert_eq!(console.set_host_powered(false), Action::None);
+		assert_eq!(console.set_host_powered(true), Action::LeaveShell);
+		assert_eq!(console.mode(), Mode::PassThrough);
+	}
+
+	#[test]
+	fn host_power_keeps_chosen_shell() {
+		// We asked for the shell, so stay there when the host comes back
+		let mut console = shell();
+		assert_eq!(console.set_host_powered(false), Action::None);
+		assert_eq!(console.set_host_powered(true), Action::None);
+		assert_eq!(console.mode(), Mode::Shell);
+		// Same if we only typed `~.` once the host was off
+		let mut console = Console::new();
+		console.set_host_powered(false);
+		type_bytes(&mut console, b"~.~.");
+		assert_eq!(console.mode(), Mode::Shell);
+		assert_eq!(console.set_host_powered(true), Action::None);
+		assert_eq!(console.mode(), Mode::Shell);
+	}
+
+	#[test]
+	fn frames_request() {
+		let mut console = frames();
+		assert_eq!(console.mode(), Mode::Frames);
+		let actions = type_bytes(&mut console, &encode(&[0xC0, 0x10, 0x00, 0x7E]));
+		assert_eq!(actions.last(), Some(&Action::Request));
+		assert_eq!(console.frame(), [0xC0, 0x10, 0x00, 0x7E]);
+	}
+
+	#[test]
+	fn frames_payload() {
+		let mut console = frames();
+		console.expect_payload(2);
+		// Payload plus CRC
+		let actions = type_bytes(&mut console, &encode(&[1, 2, 3]));
+		assert_eq!(actions.last(), Some(&Action::Payload));
+		// Only the next frame can be the payload
+		let actions = type_bytes(&mut console, &encode(&[1, 2, 3]));
+		assert_eq!(actions.last(), Some(&Action::Request));
+		// If it's the wrong length, it isn't the payload
+		console.expect_payload(2);
+		let actions = type_bytes(&mut console, &encode(&[1, 2, 3, 4]));
+		assert_eq!(actions.last(), Some(&Action::Request));
+	}
+
+	#[test]
+	fn frames_bad() {
+		let mut console = frames();
+		assert_eq!(
+			type_bytes(&mut console, &[0x05, 0x01, 0x00]),
+			[
+				Action::None,
+				Action::None,
+				Action::BadFrame(cobs::Error::Malformed)
+			]
+		);
+		// An empty frame is ignored
+		assert_eq!(console.handle_byte(cobs::DELIMITER), Action::None);
+	}
+
+	#[test]
+	fn frames_escape() {
+		let mut console = frames();
+		assert_eq!(
+			type_bytes(&mut console, b"~."),
+			[Action::None, Action::EnterShell]
+		);
+		assert_eq!(console.mode(), Mode::Shell);
+		// Part way through a frame, `~.` is just data
+		let mut console = frames();
+		let frame = encode(&[0x7E, 0x2E]);
+		let mut actions = type_bytes(&mut console, &frame[0..1]);
+		actions.extend(type_bytes(&mut console, b"~."));
+		assert_eq!(actions, [Action::None, Action::None, Action::None]);
+		assert_eq!(console.mode(), Mode::Frames);
+		assert_eq!(console.handle_byte(cobs::DELIMITER), Action::Request);
+		assert_eq!(console.frame(), [0x7E, 0x2E]);
+	}
+
+	#[test]
+	fn frames_tilde_without_dot() {
+		// A `~` between frames which isn't followed by `.` is kept
+		let mut console = frames();
+		assert_eq!(console.handle_byte(b'~'), Action::None);
+		assert_eq!(
+			console.handle_byte(cobs::DELIMITER),
+			Action::BadFrame(cobs::Error::Malformed)
+		);
+		assert_eq!(console.mode(), Mode::Frames);
+	}
+
+	#[test]
+	fn send_frame() {
+		let mut console = frames();
+		assert!(console.send_frame(&[1, 0, 2]));
+		let sent: Vec<u8> = core::iter::from_fn(|| console.next_output()).collect();
+		assert_eq!(sent, encode(&[1, 0, 2]));
+		// If there's no room, nothing is sent
+		while console.output_space() > 4 {
+			console.print_byte(b'x');
+		}
+		assert!(!console.send_frame(&[1, 2, 3, 4, 5]));
+		assert_eq!(console.output_space(), 4);
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
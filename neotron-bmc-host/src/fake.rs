@@ -0,0 +1,495 @@
+//! A pretend NBMC, for testing.
+//!
+//! It behaves like the real firmware as far as the protocol goes, but its
+//! registers are just bytes.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use neotron_bmc_commands::{i2c, interrupt, uart, Command};
+use neotron_bmc_protocol::{self as proto, Receivable, Sendable};
+
+use crate::Transport;
+
+/// A pretend NBMC, which you talk to through its [`Transport`]
+/// implementation.
+#[derive(Debug, Default)]
+pub struct FakeBmc {
+	/// What the registers read as (or were last given by a Long Write)
+	registers: HashMap<u8, Vec<u8>>,
+	/// Every Short Write, by register
+	writes: HashMap<u8, Vec<u8>>,
+	/// Registers which give *Bad Length* to any Short Write
+	rejected: HashSet<u8>,
+	/// How many more responses to corrupt
+	corrupt: usize,
+	/// How many responses to leave alone before corrupting any
+	corrupt_after: usize,
+	/// Every request we've had
+	requests: Vec<proto::Request>,
+	/// The Long Write we're expecting a payload for
+	long_write: Option<proto::Request>,
+	/// The last read, and what we sent back, so we can spot retries
+	last_read: Option<(proto::Request, Vec<u8>)>,
+	/// The last write (with any Long Write payload), and what we said, so we
+	/// can spot retries
+	last_write: Option<(proto::Request, Vec<u8>, proto::ResponseResult)>,
+	/// Bytes waiting to be received by the host
+	response: VecDeque<u8>,
+	/// The I²C targets, by address
+	i2c_targets: HashMap<u8, FakeTarget>,
+	/// The I²C TX FIFO
+	i2c_tx: Vec<u8>,
+	/// The I²C RX FIFO
+	i2c_rx: VecDeque<u8>,
+	/// The I²C Status register
+	i2c_status: u8,
+	/// How many status reads show each transfer as busy
+	i2c_busy_polls: usize,
+	/// How many more status reads show the current transfer as busy
+	i2c_busy_left: usize,
+	/// Status bits to report instead of running a transfer
+	i2c_fault: u8,
+	/// Every I²C transfer: the address, the operation and what was written
+	i2c_transfers: Vec<(u8, u8, Vec<u8>)>,
+	/// The UART RX FIFO
+	uart_rx: VecDeque<u8>,
+	/// Bytes which arrive on the UART after some interrupt status reads
+	uart_rx_later: Option<(Vec<u8>, usize)>,
+	/// The UART TX FIFO
+	uart_tx: Vec<u8>,
+	/// Everything that has left the UART TX FIFO
+	uart_sent: Vec<u8>,
+	/// The UART histories (current and previous)
+	uart_history: [VecDeque<u8>; 2],
+	/// Which UART history is being read, and how far through it we are
+	uart_history_read: (usize, usize),
+}
+
+/// A pretend I²C target, like a small EEPROM. The first byte written sets
+/// the address in its memory, and it carries on from there.
+#[derive(Debug)]
+struct FakeTarget {
+	memory: [u8; 256],
+	pointer: u8,
+}
+
+impl FakeBmc {
+	pub fn new() -> FakeBmc {
+		FakeBmc::default()
+	}
+
+	/// Set what a register reads as.
+	pub fn set_register(&mut self, command: Command, data: &[u8]) {
+		self.registers.insert(command.into(), data.to_vec());
+	}
+
+	/// Get what a register reads as.
+	pub fn register(&self, command: Command) -> &[u8] {
+		self.registers
+			.get(&command.into())
+			.map(|v| v.as_slice())
+			.unwrap_or(&[])
+	}
+
+	/// Get every Short Write made to a register.
+	pub fn writes(&self, command: Command) -> &[u8] {
+		self.writes
+			.get(&command.into())
+			.map(|v| v.as_slice())
+			.unwrap_or(&[])
+	}
+
+	/// Make Short Writes to a register fail.
+	pub fn reject_writes(&mut self, command: Command) {
+		self.rejected.insert(command.into());
+	}
+
+	/// Corrupt the next few responses.
+	pub fn corrupt_responses(&mut self, count: usize) {
+		self.corrupt_responses_after(0, count);
+	}
+
+	/// Corrupt a few responses, after leaving some alone.
+	pub fn corrupt_responses_after(&mut self, skip: usize, count: usize) {
+		self.corrupt_after = skip;
+		self.corrupt = count;
+	}
+
+	/// Get every request we've had.
+	pub fn requests(&self) -> &[proto::Request] {
+		&self.requests
+	}
+
+	/// Take the bytes waiting to be received by the host.
+	pub fn take_response(&mut self) -> Vec<u8> {
+		self.response.drain(..).collect()
+	}
+
+	/// Put an I²C target on the bus.
+	pub fn add_i2c_target(&mut self, address: u8) {
+		self.i2c_targets.insert(
+			address,
+			FakeTarget {
+				memory: [0u8; 256],
+				pointer: 0,
+			},
+		);
+	}
+
+	/// Get the memory of an I²C target.
+	pub fn i2c_target(&self, address: u8) -> &[u8] {
+		&self.i2c_targets[&address].memory
+	}
+
+	/// Make each I²C transfer look busy for the first few status reads.
+	pub fn set_i2c_busy_polls(&mut self, count: usize) {
+		self.i2c_busy_polls = count;
+	}
+
+	/// Make every I²C transfer fail with the given status bits.
+	pub fn set_i2c_fault(&mut self, bits: u8) {
+		self.i2c_fault = bits;
+	}
+
+	/// Get every I²C transfer: the address, the operation and what was
+	/// written.
+	pub fn i2c_transfers(&self) -> &[(u8, u8, Vec<u8>)] {
+		&self.i2c_transfers
+	}
+
+	/// Bytes arrive on the UART.
+	pub fn receive_uart(&mut self, data: &[u8]) {
+		self.uart_rx.extend(data);
+	}
+
+	/// Bytes arrive on the UART, once the host has read the *Interrupt
+	/// Status* register a few times.
+	pub fn receive_uart_later(&mut self, data: &[u8], status_reads: usize) {
+		self.uart_rx_later = Some((data.to_vec(), status_reads));
+	}
+
+	/// Set what the host sent since it last started, and before that.
+	pub fn set_uart_history(&mut self, previous: &[u8], current: &[u8]) {
+		self.uart_history = [
+			current.iter().copied().collect(),
+			previous.iter().copied().collect(),
+		];
+	}
+
+	/// Get everything the UART has sent.
+	pub fn uart_sent(&self) -> &[u8] {
+		&self.uart_sent
+	}
+
+	/// Handle reads of the UART registers (and the *Interrupt Status*
+	/// register, which the UART drives).
+	///
+	/// The TX FIFO is sent whenever the *Interrupt Status* register is read.
+	fn uart_read(&mut self, req: &proto::Request) -> Option<Vec<u8>> {
+		let length = req.length_or_data as usize;
+		match Command::try_from(req.register) {
+			Ok(Command::InterruptStatus) => {
+				if let Some((data, reads)) = self.uart_rx_later.take() {
+					if reads == 0 {
+						self.uart_rx.extend(data);
+					} else {
+						self.uart_rx_later = Some((data, reads - 1));
+					}
+				}
+				self.uart_sent.append(&mut self.uart_tx);
+				let mut bits = interrupt::UART_TX_EMPTY;
+				if !self.uart_rx.is_empty() {
+					bits |= interrupt::UART_RX_NOT_EMPTY;
+				}
+				Some(bits.to_le_bytes()[0..length].to_vec())
+			}
+			Ok(Command::UartBuffer) => {
+				let mut data = vec![self.uart_rx.len() as u8];
+				for _ in 1..length {
+					data.push(self.uart_rx.pop_front().unwrap_or(0));
+				}
+				Some(data)
+			}
+			Ok(Command::UartFifoControl) => Some(vec![self.uart_tx.len() as u8]),
+			Ok(Command::UartHistory) => {
+				let (which, pos) = self.uart_history_read;
+				let unread: Vec<u8> = self.uart_history[which].iter().skip(pos).copied().collect();
+				let mut data = vec![unread.len() as u8];
+				for i in 1..length {
+					data.push(unread.get(i - 1).copied().unwrap_or(0));
+				}
+				self.uart_history_read.1 += unread.len().min(length - 1);
+				Some(data)
+			}
+			_ => None,
+		}
+	}
+
+	/// Add bytes to the UART TX FIFO, if they fit.
+	fn uart_queue(&mut self, data: &[u8]) -> proto::ResponseResult {
+		if self.uart_tx.len() + data.len() > uart::FIFO_LEN {
+			proto::ResponseResult::BadLength
+		} else {
+			self.uart_tx.extend_from_slice(data);
+			let history = &mut self.uart_history[uart::history::CURRENT as usize];
+			history.extend(data);
+			while history.len() > uart::HISTORY_LEN {
+				history.pop_front();
+			}
+			proto::ResponseResult::Ok
+		}
+	}
+
+	/// Run an I²C transfer, as set up by a write to the I²C Control register.
+	fn i2c_transfer(&mut self, control: &[u8]) -> proto::ResponseResult {
+		let (address, op, read_len) = (control[0], control[1], usize::from(control[2]));
+		self.i2c_transfers.push((address, op, self.i2c_tx.clone()));
+		self.i2c_rx.clear();
+		self.i2c_busy_left = self.i2c_busy_polls;
+		let tx = core::mem::take(&mut self.i2c_tx);
+		if self.i2c_fault != 0 {
+			self.i2c_status = i2c::status::DONE | self.i2c_fault;
+			return proto::ResponseResult::Ok;
+		}
+		if op == i2c::operation::SCAN {
+			let mut found = [0u8; i2c::FIFO_LEN];
+			for address in self
+				.i2c_targets
+				.keys()
+				.filter(|a| (0x08..=0x77).contains(*a))
+			{
+				found[usize::from(*address / 8)] |= 1 << (address % 8);
+			}
+			self.i2c_rx.extend(found);
+			self.i2c_status = i2c::status::DONE;
+			return proto::ResponseResult::Ok;
+		}
+		let Some(target) = self.i2c_targets.get_mut(&address) else {
+			self.i2c_status = i2c::status::DONE | i2c::status::NACK;
+			return proto::ResponseResult::Ok;
+		};
+		if op == i2c::operation::WRITE || op == i2c::operation::WRITE_READ {
+			if let Some((first, rest)) = tx.split_first() {
+				target.pointer = *first;
+				for b in rest {
+					target.memory[usize::from(target.pointer)] = *b;
+					target.pointer = target.pointer.wrapping_add(1);
+				}
+			}
+		}
+		if op == i2c::operation::READ || op == i2c::operation::WRITE_READ {
+			for _ in 0..read_len {
+				self.i2c_rx
+					.push_back(target.memory[usize::from(target.pointer)]);
+				target.pointer = target.pointer.wrapping_add(1);
+			}
+		}
+		self.i2c_status = i2c::status::DONE;
+		proto::ResponseResult::Ok
+	}
+
+	/// Handle reads of the I²C registers which aren't just bytes.
+	fn i2c_read(&mut self, req: &proto::Request) -> Option<Vec<u8>> {
+		let length = req.length_or_data as usize;
+		match Command::try_from(req.register) {
+			Ok(Command::I2cBuffer) => {
+				let mut data = vec![self.i2c_rx.len() as u8];
+				for _ in 1..length {
+					data.push(self.i2c_rx.pop_front().unwrap_or(0));
+				}
+				Some(data)
+			}
+			Ok(Command::I2cStatus) => {
+				if self.i2c_busy_left > 0 {
+					self.i2c_busy_left -= 1;
+					Some(vec![i2c::status::BUSY])
+				} else {
+					Some(vec![self.i2c_status])
+				}
+			}
+			_ => None,
+		}
+	}
+
+	/// Handle Short Writes to the I²C registers.
+	fn i2c_short_write(&mut self, req: &proto::Request) -> bool {
+		match Command::try_from(req.register) {
+			Ok(Command::I2cBuffer) => self.i2c_tx.push(req.length_or_data),
+			Ok(Command::I2cFifoControl) => {
+				if (req.length_or_data & 0x01) != 0 {
+					self.i2c_tx.clear();
+				}
+				if (req.length_or_data & 0x02) != 0 {
+					self.i2c_rx.clear();
+				}
+			}
+			Ok(Command::I2cStatus) => self.i2c_status &= !req.length_or_data,
+			_ => return false,
+		}
+		true
+	}
+
+	/// Queue a response for the host.
+	fn respond(&mut self, rsp: &proto::Response) {
+		let mut buffer = [0u8; 80];
+		let len = rsp.render_to_buffer(&mut buffer).unwrap();
+		if self.corrupt_after > 0 {
+			self.corrupt_after -= 1;
+		} else if self.corrupt > 0 {
+			self.corrupt -= 1;
+			buffer[len - 1] ^= 0x01;
+		}
+		self.response.extend(&buffer[0..len]);
+	}
+
+	fn handle_request(&mut self, req: proto::Request) {
+		self.requests.push(req.clone());
+		let length = req.length_or_data as usize;
+		// Like the firmware, we only remember the read or write we just did
+		let last_read = self.last_read.take();
+		let last_write = self.last_write.take();
+		match req.request_type.flatten() {
+			proto::RequestType::Read => {
+				if length > crate::MAX_READ_LEN {
+					// The firmware can't send this much
+					self.respond(&proto::Response::new_without_data(
+						proto::ResponseResult::BadLength,
+					));
+					return;
+				}
+				if let Some((last_req, data)) = last_read {
+					if last_req == req {
+						// A retry, so send exactly the same again
+						self.respond(&proto::Response::new_ok_with_data(&data));
+						self.last_read = Some((last_req, data));
+						return;
+					}
+				}
+				if let Some(data) = self.i2c_read(&req).or_else(|| self.uart_read(&req)) {
+					self.respond(&proto::Response::new_ok_with_data(&data));
+					self.last_read = Some((req, data));
+					return;
+				}
+				match self.registers.get(&req.register) {
+					Some(value) if length <= value.len() => {
+						let data = value[0..length].to_vec();
+						self.respond(&proto::Response::new_ok_with_data(&data));
+						self.last_read = Some((req, data));
+					}
+					Some(_) => self.respond(&proto::Response::new_without_data(
+						proto::ResponseResult::BadLength,
+					)),
+					None => self.respond(&proto::Response::new_without_data(
+						proto::ResponseResult::BadRegister,
+					)),
+				}
+			}
+			proto::RequestType::ShortWrite => {
+				if let Some((last_req, payload, result)) = last_write {
+					if last_req == req {
+						// A retry, so don't do it again
+						self.respond(&proto::Response::new_without_data(result));
+						self.last_write = Some((last_req, payload, result));
+						return;
+					}
+				}
+				let result = if req.register == Command::UartBuffer as u8 {
+					self.uart_queue(&[req.length_or_data])
+				} else if req.register == Command::UartHistoryControl as u8 {
+					self.uart_history_read = (usize::from(req.length_or_data), 0);
+					proto::ResponseResult::Ok
+				} else if self.i2c_short_write(&req) {
+					proto::ResponseResult::Ok
+				} else if self.rejected.contains(&req.register) {
+					proto::ResponseResult::BadLength
+				} else {
+					self.writes
+						.entry(req.register)
+						.or_default()
+						.push(req.length_or_data);
+					proto::ResponseResult::Ok
+				};
+				self.respond(&proto::Response::new_without_data(result));
+				self.last_write = Some((req, Vec::new(), result));
+			}
+			_ => {
+				// The payload tells us whether this is a retry
+				self.last_write = last_write.filter(|(last_req, _, _)| *last_req == req);
+				self.long_write = Some(req);
+				self.respond(&proto::Response::new_without_data(
+					proto::ResponseResult::Ok,
+				));
+			}
+		}
+	}
+
+	fn handle_payload(&mut self, req: proto::Request, payload: &[u8]) {
+		let result = if payload.len() != req.length_or_data as usize + 1 {
+			proto::ResponseResult::BadLength
+		} else if proto::calculate_crc(payload) != 0 {
+			proto::ResponseResult::CrcFailure
+		} else if let Some((_, _, result)) = self
+			.last_write
+			.as_ref()
+			.filter(|(last_req, last_payload, _)| *last_req == req && last_payload == payload)
+		{
+			// A retry, so don't do it again
+			*result
+		} else {
+			let data = &payload[0..payload.len() - 1];
+			let result = match Command::try_from(req.register) {
+				Ok(Command::I2cBuffer) => {
+					self.i2c_tx.extend_from_slice(data);
+					proto::ResponseResult::Ok
+				}
+				Ok(Command::I2cControl) => self.i2c_transfer(data),
+				Ok(Command::UartBuffer) => self.uart_queue(data),
+				_ => {
+					self.registers.insert(req.register, data.to_vec());
+					proto::ResponseResult::Ok
+				}
+			};
+			self.last_write = Some((req, payload.to_vec(), result));
+			result
+		};
+		self.respond(&proto::Response::new_without_data(result));
+	}
+}
+
+impl Transport for FakeBmc {
+	type Error = core::convert::Infallible;
+
+	fn start(&mut self) -> Result<(), Self::Error> {
+		self.long_write = None;
+		self.response.clear();
+		Ok(())
+	}
+
+	fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+		if let Some(req) = self.long_write.take() {
+			self.handle_payload(req, data);
+		} else {
+			match proto::Request::from_bytes(data) {
+				Ok(req) => self.handle_request(req),
+				Err(_) => self.respond(&proto::Response::new_without_data(
+					proto::ResponseResult::CrcFailure,
+				)),
+			}
+		}
+		Ok(())
+	}
+
+	fn receive(&mut self, buffer: &mut [u8]) -> Result<(), Self::Error> {
+		for b in buffer.iter_mut() {
+			*b = self
+				.response
+				.pop_front()
+				.expect("read past end of response");
+		}
+		Ok(())
+	}
+
+	fn end(&mut self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
@@ -0,0 +1,417 @@
+//! Uses the NBMC's I²C bridge as an [`embedded_hal::i2c::I2c`] bus.
+//!
+//! This lets ordinary `embedded-hal` drivers (RTC chips, EEPROMs, sensors)
+//! talk to devices on the NBMC's I²C bus.
+//!
+//! The NBMC runs one transfer at a time, with at most
+//! [`FIFO_LEN`] bytes written and [`FIFO_LEN`] bytes read, and each
+//! transfer ends with a STOP. So a transaction can be some writes, some reads,
+//! or some writes followed by some reads (which uses a repeated START) - and
+//! anything else gives [`Error::Unsupported`].
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+use core::fmt::Debug;
+
+use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+pub use neotron_bmc_commands::i2c::{operation, status, FIFO_LEN};
+
+use crate::{Bmc, Command, Transport};
+
+// ============================================================================
+// Enums
+// ============================================================================
+
+/// The ways an I²C transaction can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+	/// We couldn't talk to the NBMC
+	Bmc(crate::Error<E>),
+	/// The target didn't acknowledge its address or a byte
+	NoAcknowledge,
+	/// Another controller won the bus
+	ArbitrationLoss,
+	/// A misplaced START or STOP was seen on the bus
+	Bus,
+	/// SDA is held low, and the NBMC couldn't free it
+	BusStuck,
+	/// The transfer took too long (e.g. a target held SCL low)
+	Timeout,
+	/// The NBMC didn't read as many bytes as we asked for
+	ShortRead,
+	/// The NBMC can't do this transaction (it's too long, the wrong shape, or
+	/// the address is more than seven bits)
+	Unsupported,
+}
+
+impl<E> From<crate::Error<E>> for Error<E> {
+	fn from(e: crate::Error<E>) -> Error<E> {
+		Error::Bmc(e)
+	}
+}
+
+impl<E> embedded_hal::i2c::Error for Error<E>
+where
+	E: Debug,
+{
+	fn kind(&self) -> ErrorKind {
+		match self {
+			Error::NoAcknowledge => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+			Error::ArbitrationLoss => ErrorKind::ArbitrationLoss,
+			Error::Bus | Error::BusStuck => ErrorKind::Bus,
+			Error::Bmc(_) | Error::Timeout | Error::ShortRead | Error::Unsupported => {
+				ErrorKind::Other
+			}
+		}
+	}
+}
+
+// ============================================================================
+// Structs and Impls
+// ============================================================================
+
+/// The NBMC's I²C bus. Get one with [`Bmc::i2c_bus`].
+pub struct I2cBus<'a, T> {
+	bmc: &'a mut Bmc<T>,
+}
+
+impl<'a, T> I2cBus<'a, T>
+where
+	T: Transport,
+{
+	/// Use the I²C bus on the given NBMC.
+	pub fn new(bmc: &'a mut Bmc<T>) -> I2cBus<'a, T> {
+		I2cBus { bmc }
+	}
+
+	/// Set the clock rate, in Hz.
+	///
+	/// The NBMC rounds it down to a rate it supports, which we return.
+	pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<u32, Error<T::Error>> {
+		self.bmc
+			.long_write(Command::I2cBaudRate, &baud_rate.to_le_bytes())?;
+		let mut actual = [0u8; 4];
+		self.bmc.read(Command::I2cBaudRate, &mut actual)?;
+		Ok(u32::from_le_bytes(actual))
+	}
+
+	/// Find out which addresses (from 0x08 to 0x77) have a target on them.
+	///
+	/// Bit `n % 8` of byte `n / 8` is set if a target answered at address
+	/// `n`.
+	pub fn scan(&mut self) -> Result<[u8; FIFO_LEN], Error<T::Error>> {
+		let mut found = [0u8; FIFO_LEN];
+		self.transfer(0, operation::SCAN, &[], &mut found)?;
+		Ok(found)
+	}
+
+	/// Run one transfer on the NBMC, and wait for it to finish.
+	///
+	/// `read` is filled with what the target sent back, if anything.
+	fn transfer(
+		&mut self,
+		address: u8,
+		op: u8,
+		write: &[u8],
+		read: &mut [u8],
+	) -> Result<(), Error<T::Error>> {
+		// Start from empty FIFOs
+		self.bmc.write(Command::I2cFifoControl, 0x03)?;
+		if !write.is_empty() {
+			self.bmc.long_write(Command::I2cBuffer, write)?;
+		}
+		self.bmc
+			.long_write(Command::I2cControl, &[address, op, read.len() as u8])?;
+
+		// The NBMC gives up on the bus after a while, so this won't spin for
+		// ever.
+		let mut state = [status::BUSY];
+		while (state[0] & status::BUSY) != 0 {
+			self.bmc.read(Command::I2cStatus, &mut state)?;
+		}
+		let state = state[0];
+		if (state & status::NACK) != 0 {
+			return Err(Error::NoAcknowledge);
+		} else if (state & status::ARBITRATION_LOST) != 0 {
+			return Err(Error::ArbitrationLoss);
+		} else if (state & status::BUS_ERROR) != 0 {
+			return Err(Error::Bus);
+		} else if (state & status::BUS_STUCK) != 0 {
+			return Err(Error::BusStuck);
+		} else if (state & status::TIMEOUT) != 0 {
+			return Err(Error::Timeout);
+		} else if (state & status::DONE) == 0 {
+			// The NBMC forgot about our transfer (perhaps it reset)
+			return Err(Error::Timeout);
+		}
+
+		if !read.is_empty() {
+			// The first byte is how many bytes there were
+			let mut buffer = [0u8; FIFO_LEN + 1];
+			let buffer = &mut buffer[0..=read.len()];
+			self.bmc.read(Command::I2cBuffer, buffer)?;
+			if usize::from(buffer[0]) < read.len() {
+				return Err(Error::ShortRead);
+			}
+			read.copy_from_slice(&buffer[1..]);
+		}
+		Ok(())
+	}
+}
+
+impl<'a, T> embedded_hal::i2c::ErrorType for I2cBus<'a, T>
+where
+	T: Transport,
+	T::Error: Debug,
+{
+	type Error = Error<T::Error>;
+}
+
+impl<'a, T> embedded_hal::i2c::I2c for I2cBus<'a, T>
+where
+	T: Transport,
+	T::Error: Debug,
+{
+	fn transaction(
+		&mut self,
+		address: u8,
+		operations: &mut [Operation<'_>],
+	) -> Result<(), Self::Error> {
+		if address > 0x7F {
+			return Err(Error::Unsupported);
+		}
+
+		// Gather up the writes, which must all come before the reads
+		let mut write = [0u8; FIFO_LEN];
+		let mut write_len = 0;
+		let mut has_write = false;
+		let mut read_len = 0;
+		for op in operations.iter() {
+			match op {
+				Operation::Write(bytes) => {
+					if read_len > 0 || write_len + bytes.len() > FIFO_LEN {
+						return Err(Error::Unsupported);
+					}
+					write[write_len..write_len + bytes.len()].copy_from_slice(bytes);
+					write_len += bytes.len();
+					has_write = true;
+				}
+				Operation::Read(buffer) => {
+					read_len += buffer.len();
+				}
+			}
+		}
+		if read_len > FIFO_LEN {
+			return Err(Error::Unsupported);
+		}
+
+		let op = match (has_write, read_len > 0) {
+			(true, false) => operation::WRITE,
+			(false, true) => operation::READ,
+			(true, true) => operation::WRITE_READ,
+			(false, false) => return Ok(()),
+		};
+		let mut read = [0u8; FIFO_LEN];
+		self.transfer(address, op, &write[0..write_len], &mut read[0..read_len])?;
+
+		// Hand out what we read
+		let mut read = &read[0..read_len];
+		for op in operations.iter_mut() {
+			if let Operation::Read(buffer) = op {
+				let (this, rest) = read.split_at(buffer.len());
+				buffer.copy_from_slice(this);
+				read = rest;
+			}
+		}
+		Ok(())
+	}
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::fake::FakeBmc;
+	use embedded_hal::i2c::{Error as _, I2c};
+
+	/// A fake BMC with a 256 byte EEPROM-like target at 0x50.
+	fn bmc_with_eeprom() -> Bmc<FakeBmc> {
+		let mut fake = FakeBmc::new();
+		fake.add_i2c_target(0x50);
+		Bmc::new(fake)
+	}
+
+	#[test]
+	fn write() {
+		let mut bmc = bmc_with_eeprom();
+		bmc.i2c_bus().write(0x50, &[0x10, 1, 2, 3]).unwrap();
+		let fake = bmc.release();
+		assert_eq!(&fake.i2c_target(0x50)[0x10..0x13], &[1, 2, 3]);
+	}
+
+	#[test]
+	fn write_read() {
+		let mut bmc = bmc_with_eeprom();
+		bmc.i2c_bus().write(0x50, &[0x20, 0xAA, 0xBB]).unwrap();
+		let mut buffer = [0u8; 2];
+		bmc.i2c_bus()
+			.write_read(0x50, &[0x20], &mut buffer)
+			.unwrap();
+		assert_eq!(buffer, [0xAA, 0xBB]);
+		let fake = bmc.release();
+		assert_eq!(
+			fake.i2c_transfers().last(),
+			Some(&(0x50, operation::WRITE_READ, vec![0x20]))
+		);
+	}
+
+	#[test]
+	fn read_carries_on() {
+		let mut bmc = bmc_with_eeprom();
+		bmc.i2c_bus().write(0x50, &[0x30, 5, 6, 7]).unwrap();
+		bmc.i2c_bus().write(0x50, &[0x30]).unwrap();
+		let mut buffer = [0u8; 3];
+		bmc.i2c_bus().read(0x50, &mut buffer).unwrap();
+		assert_eq!(buffer, [5, 6, 7]);
+	}
+
+	#[test]
+	fn transaction_merges_operations() {
+		let mut bmc = bmc_with_eeprom();
+		bmc.i2c_bus().write(0x50, &[0x40, 1, 2, 3, 4]).unwrap();
+		let mut first = [0u8; 1];
+		let mut second = [0u8; 3];
+		bmc.i2c_bus()
+			.transaction(
+				0x50,
+				&mut [
+					Operation::Write(&[]),
+					Operation::Write(&[0x40]),
+					Operation::Read(&mut first),
+					Operation::Read(&mut second),
+				],
+			)
+			.unwrap();
+		assert_eq!(first, [1]);
+		assert_eq!(second, [2, 3, 4]);
+		let fake = bmc.release();
+		assert_eq!(
+			fake.i2c_transfers().last(),
+			Some(&(0x50, operation::WRITE_READ, vec![0x40]))
+		);
+	}
+
+	#[test]
+	fn waits_while_busy() {
+		let mut bmc = bmc_with_eeprom();
+		bmc.i2c_bus().write(0x50, &[0x00, 0x42]).unwrap();
+		let mut fake = bmc.release();
+		fake.set_i2c_busy_polls(3);
+		let mut bmc = Bmc::new(fake);
+		let mut buffer = [0u8; 1];
+		bmc.i2c_bus()
+			.write_read(0x50, &[0x00], &mut buffer)
+			.unwrap();
+		assert_eq!(buffer, [0x42]);
+	}
+
+	#[test]
+	fn no_target() {
+		let mut bmc = bmc_with_eeprom();
+		let err = bmc.i2c_bus().write(0x51, &[0x00]).unwrap_err();
+		assert_eq!(err, Error::NoAcknowledge);
+		assert_eq!(
+			err.kind(),
+			ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown)
+		);
+	}
+
+	#[test]
+	fn bus_faults() {
+		for (fault, expected) in [
+			(status::ARBITRATION_LOST, Error::ArbitrationLoss),
+			(status::BUS_ERROR, Error::Bus),
+			(status::TIMEOUT, Error::Timeout),
+		] {
+			let mut fake = FakeBmc::new();
+			fake.add_i2c_target(0x50);
+			fake.set_i2c_fault(fault);
+			let mut bmc = Bmc::new(fake);
+			assert_eq!(bmc.i2c_bus().write(0x50, &[0x00]), Err(expected));
+		}
+		assert_eq!(
+			Error::<()>::ArbitrationLoss.kind(),
+			ErrorKind::ArbitrationLoss
+		);
+		assert_eq!(Error::<()>::Bus.kind(), ErrorKind::Bus);
+		assert_eq!(Error::<()>::Timeout.kind(), ErrorKind::Other);
+	}
+
+	#[test]
+	fn unsupported_transactions() {
+		let mut bmc = bmc_with_eeprom();
+		let mut buffer = [0u8; 1];
+		// Too long
+		assert_eq!(
+			bmc.i2c_bus().write(0x50, &[0u8; FIFO_LEN + 1]),
+			Err(Error::Unsupported)
+		);
+		assert_eq!(
+			bmc.i2c_bus().read(0x50, &mut [0u8; FIFO_LEN + 1]),
+			Err(Error::Unsupported)
+		);
+		// A write after a read
+		assert_eq!(
+			bmc.i2c_bus().transaction(
+				0x50,
+				&mut [Operation::Read(&mut buffer), Operation::Write(&[0x00])]
+			),
+			Err(Error::Unsupported)
+		);
+		// Ten-bit addresses
+		assert_eq!(bmc.i2c_bus().write(0x80, &[0x00]), Err(Error::Unsupported));
+		// None of those should have reached the bus
+		let fake = bmc.release();
+		assert!(fake.i2c_transfers().is_empty());
+	}
+
+	#[test]
+	fn scan() {
+		let mut fake = FakeBmc::new();
+		fake.add_i2c_target(0x08);
+		fake.add_i2c_target(0x50);
+		fake.add_i2c_target(0x77);
+		let mut bmc = Bmc::new(fake);
+		let found = bmc.i2c_bus().scan().unwrap();
+		let mut expected = [0u8; FIFO_LEN];
+		expected[1] = 0x01;
+		expected[10] = 0x01;
+		expected[14] = 0x80;
+		assert_eq!(found, expected);
+	}
+
+	#[test]
+	fn scan_bus_stuck() {
+		let mut fake = FakeBmc::new();
+		fake.set_i2c_fault(status::BUS_STUCK);
+		let mut bmc = Bmc::new(fake);
+		let err = bmc.i2c_bus().scan().unwrap_err();
+		assert_eq!(err, Error::BusStuck);
+		assert_eq!(err.kind(), ErrorKind::Bus);
+	}
+
+	#[test]
+	fn baud_rate() {
+		let mut bmc = bmc_with_eeprom();
+		assert_eq!(bmc.i2c_bus().set_baud_rate(400_000), Ok(400_000));
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
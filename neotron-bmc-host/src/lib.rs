@@ -0,0 +1,413 @@
+#![doc = include_str!("../README.md")]
+#![cfg_attr(not(test), no_std)]
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+pub mod i2c;
+pub mod serial;
+pub mod spi;
+pub mod uart;
+
+#[cfg(test)]
+mod fake;
+
+pub use neotron_bmc_commands::Command;
+use neotron_bmc_protocol::{self as proto, Receivable};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// How many times we try a request which failed a CRC check, in either
+/// direction.
+pub const MAX_ATTEMPTS: usize = 3;
+
+/// The most bytes we can read from a register in one go. The NBMC's SPI
+/// transmit buffer is 64 bytes, and the *Response* needs a result byte and a
+/// CRC as well.
+pub const MAX_READ_LEN: usize = 62;
+
+/// The most bytes we can write to a register in one Long Write.
+pub const MAX_WRITE_LEN: usize = 64;
+
+// ============================================================================
+// Traits
+// ============================================================================
+
+/// Something which can carry NBMC *Requests* and *Responses*, such as an SPI
+/// bus.
+///
+/// A transaction is a call to [`Transport::start`], one or more calls to
+/// [`Transport::send`] and [`Transport::receive`], and then a call to
+/// [`Transport::end`].
+pub trait Transport {
+	/// The ways the transport can fail
+	type Error;
+
+	/// Start a transaction (e.g. take `nCS` low).
+	fn start(&mut self) -> Result<(), Self::Error>;
+
+	/// Send a *Request*, or a *Long Write Payload*.
+	fn send(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+	/// Receive (some of) a *Response*.
+	///
+	/// The first call after a [`Transport::send`] must skip any *Turn-Around*
+	/// padding the NBMC sends before the *Response*.
+	fn receive(&mut self, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+	/// End a transaction (e.g. raise `nCS`).
+	fn end(&mut self) -> Result<(), Self::Error>;
+}
+
+// ============================================================================
+// Enums
+// ============================================================================
+
+/// The ways talking to the NBMC can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+	/// The [`Transport`] failed
+	Transport(E),
+	/// We couldn't decode the *Response* (e.g. it failed its CRC check), even
+	/// after retrying, or we were asked to send something too long
+	Protocol(proto::Error),
+	/// The NBMC didn't like our *Request*
+	Response(proto::ResponseResult),
+}
+
+// ============================================================================
+// Structs and Impls
+// ============================================================================
+
+/// Talks to an NBMC over some [`Transport`].
+pub struct Bmc<T> {
+	/// How we get to the NBMC
+	transport: T,
+	/// Which *Request Type* we use next
+	use_alt: bool,
+}
+
+impl<T> Bmc<T>
+where
+	T: Transport,
+{
+	/// Make a new NBMC handle, using the given transport.
+	pub fn new(transport: T) -> Bmc<T> {
+		Bmc {
+			transport,
+			use_alt: false,
+		}
+	}
+
+	/// Give back the transport.
+	pub fn release(self) -> T {
+		self.transport
+	}
+
+	/// Read `buffer.len()` bytes from a register.
+	///
+	/// If the *Response* is corrupted on the way back, the same *Request* is
+	/// sent again, so the NBMC gives us the same bytes rather than taking more
+	/// from a FIFO.
+	pub fn read(&mut self, command: Command, buffer: &mut [u8]) -> Result<(), Error<T::Error>> {
+		if buffer.len() > MAX_READ_LEN {
+			return Err(Error::Protocol(proto::Error::BufferTooSmall));
+		}
+		let req = proto::Request::new_read(self.next_alt(), command.into(), buffer.len() as u8);
+		let mut rsp_buffer = [0u8; MAX_READ_LEN + 2];
+		let rsp_buffer = &mut rsp_buffer[0..buffer.len() + 2];
+		self.retry(|transport| {
+			transport.start().map_err(Error::Transport)?;
+			let result = exchange(transport, &req.as_bytes(), rsp_buffer);
+			transport.end().map_err(Error::Transport)?;
+			let rsp = proto::Response::from_bytes(result?).map_err(Error::Protocol)?;
+			check(rsp.result)?;
+			buffer.copy_from_slice(rsp.data);
+			Ok(())
+		})
+	}
+
+	/// Write one byte to a register, with a *Short Write*.
+	pub fn write(&mut self, command: Command, value: u8) -> Result<(), Error<T::Error>> {
+		let req = proto::Request::new_short_write(self.next_alt(), command.into(), value);
+		self.retry(|transport| {
+			transport.start().map_err(Error::Transport)?;
+			let result = short_exchange(transport, &req.as_bytes());
+			transport.end().map_err(Error::Transport)?;
+			result
+		})
+	}
+
+	/// Write several bytes to a register, with a *Long Write*.
+	pub fn long_write(&mut self, command: Command, data: &[u8]) -> Result<(), Error<T::Error>> {
+		if data.is_empty() || data.len() > MAX_WRITE_LEN {
+			return Err(Error::Protocol(proto::Error::BadLength));
+		}
+		let req = proto::Request::new_long_write(self.next_alt(), command.into(), data.len() as u8);
+		let mut payload = [0u8; MAX_WRITE_LEN + 1];
+		payload[0..data.len()].copy_from_slice(data);
+		payload[data.len()] = proto::calculate_crc(data);
+		let payload = &payload[0..=data.len()];
+		self.retry(|transport| {
+			transport.start().map_err(Error::Transport)?;
+			let result = short_exchange(transport, &req.as_bytes())
+				.and_then(|_| short_exchange(transport, payload));
+			transport.end().map_err(Error::Transport)?;
+			result
+		})
+	}
+
+	/// Use the NBMC's I²C bus, as an [`embedded_hal::i2c::I2c`].
+	pub fn i2c_bus(&mut self) -> i2c::I2cBus<'_, T> {
+		i2c::I2cBus::new(self)
+	}
+
+	/// Use the NBMC's UART, as an [`embedded_io`] serial port.
+	pub fn uart_port(&mut self) -> uart::UartPort<'_, T> {
+		uart::UartPort::new(self)
+	}
+
+	/// Restart the NBMC in the STM32's ROM bootloader, so it can be reflashed
+	/// over its UART.
+	///
+	/// If this returns `Ok`, the NBMC has accepted the whole key and will
+	/// reset shortly. The system will lose power.
+	pub fn enter_rom_bootloader(&mut self) -> Result<(), Error<T::Error>> {
+		for byte in ROM_BOOTLOADER_KEY {
+			self.write(Command::RomBootloaderEntry, byte)?;
+		}
+		Ok(())
+	}
+
+	/// Get the *Request Type* for the next *Request*.
+	fn next_alt(&mut self) -> bool {
+		self.use_alt = !self.use_alt;
+		self.use_alt
+	}
+
+	/// Run `f` until it works, or doesn't fail because of a CRC problem, or
+	/// we've tried [`MAX_ATTEMPTS`] times.
+	fn retry<F>(&mut self, mut f: F) -> Result<(), Error<T::Error>>
+	where
+		F: FnMut(&mut T) -> Result<(), Error<T::Error>>,
+	{
+		let mut attempts = 0;
+		loop {
+			attempts += 1;
+			match f(&mut self.transport) {
+				Err(Error::Protocol(proto::Error::BadCrc))
+				| Err(Error::Response(proto::ResponseResult::CrcFailure))
+					if attempts < MAX_ATTEMPTS =>
+				{
+					// Try exactly the same request again
+				}
+				result => return result,
+			}
+		}
+	}
+}
+
+/// The key for [`Command::RomBootloaderEntry`]
+const ROM_BOOTLOADER_KEY: [u8; 4] = *b"BOOT";
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Send some bytes, and get a *Response* back.
+///
+/// `buffer` must be big enough for an OK *Response* - if we get an error
+/// *Response* instead, only the first two bytes are used.
+fn exchange<'a, T>(
+	transport: &mut T,
+	data: &[u8],
+	buffer: &'a mut [u8],
+) -> Result<&'a [u8], Error<T::Error>>
+where
+	T: Transport,
+{
+	transport.send(data).map_err(Error::Transport)?;
+	transport
+		.receive(&mut buffer[0..1])
+		.map_err(Error::Transport)?;
+	let len = if buffer[0] == proto::ResponseResult::Ok as u8 {
+		buffer.len()
+	} else {
+		2
+	};
+	transport
+		.receive(&mut buffer[1..len])
+		.map_err(Error::Transport)?;
+	Ok(&buffer[0..len])
+}
+
+/// Send some bytes, and check we get an OK *Short Response* back.
+fn short_exchange<T>(transport: &mut T, data: &[u8]) -> Result<(), Error<T::Error>>
+where
+	T: Transport,
+{
+	let mut buffer = [0u8; 2];
+	let rsp = exchange(transport, data, &mut buffer)?;
+	let rsp = proto::Response::from_bytes(rsp).map_err(Error::Protocol)?;
+	check(rsp.result)
+}
+
+/// Turn a *Response Result* into a `Result`.
+fn check<E>(result: proto::ResponseResult) -> Result<(), Error<E>> {
+	match result {
+		proto::ResponseResult::Ok => Ok(()),
+		e => Err(Error::Response(e)),
+	}
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use fake::FakeBmc;
+
+	#[test]
+	fn read_register() {
+		let mut fake = FakeBmc::new();
+		fake.set_register(Command::ProtocolVersion, &[0, 1, 1]);
+		let mut bmc = Bmc::new(fake);
+		let mut buffer = [0u8; 3];
+		bmc.read(Command::ProtocolVersion, &mut buffer).unwrap();
+		assert_eq!(buffer, [0, 1, 1]);
+	}
+
+	#[test]
+	fn read_unknown_register() {
+		let mut bmc = Bmc::new(FakeBmc::new());
+		let mut buffer = [0u8; 3];
+		assert_eq!(
+			bmc.read(Command::ProtocolVersion, &mut buffer),
+			Err(Error::Response(proto::ResponseResult::BadRegister))
+		);
+	}
+
+	#[test]
+	fn read_retries_with_same_request() {
+		let mut fake = FakeBmc::new();
+		fake.set_register(Command::ProtocolVersion, &[0, 1, 1]);
+		fake.corrupt_responses(1);
+		let mut bmc = Bmc::new(fake);
+		let mut buffer = [0u8; 3];
+		bmc.read(Command::ProtocolVersion, &mut buffer).unwrap();
+		assert_eq!(buffer, [0, 1, 1]);
+		let fake = bmc.release();
+		let requests = fake.requests();
+		assert_eq!(requests.len(), 2);
+		assert_eq!(requests[0], requests[1]);
+	}
+
+	#[test]
+	fn read_gives_up() {
+		let mut fake = FakeBmc::new();
+		fake.set_register(Command::ProtocolVersion, &[0, 1, 1]);
+		fake.corrupt_responses(MAX_ATTEMPTS);
+		let mut bmc = Bmc::new(fake);
+		let mut buffer = [0u8; 3];
+		assert_eq!(
+			bmc.read(Command::ProtocolVersion, &mut buffer),
+			Err(Error::Protocol(proto::Error::BadCrc))
+		);
+	}
+
+	#[test]
+	fn requests_alternate() {
+		let mut fake = FakeBmc::new();
+		fake.set_register(Command::ProtocolVersion, &[0, 1, 1]);
+		let mut bmc = Bmc::new(fake);
+		let mut buffer = [0u8; 3];
+		bmc.read(Command::ProtocolVersion, &mut buffer).unwrap();
+		bmc.read(Command::ProtocolVersion, &mut buffer).unwrap();
+		let fake = bmc.release();
+		let requests = fake.requests();
+		assert_eq!(requests[0].request_type, proto::RequestType::ReadAlt);
+		assert_eq!(requests[1].request_type, proto::RequestType::Read);
+	}
+
+	#[test]
+	fn long_write() {
+		let mut bmc = Bmc::new(FakeBmc::new());
+		bmc.long_write(Command::RtcDateTime, &[1, 2, 3, 4, 5, 6])
+			.unwrap();
+		let fake = bmc.release();
+		assert_eq!(fake.register(Command::RtcDateTime), &[1, 2, 3, 4, 5, 6]);
+	}
+
+	#[test]
+	fn write_retry_is_not_repeated() {
+		let mut fake = FakeBmc::new();
+		fake.corrupt_responses(1);
+		let mut bmc = Bmc::new(fake);
+		bmc.write(Command::PowerControl, 1).unwrap();
+		let fake = bmc.release();
+		assert_eq!(fake.requests().len(), 2);
+		assert_eq!(fake.writes(Command::PowerControl), &[1]);
+	}
+
+	#[test]
+	fn same_write_twice_is_repeated() {
+		let mut fake = FakeBmc::new();
+		fake.corrupt_responses(1);
+		let mut bmc = Bmc::new(fake);
+		bmc.write(Command::PowerControl, 1).unwrap();
+		bmc.write(Command::PowerControl, 1).unwrap();
+		let fake = bmc.release();
+		assert_eq!(fake.writes(Command::PowerControl), &[1, 1]);
+	}
+
+	#[test]
+	fn long_write_retry_is_not_repeated() {
+		let mut fake = FakeBmc::new();
+		// Corrupt the response to the payload, not to the request
+		fake.corrupt_responses_after(1, 1);
+		let mut bmc = Bmc::new(fake);
+		bmc.long_write(Command::UartBuffer, b"Hi").unwrap();
+		let mut port = bmc.uart_port();
+		embedded_io::Write::flush(&mut port).unwrap();
+		let fake = bmc.release();
+		assert_eq!(fake.requests().len(), 3);
+		assert_eq!(fake.uart_sent(), b"Hi");
+	}
+
+	#[test]
+	fn long_write_too_long() {
+		let mut bmc = Bmc::new(FakeBmc::new());
+		assert_eq!(
+			bmc.long_write(Command::FirmwareUpdateData, &[0u8; MAX_WRITE_LEN + 1]),
+			Err(Error::Protocol(proto::Error::BadLength))
+		);
+	}
+
+	#[test]
+	fn rom_bootloader() {
+		let mut bmc = Bmc::new(FakeBmc::new());
+		bmc.enter_rom_bootloader().unwrap();
+		let fake = bmc.release();
+		assert_eq!(fake.writes(Command::RomBootloaderEntry), b"BOOT");
+	}
+
+	#[test]
+	fn rom_bootloader_refused() {
+		let mut fake = FakeBmc::new();
+		fake.reject_writes(Command::RomBootloaderEntry);
+		let mut bmc = Bmc::new(fake);
+		assert_eq!(
+			bmc.enter_rom_bootloader(),
+			Err(Error::Response(proto::ResponseResult::BadLength))
+		);
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
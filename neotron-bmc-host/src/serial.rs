@@ -0,0 +1,254 @@
+//! Talks to the NBMC over a serial port, for bench testing.
+//!
+//! The NBMC's shell has a `frames` command, which makes its UART (the FTDI
+//! header) carry the protocol in frames (see [`neotron_bmc_protocol::cobs`]).
+//! They are handled just like SPI transactions, so everything in this crate
+//! works from a PC with a USB serial adapter, without a Neotron host.
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+use embedded_io::{Read, ReadExactError, Write};
+use neotron_bmc_protocol::cobs;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The longest frame we send: a *Long Write Payload* and its CRC.
+const MAX_SEND_LEN: usize = crate::MAX_WRITE_LEN + 1;
+
+/// The longest frame we receive: a *Response* with its result and CRC.
+const MAX_RECEIVE_LEN: usize = crate::MAX_READ_LEN + 2;
+
+// ============================================================================
+// Enums
+// ============================================================================
+
+/// The ways the serial transport can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+	/// The serial port failed (e.g. it timed out waiting for a *Response*)
+	Port(E),
+	/// The serial port has closed
+	Closed,
+	/// A frame was the wrong length: a *Response* shorter than we expected,
+	/// or something too long to send
+	BadLength,
+}
+
+// ============================================================================
+// Structs and Impls
+// ============================================================================
+
+/// A serial port, with an NBMC in frames mode on the end.
+pub struct SerialTransport<P> {
+	port: P,
+	/// Collects the *Response*
+	decoder: cobs::Decoder<{ cobs::max_encoded_len(MAX_RECEIVE_LEN) - 1 }>,
+	/// Do we need to wait for a *Response*?
+	waiting: bool,
+	/// How much of the *Response* has been received
+	read_pos: usize,
+}
+
+impl<P> SerialTransport<P>
+where
+	P: Read + Write,
+{
+	/// Make a new transport.
+	///
+	/// The NBMC must already be in frames mode. The port should time out if
+	/// nothing arrives for a while, or a lost *Response* will leave us waiting
+	/// for ever. Anything which isn't a valid frame (such as the text the
+	/// `frames` command prints) is skipped.
+	pub fn new(port: P) -> SerialTransport<P> {
+		SerialTransport {
+			port,
+			decoder: cobs::Decoder::new(),
+			waiting: false,
+			read_pos: 0,
+		}
+	}
+
+	/// Give back the serial port.
+	pub fn release(self) -> P {
+		self.port
+	}
+
+	/// Wait for a whole frame to arrive.
+	fn read_frame(&mut self) -> Result<(), Error<P::Error>> {
+		loop {
+			let mut byte = [0u8];
+			self.port.read_exact(&mut byte).map_err(|e| match e {
+				ReadExactError::UnexpectedEof => Error::Closed,
+				ReadExactError::Other(e) => Error::Port(e),
+			})?;
+			if let Some(Ok(_)) = self.decoder.push(byte[0]) {
+				return Ok(());
+			}
+		}
+	}
+}
+
+impl<P> crate::Transport for SerialTransport<P>
+where
+	P: Read + Write,
+{
+	type Error = Error<P::Error>;
+
+	fn start(&mut self) -> Result<(), Self::Error> {
+		self.decoder.reset();
+		Ok(())
+	}
+
+	fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+		let mut buffer = [0u8; cobs::max_encoded_len(MAX_SEND_LEN)];
+		let len = cobs::encode(data, &mut buffer).map_err(|_| Error::BadLength)?;
+		self.port.write_all(&buffer[0..len]).map_err(Error::Port)?;
+		self.port.flush().map_err(Error::Port)?;
+		self.waiting = true;
+		self.read_pos = 0;
+		Ok(())
+	}
+
+	fn receive(&mut self, buffer: &mut [u8]) -> Result<(), Self::Error> {
+		if self.waiting {
+			self.read_frame()?;
+			self.waiting = false;
+		}
+		let end = self.read_pos + buffer.len();
+		let frame = self.decoder.frame();
+		if end > frame.len() {
+			return Err(Error::BadLength);
+		}
+		buffer.copy_from_slice(&frame[self.read_pos..end]);
+		self.read_pos = end;
+		Ok(())
+	}
+
+	fn end(&mut self) -> Result<(), Self::Error> {
+		self.waiting = false;
+		Ok(())
+	}
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::{fake::FakeBmc, Bmc, Command, Transport};
+	use std::collections::VecDeque;
+
+	/// A serial port with a [`FakeBmc`] on the end, in frames mode.
+	struct FakePort {
+		bmc: FakeBmc,
+		decoder: cobs::Decoder<128>,
+		rx: VecDeque<u8>,
+	}
+
+	impl FakePort {
+		fn new(bmc: FakeBmc) -> FakePort {
+			FakePort {
+				bmc,
+				decoder: cobs::Decoder::new(),
+				rx: VecDeque::new(),
+			}
+		}
+	}
+
+	impl embedded_io::ErrorType for FakePort {
+		type Error = core::convert::Infallible;
+	}
+
+	impl Write for FakePort {
+		fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+			for b in buf {
+				if let Some(frame) = self.decoder.push(*b) {
+					let frame = frame.unwrap().to_vec();
+					self.bmc.send(&frame).unwrap();
+					let mut encoded = [0u8; 128];
+					let len = cobs::encode(&self.bmc.take_response(), &mut encoded).unwrap();
+					self.rx.extend(&encoded[0..len]);
+				}
+			}
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> Result<(), Self::Error> {
+			Ok(())
+		}
+	}
+
+	impl Read for FakePort {
+		fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+			let len = buf.len().min(self.rx.len());
+			for b in &mut buf[0..len] {
+				*b = self.rx.pop_front().unwrap();
+			}
+			Ok(len)
+		}
+	}
+
+	#[test]
+	fn read_register() {
+		let mut fake = FakeBmc::new();
+		fake.set_register(Command::ProtocolVersion, &[0, 6, 0]);
+		let mut bmc = Bmc::new(SerialTransport::new(FakePort::new(fake)));
+		let mut version = [0u8; 3];
+		bmc.read(Command::ProtocolVersion, &mut version).unwrap();
+		assert_eq!(version, [0, 6, 0]);
+		// Errors come back too
+		assert_eq!(
+			bmc.read(Command::FirmwareVersion, &mut version),
+			Err(crate::Error::Response(
+				neotron_bmc_protocol::ResponseResult::BadRegister
+			))
+		);
+	}
+
+	#[test]
+	fn long_write() {
+		let mut bmc = Bmc::new(SerialTransport::new(FakePort::new(FakeBmc::new())));
+		// Zeros have to be stuffed
+		bmc.long_write(Command::RtcDateTime, &[1, 0, 0, 4, 5, 0])
+			.unwrap();
+		let port = bmc.release().release();
+		assert_eq!(port.bmc.register(Command::RtcDateTime), &[1, 0, 0, 4, 5, 0]);
+	}
+
+	#[test]
+	fn retries() {
+		let mut fake = FakeBmc::new();
+		fake.set_register(Command::ProtocolVersion, &[0, 6, 0]);
+		fake.corrupt_responses(1);
+		let mut bmc = Bmc::new(SerialTransport::new(FakePort::new(fake)));
+		let mut version = [0u8; 3];
+		bmc.read(Command::ProtocolVersion, &mut version).unwrap();
+		assert_eq!(version, [0, 6, 0]);
+		let port = bmc.release().release();
+		assert_eq!(port.bmc.requests().len(), 2);
+	}
+
+	#[test]
+	fn skips_junk() {
+		let mut fake = FakeBmc::new();
+		fake.set_register(Command::ProtocolVersion, &[0, 6, 0]);
+		let mut port = FakePort::new(fake);
+		// What the `frames` command prints
+		port.rx
+			.extend(b"Frames mode - send '~.' between frames to leave\r\n\x00");
+		let mut bmc = Bmc::new(SerialTransport::new(port));
+		let mut version = [0u8; 3];
+		bmc.read(Command::ProtocolVersion, &mut version).unwrap();
+		assert_eq!(version, [0, 6, 0]);
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,122 @@
+//! Talks to the NBMC over SPI, as on a Neotron Pico.
+//!
+//! We drive `nCS` ourselves, rather than using an
+//! [`embedded_hal::spi::SpiDevice`], because we don't know how long the
+//! *Turn-Around* will be until we see the *Response* start.
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+use embedded_hal::{digital::OutputPin, spi::SpiBus};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// How many padding bytes we accept before giving up on a *Response*.
+///
+/// This is about 1 ms at 8 MHz, which is plenty unless the NBMC is writing
+/// to its flash.
+pub const MAX_TURNAROUND: usize = 1024;
+
+/// What the NBMC sends when it has nothing to say.
+const PADDING: u8 = 0xFF;
+
+// ============================================================================
+// Enums
+// ============================================================================
+
+/// The ways the SPI transport can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error<S, P> {
+	/// The SPI bus failed
+	Spi(S),
+	/// The chip select pin failed
+	Pin(P),
+	/// The NBMC didn't start its *Response* in time
+	Timeout,
+}
+
+// ============================================================================
+// Structs and Impls
+// ============================================================================
+
+/// An SPI bus and a chip select pin, with an NBMC on the end.
+pub struct SpiTransport<SPI, CS> {
+	spi: SPI,
+	cs: CS,
+	/// Are we waiting for a *Response* to start?
+	turnaround: bool,
+}
+
+impl<SPI, CS> SpiTransport<SPI, CS>
+where
+	SPI: SpiBus,
+	CS: OutputPin,
+{
+	/// Make a new transport.
+	///
+	/// The bus must be in SPI mode 0, at no more than 8 MHz.
+	pub fn new(spi: SPI, cs: CS) -> SpiTransport<SPI, CS> {
+		SpiTransport {
+			spi,
+			cs,
+			turnaround: false,
+		}
+	}
+
+	/// Give back the SPI bus and chip select pin.
+	pub fn release(self) -> (SPI, CS) {
+		(self.spi, self.cs)
+	}
+}
+
+impl<SPI, CS> crate::Transport for SpiTransport<SPI, CS>
+where
+	SPI: SpiBus,
+	CS: OutputPin,
+{
+	type Error = Error<SPI::Error, CS::Error>;
+
+	fn start(&mut self) -> Result<(), Self::Error> {
+		self.cs.set_low().map_err(Error::Pin)
+	}
+
+	fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+		self.spi.write(data).map_err(Error::Spi)?;
+		self.turnaround = true;
+		Ok(())
+	}
+
+	fn receive(&mut self, buffer: &mut [u8]) -> Result<(), Self::Error> {
+		let mut waited = 0;
+		for slot in buffer.iter_mut() {
+			loop {
+				let mut word = [PADDING];
+				self.spi.transfer_in_place(&mut word).map_err(Error::Spi)?;
+				if self.turnaround && word[0] == PADDING {
+					waited += 1;
+					if waited > MAX_TURNAROUND {
+						return Err(Error::Timeout);
+					}
+				} else {
+					self.turnaround = false;
+					*slot = word[0];
+					break;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn end(&mut self) -> Result<(), Self::Error> {
+		self.spi.flush().map_err(Error::Spi)?;
+		self.turnaround = false;
+		self.cs.set_high().map_err(Error::Pin)
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
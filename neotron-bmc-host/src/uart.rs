@@ -0,0 +1,381 @@
+//! Uses the NBMC's UART bridge as an [`embedded_io`] serial port.
+//!
+//! Bytes are read from the RX FIFO with FIFO reads of [`Command::UartBuffer`]
+//! (which are retried safely, like any other read), and written to the TX FIFO
+//! with *Long Writes*.
+//!
+//! Rather than reading the FIFOs over and over, we wait on the *UART RX Not
+//! Empty* and *UART TX Empty* bits in [`Command::InterruptStatus`]. These
+//! are active whether or not they are enabled in
+//! [`Command::InterruptControl`], so we don't disturb the host's interrupt
+//! settings.
+//!
+//! That wait is still a busy-poll of [`Command::InterruptStatus`] over the
+//! bus, as we have no way to sleep. If you'd rather wait on the NBMC's IRQ
+//! line, enable the UART bits in [`Command::InterruptControl`] and only call
+//! `read` once [`embedded_io::ReadReady::read_ready`] says so.
+//!
+//! The NBMC also keeps a copy of what we last sent, which
+//! [`UartPort::read_history`] fetches - handy after a crash.
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+use core::fmt::Debug;
+
+use embedded_io::ErrorKind;
+use neotron_bmc_commands::interrupt;
+pub use neotron_bmc_commands::uart::{
+	control, fifo_control, history, status, FIFO_LEN, HISTORY_LEN,
+};
+use neotron_bmc_protocol as proto;
+
+use crate::{Bmc, Command, Error, Transport, MAX_READ_LEN};
+
+// ============================================================================
+// Traits
+// ============================================================================
+
+impl<E> embedded_io::Error for Error<E>
+where
+	E: Debug,
+{
+	fn kind(&self) -> ErrorKind {
+		match self {
+			Error::Transport(_) => ErrorKind::Other,
+			Error::Protocol(_) => ErrorKind::InvalidData,
+			Error::Response(proto::ResponseResult::BadLength) => ErrorKind::InvalidInput,
+			Error::Response(_) => ErrorKind::Other,
+		}
+	}
+}
+
+// ============================================================================
+// Structs and Impls
+// ============================================================================
+
+/// The NBMC's UART. Get one with [`Bmc::uart_port`].
+pub struct UartPort<'a, T> {
+	bmc: &'a mut Bmc<T>,
+}
+
+impl<'a, T> UartPort<'a, T>
+where
+	T: Transport,
+{
+	/// Use the UART on the given NBMC.
+	pub fn new(bmc: &'a mut Bmc<T>) -> UartPort<'a, T> {
+		UartPort { bmc }
+	}
+
+	/// Set the baud rate, in bits per second.
+	pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error<T::Error>> {
+		self.bmc
+			.long_write(Command::UartBaudRate, &baud_rate.to_le_bytes())
+	}
+
+	/// Get the baud rate, in bits per second.
+	pub fn baud_rate(&mut self) -> Result<u32, Error<T::Error>> {
+		let mut bytes = [0u8; 4];
+		self.bmc.read(Command::UartBaudRate, &mut bytes)?;
+		Ok(u32::from_le_bytes(bytes))
+	}
+
+	/// Set the *UART Control* register (see [`control`]).
+	pub fn set_control(&mut self, bits: u8) -> Result<(), Error<T::Error>> {
+		self.bmc.write(Command::UartControl, bits)
+	}
+
+	/// Get the *UART Control* register (see [`control`]).
+	pub fn control(&mut self) -> Result<u8, Error<T::Error>> {
+		let mut bits = [0u8; 1];
+		self.bmc.read(Command::UartControl, &mut bits)?;
+		Ok(bits[0])
+	}
+
+	/// Read what we sent over the UART, either since we last started
+	/// ([`history::CURRENT`]) or before that ([`history::PREVIOUS`]).
+	///
+	/// Up to [`HISTORY_LEN`] bytes are kept. You get how many bytes were
+	/// copied into `buf`, oldest first.
+	pub fn read_history(&mut self, which: u8, buf: &mut [u8]) -> Result<usize, Error<T::Error>> {
+		// This also starts us from the oldest byte
+		self.bmc.write(Command::UartHistoryControl, which)?;
+		let mut count = 0;
+		while count < buf.len() {
+			// The first byte is how many bytes were left
+			let want = (buf.len() - count).min(MAX_READ_LEN - 1);
+			let mut buffer = [0u8; MAX_READ_LEN];
+			let buffer = &mut buffer[0..=want];
+			self.bmc.read(Command::UartHistory, buffer)?;
+			let got = usize::from(buffer[0]).min(want);
+			if got == 0 {
+				break;
+			}
+			buf[count..count + got].copy_from_slice(&buffer[1..=got]);
+			count += got;
+		}
+		Ok(count)
+	}
+
+	/// Get the *Interrupt Status* register.
+	fn interrupt_status(&mut self) -> Result<u16, Error<T::Error>> {
+		let mut bits = [0u8; 2];
+		self.bmc.read(Command::InterruptStatus, &mut bits)?;
+		Ok(u16::from_le_bytes(bits))
+	}
+
+	/// Wait until any of the given interrupt bits is active.
+	///
+	/// This reads the *Interrupt Status* register in a tight loop, so it
+	/// keeps the bus busy until the bits turn up.
+	fn wait_for(&mut self, bits: u16) -> Result<(), Error<T::Error>> {
+		while (self.interrupt_status()? & bits) == 0 {
+			// Spin
+		}
+		Ok(())
+	}
+}
+
+impl<'a, T> embedded_io::ErrorType for UartPort<'a, T>
+where
+	T: Transport,
+	T::Error: Debug,
+{
+	type Error = Error<T::Error>;
+}
+
+impl<'a, T> embedded_io::ReadReady for UartPort<'a, T>
+where
+	T: Transport,
+	T::Error: Debug,
+{
+	fn read_ready(&mut self) -> Result<bool, Self::Error> {
+		Ok((self.interrupt_status()? & interrupt::UART_RX_NOT_EMPTY) != 0)
+	}
+}
+
+impl<'a, T> embedded_io::Read for UartPort<'a, T>
+where
+	T: Transport,
+	T::Error: Debug,
+{
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		loop {
+			self.wait_for(interrupt::UART_RX_NOT_EMPTY)?;
+			// The first byte is how many bytes there were
+			let want = buf.len().min(MAX_READ_LEN - 1);
+			let mut buffer = [0u8; MAX_READ_LEN];
+			let buffer = &mut buffer[0..=want];
+			self.bmc.read(Command::UartBuffer, buffer)?;
+			let got = usize::from(buffer[0]).min(want);
+			if got > 0 {
+				buf[0..got].copy_from_slice(&buffer[1..=got]);
+				return Ok(got);
+			}
+		}
+	}
+}
+
+impl<'a, T> embedded_io::Write for UartPort<'a, T>
+where
+	T: Transport,
+	T::Error: Debug,
+{
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		loop {
+			let mut waiting = [0u8; 1];
+			self.bmc.read(Command::UartFifoControl, &mut waiting)?;
+			let space = FIFO_LEN.saturating_sub(usize::from(waiting[0]));
+			let len = buf.len().min(space);
+			if len == 1 {
+				self.bmc.write(Command::UartBuffer, buf[0])?;
+				return Ok(1);
+			} else if len > 1 {
+				self.bmc.long_write(Command::UartBuffer, &buf[0..len])?;
+				return Ok(len);
+			}
+			// The TX FIFO is full, so wait for it to drain
+			self.wait_for(interrupt::UART_TX_EMPTY)?;
+		}
+	}
+
+	fn flush(&mut self) -> Result<(), Self::Error> {
+		self.wait_for(interrupt::UART_TX_EMPTY)
+	}
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::fake::FakeBmc;
+	use embedded_io::{Read, ReadReady, Write};
+
+	#[test]
+	fn read() {
+		let mut fake = FakeBmc::new();
+		fake.receive_uart(b"Hello");
+		let mut bmc = Bmc::new(fake);
+		let mut port = bmc.uart_port();
+		assert!(port.read_ready().unwrap());
+		let mut buffer = [0u8; 16];
+		assert_eq!(port.read(&mut buffer).unwrap(), 5);
+		assert_eq!(&buffer[0..5], b"Hello");
+		assert!(!port.read_ready().unwrap());
+	}
+
+	#[test]
+	fn read_full_fifo() {
+		let mut fake = FakeBmc::new();
+		let data: Vec<u8> = (0..FIFO_LEN as u8).collect();
+		fake.receive_uart(&data);
+		let mut bmc = Bmc::new(fake);
+		let mut port = bmc.uart_port();
+		// More than one read fits in a Response
+		let mut buffer = [0u8; FIFO_LEN];
+		let first = port.read(&mut buffer).unwrap();
+		assert_eq!(first, MAX_READ_LEN - 1);
+		let second = port.read(&mut buffer[first..]).unwrap();
+		assert_eq!(first + second, FIFO_LEN);
+		assert_eq!(&buffer[..], &data[..]);
+	}
+
+	#[test]
+	fn read_waits() {
+		let mut fake = FakeBmc::new();
+		fake.receive_uart_later(b"Hi", 3);
+		let mut bmc = Bmc::new(fake);
+		let mut buffer = [0u8; 2];
+		bmc.uart_port().read_exact(&mut buffer).unwrap();
+		assert_eq!(&buffer, b"Hi");
+		// We waited on the interrupt status, not by reading the FIFO
+		let fake = bmc.release();
+		let fifo_reads = fake
+			.requests()
+			.iter()
+			.filter(|r| r.register == Command::UartBuffer as u8)
+			.count();
+		assert_eq!(fifo_reads, 1);
+	}
+
+	#[test]
+	fn read_retries_without_losing_bytes() {
+		let mut fake = FakeBmc::new();
+		fake.receive_uart(b"abc");
+		let mut bmc = Bmc::new(fake);
+		let mut port = bmc.uart_port();
+		// Check the status first, so the FIFO read is what gets corrupted
+		assert!(port.read_ready().unwrap());
+		port.bmc.transport.corrupt_responses(1);
+		let mut buffer = [0u8; 3];
+		assert_eq!(port.read(&mut buffer).unwrap(), 3);
+		assert_eq!(&buffer, b"abc");
+	}
+
+	#[test]
+	fn write() {
+		let mut bmc = Bmc::new(FakeBmc::new());
+		let mut port = bmc.uart_port();
+		port.write_all(b"Hello, world!").unwrap();
+		port.write_all(b"!").unwrap();
+		port.flush().unwrap();
+		let fake = bmc.release();
+		assert_eq!(fake.uart_sent(), b"Hello, world!!");
+	}
+
+	#[test]
+	fn write_waits_for_space() {
+		let data: Vec<u8> = (0..100).collect();
+		let mut bmc = Bmc::new(FakeBmc::new());
+		bmc.uart_port().write_all(&data).unwrap();
+		bmc.uart_port().flush().unwrap();
+		let fake = bmc.release();
+		assert_eq!(fake.uart_sent(), data.as_slice());
+	}
+
+	#[test]
+	fn settings() {
+		let mut bmc = Bmc::new(FakeBmc::new());
+		let mut port = bmc.uart_port();
+		port.set_baud_rate(9600).unwrap();
+		assert_eq!(port.baud_rate().unwrap(), 9600);
+		port.set_control(control::ENABLE | control::PARITY_EVEN)
+			.unwrap();
+		let fake = bmc.release();
+		assert_eq!(
+			fake.writes(Command::UartControl),
+			&[control::ENABLE | control::PARITY_EVEN]
+		);
+	}
+
+	#[test]
+	fn history() {
+		let mut fake = FakeBmc::new();
+		fake.set_uart_history(b"Kernel panic!", b"Booting...");
+		let mut bmc = Bmc::new(fake);
+		let mut port = bmc.uart_port();
+		let mut buffer = [0u8; HISTORY_LEN];
+		let len = port.read_history(history::PREVIOUS, &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], b"Kernel panic!");
+		let len = port.read_history(history::CURRENT, &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], b"Booting...");
+		// It can be read again, in pieces
+		let mut small = [0u8; 4];
+		assert_eq!(port.read_history(history::CURRENT, &mut small).unwrap(), 4);
+		assert_eq!(&small, b"Boot");
+	}
+
+	#[test]
+	fn long_history() {
+		let mut fake = FakeBmc::new();
+		let data: Vec<u8> = (0..100).collect();
+		fake.set_uart_history(&data, b"");
+		let mut bmc = Bmc::new(fake);
+		let mut buffer = [0u8; HISTORY_LEN];
+		let len = bmc
+			.uart_port()
+			.read_history(history::PREVIOUS, &mut buffer)
+			.unwrap();
+		assert_eq!(&buffer[0..len], &data[..]);
+	}
+
+	#[test]
+	fn history_is_what_we_sent() {
+		let data: Vec<u8> = (0..200).collect();
+		let mut bmc = Bmc::new(FakeBmc::new());
+		let mut port = bmc.uart_port();
+		port.write_all(&data).unwrap();
+		// Only the last few bytes are kept, and it takes more than one read
+		let mut buffer = [0u8; 256];
+		let len = port.read_history(history::CURRENT, &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], &data[data.len() - HISTORY_LEN..]);
+	}
+
+	#[test]
+	fn error_kinds() {
+		use embedded_io::Error as _;
+		assert_eq!(
+			Error::<()>::Response(proto::ResponseResult::BadLength).kind(),
+			ErrorKind::InvalidInput
+		);
+		assert_eq!(
+			Error::<()>::Protocol(proto::Error::BadCrc).kind(),
+			ErrorKind::InvalidData
+		);
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,353 @@
+#![doc = include_str!("../README.md")]
+#![cfg_attr(not(test), no_std)]
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// The actions the BMC can take in response to a keyboard shortcut.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Action {
+	/// Ctrl-Alt-Del was pressed - reset the host.
+	Reset,
+	/// An ACPI Power, Sleep or Wake key was pressed.
+	PowerPress,
+	/// An ACPI Power, Sleep or Wake key was released.
+	PowerRelease,
+	/// Ctrl-Alt-Power was pressed - turn the power off.
+	ForcePowerOff,
+}
+
+/// Which shortcuts are enabled, as stored in the *PS/2 Keyboard Shortcuts*
+/// register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShortcutConfig(u8);
+
+/// Tracks the state of the keys we care about.
+#[derive(Debug, Default)]
+pub struct KeyTracker {
+	/// The last byte was 0xE0
+	extended: bool,
+	/// The last byte was 0xF0
+	release: bool,
+	/// How many bytes of a Pause/Break sequence are left to ignore
+	skip: u8,
+	left_ctrl: bool,
+	right_ctrl: bool,
+	left_alt: bool,
+	right_alt: bool,
+	/// One of the ACPI keys is held down
+	acpi: bool,
+	/// The make code of the key pressed by the last byte (if any)
+	make_code: Option<u8>,
+}
+
+// ============================================================================
+// Impls
+// ============================================================================
+
+impl ShortcutConfig {
+	/// Ctrl-Alt-Del resets the host
+	pub const RESET: u8 = 1 << 0;
+	/// ACPI Power/Sleep/Wake keys power on the host
+	pub const POWER_ON: u8 = 1 << 1;
+	/// Ctrl-Alt-Power forces the host off
+	pub const POWER_OFF: u8 = 1 << 2;
+	/// All the bits that mean something
+	pub const ALL: u8 = Self::RESET | Self::POWER_ON | Self::POWER_OFF;
+
+	/// Create a new config from a register value. Unknown bits are ignored.
+	pub const fn new(bits: u8) -> ShortcutConfig {
+		ShortcutConfig(bits & Self::ALL)
+	}
+
+	/// Get the register value
+	pub const fn bits(&self) -> u8 {
+		self.0
+	}
+
+	/// Is the given action enabled?
+	pub const fn is_enabled(&self, action: Action) -> bool {
+		let mask = match action {
+			Action::Reset => Self::RESET,
+			Action::PowerPress | Action::PowerRelease => Self::POWER_ON,
+			Action::ForcePowerOff => Self::POWER_OFF,
+		};
+		(self.0 & mask) != 0
+	}
+}
+
+impl Default for ShortcutConfig {
+	/// Everything is on by default
+	fn default() -> Self {
+		ShortcutConfig(Self::ALL)
+	}
+}
+
+impl KeyTracker {
+	/// Prefix for extended keys
+	const EXTENDED: u8 = 0xE0;
+	/// Prefix for key releases
+	const RELEASE: u8 = 0xF0;
+	/// Prefix for the Pause/Break sequence (E1 14 77 E1 F0 14 F0 77)
+	const PAUSE: u8 = 0xE1;
+	const PAUSE_LEN: u8 = 7;
+
+	const CTRL: u8 = 0x14;
+	const ALT: u8 = 0x11;
+	/// Delete (extended) or Keypad '.' (not extended)
+	const DELETE: u8 = 0x71;
+	const ACPI_POWER: u8 = 0x37;
+	const ACPI_SLEEP: u8 = 0x3F;
+	const ACPI_WAKE: u8 = 0x5E;
+	/// The highest make code in Scan Code Set 2. Anything above this is a
+	/// reply from the keyboard (like 0xAA for 'self-test passed').
+	const MAX_MAKE_CODE: u8 = 0x83;
+
+	/// Create a new tracker, with no keys held down.
+	pub const fn new() -> KeyTracker {
+		KeyTracker {
+			extended: false,
+			release: false,
+			skip: 0,
+			left_ctrl: false,
+			right_ctrl: false,
+			left_alt: false,
+			right_alt: false,
+			acpi: false,
+			make_code: None,
+		}
+	}
+
+	/// Forget all the keys (e.g. if the keyboard was unplugged).
+	pub fn reset(&mut self) {
+		*self = KeyTracker::new();
+	}
+
+	/// Is either Ctrl key held down?
+	fn ctrl(&self) -> bool {
+		self.left_ctrl || self.right_ctrl
+	}
+
+	/// Is either Alt key held down?
+	fn alt(&self) -> bool {
+		self.left_alt || self.right_alt
+	}
+
+	/// If the last byte given to [`Self::handle_byte`] completed a key
+	/// press, get the make code of that key.
+	///
+	/// Any `0xE0` prefix is not included, so Delete and Keypad '.' both give
+	/// `0x71`.
+	pub fn make_code(&self) -> Option<u8> {
+		self.make_code
+	}
+
+	/// Feed in a byte from the keyboard.
+	///
+	/// If that byte completed a shortcut, you get back the action to take.
+	pub fn handle_byte(&mut self, byte: u8) -> Option<Action> {
+		self.make_code = None;
+		if self.skip > 0 {
+			self.skip -= 1;
+			return None;
+		}
+		match byte {
+			Self::EXTENDED => {
+				self.extended = true;
+				return None;
+			}
+			Self::RELEASE => {
+				self.release = true;
+				return None;
+			}
+			Self::PAUSE => {
+				self.skip = Self::PAUSE_LEN;
+				self.extended = false;
+				self.release = false;
+				return None;
+			}
+			_ => {}
+		}
+
+		let pressed = !self.release;
+		let extended = self.extended;
+		self.extended = false;
+		self.release = false;
+
+		if pressed && byte != 0 && byte <= Self::MAX_MAKE_CODE {
+			self.make_code = Some(byte);
+		}
+
+		match (extended, byte) {
+			(false, Self::CTRL) => self.left_ctrl = pressed,
+			(true, Self::CTRL) => self.right_ctrl = pressed,
+			(false, Self::ALT) => self.left_alt = pressed,
+			(true, Self::ALT) => self.right_alt = pressed,
+			(_, Self::DELETE) if pressed && self.ctrl() && self.alt() => {
+				return Some(Action::Reset);
+			}
+			(true, Self::ACPI_POWER) if pressed && self.ctrl() && self.alt() => {
+				return Some(Action::ForcePowerOff);
+			}
+			(true, Self::ACPI_POWER | Self::ACPI_SLEEP | Self::ACPI_WAKE) => {
+				if pressed && !self.acpi {
+					self.acpi = true;
+					return Some(Action::PowerPress);
+				} else if !pressed && self.acpi {
+					self.acpi = false;
+					return Some(Action::PowerRelease);
+				}
+			}
+			_ => {}
+		}
+		None
+	}
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Feed in some bytes, and collect the actions they caused.
+	fn feed(tracker: &mut KeyTracker, bytes: &[u8]) -> Vec<Action> {
+		bytes
+			.iter()
+			.filter_map(|b| tracker.handle_byte(*b))
+			.collect()
+	}
+
+	#[test]
+	fn ctrl_alt_del() {
+		let mut tracker = KeyTracker::new();
+		// Left Ctrl, Left Alt, Delete
+		assert_eq!(feed(&mut tracker, &[0x14, 0x11]), vec![]);
+		assert_eq!(feed(&mut tracker, &[0xE0, 0x71]), vec![Action::Reset]);
+		// Still held, so Keypad '.' works too
+		assert_eq!(feed(&mut tracker, &[0x71]), vec![Action::Reset]);
+	}
+
+	#[test]
+	fn ctrl_alt_del_right_hand() {
+		let mut tracker = KeyTracker::new();
+		// Right Ctrl, Right Alt (AltGr), Delete
+		assert_eq!(
+			feed(&mut tracker, &[0xE0, 0x14, 0xE0, 0x11, 0xE0, 0x71]),
+			vec![Action::Reset]
+		);
+	}
+
+	#[test]
+	fn released_modifiers() {
+		let mut tracker = KeyTracker::new();
+		// Ctrl and Alt pressed, then Ctrl released
+		assert_eq!(feed(&mut tracker, &[0x14, 0x11, 0xF0, 0x14]), vec![]);
+		assert_eq!(feed(&mut tracker, &[0xE0, 0x71]), vec![]);
+		// Releasing Right Ctrl doesn't release Left Ctrl
+		assert_eq!(
+			feed(&mut tracker, &[0x14, 0xE0, 0xF0, 0x14, 0xE0, 0x71]),
+			vec![Action::Reset]
+		);
+		// Releasing Delete does nothing
+		assert_eq!(feed(&mut tracker, &[0xE0, 0xF0, 0x71]), vec![]);
+	}
+
+	#[test]
+	fn acpi_keys() {
+		let mut tracker = KeyTracker::new();
+		// Power, auto-repeated, then released
+		assert_eq!(
+			feed(&mut tracker, &[0xE0, 0x37, 0xE0, 0x37]),
+			vec![Action::PowerPress]
+		);
+		assert_eq!(
+			feed(&mut tracker, &[0xE0, 0xF0, 0x37]),
+			vec![Action::PowerRelease]
+		);
+		// Sleep and Wake
+		assert_eq!(
+			feed(&mut tracker, &[0xE0, 0x3F, 0xE0, 0xF0, 0x3F]),
+			vec![Action::PowerPress, Action::PowerRelease]
+		);
+		assert_eq!(
+			feed(&mut tracker, &[0xE0, 0x5E, 0xE0, 0xF0, 0x5E]),
+			vec![Action::PowerPress, Action::PowerRelease]
+		);
+		// Without the prefix, these are other keys
+		assert_eq!(feed(&mut tracker, &[0x37, 0x3F, 0x5E]), vec![]);
+	}
+
+	#[test]
+	fn ctrl_alt_power() {
+		let mut tracker = KeyTracker::new();
+		assert_eq!(
+			feed(&mut tracker, &[0x14, 0x11, 0xE0, 0x37]),
+			vec![Action::ForcePowerOff]
+		);
+	}
+
+	#[test]
+	fn pause_is_skipped() {
+		let mut tracker = KeyTracker::new();
+		// Pause/Break contains a Ctrl make code, which mustn't count
+		let pause = [0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77];
+		assert_eq!(feed(&mut tracker, &pause), vec![]);
+		assert_eq!(feed(&mut tracker, &[0x11, 0x71]), vec![]);
+		// But a real Ctrl press straight after does
+		assert_eq!(feed(&mut tracker, &[0x14, 0x71]), vec![Action::Reset]);
+	}
+
+	#[test]
+	fn make_codes() {
+		let mut tracker = KeyTracker::new();
+		tracker.handle_byte(0x1C);
+		assert_eq!(tracker.make_code(), Some(0x1C));
+		// Prefixes aren't keys, and the prefix is dropped from the code
+		tracker.handle_byte(0xE0);
+		assert_eq!(tracker.make_code(), None);
+		tracker.handle_byte(0x71);
+		assert_eq!(tracker.make_code(), Some(0x71));
+		// Releases aren't presses
+		feed(&mut tracker, &[0xF0, 0x1C]);
+		assert_eq!(tracker.make_code(), None);
+		// Nor are replies from the keyboard
+		tracker.handle_byte(0xAA);
+		assert_eq!(tracker.make_code(), None);
+	}
+
+	#[test]
+	fn reset_forgets_keys() {
+		let mut tracker = KeyTracker::new();
+		feed(&mut tracker, &[0x14, 0x11, 0xE0]);
+		tracker.reset();
+		assert_eq!(feed(&mut tracker, &[0x71]), vec![]);
+	}
+
+	#[test]
+	fn shortcut_config() {
+		let config = ShortcutConfig::new(0xFF);
+		assert_eq!(config.bits(), ShortcutConfig::ALL);
+		assert_eq!(ShortcutConfig::default(), config);
+		let config = ShortcutConfig::new(ShortcutConfig::POWER_ON);
+		assert!(!config.is_enabled(Action::Reset));
+		assert!(config.is_enabled(Action::PowerPress));
+		assert!(config.is_enabled(Action::PowerRelease));
+		assert!(!config.is_enabled(Action::ForcePowerOff));
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -1,12 +1,18 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
-  RAM : ORIGIN = 0x20000000, LENGTH = 4K
+  /* The first two 1K pages hold the bootloader - see `neotron-bmc-bootloader` */
+  FLASH : ORIGIN = 0x08000800, LENGTH = 14K
+  /* New firmware is received here - see `src/update.rs` */
+  STAGING : ORIGIN = 0x08004000, LENGTH = 14K
+  /* The last two 1K pages hold the BMC settings - see `src/config.rs` */
+  CONFIG : ORIGIN = 0x08007800, LENGTH = 2K
+  /* The first 192 bytes of RAM hold a copy of our vector table, as the
+   * Cortex-M0 has no VTOR - see `update::use_ram_vector_table` */
+  RAM : ORIGIN = 0x200000C0, LENGTH = 0xF40
 }
 
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
-
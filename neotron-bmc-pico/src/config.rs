@@ -0,0 +1,439 @@
+//! # Configuration Store
+//!
+//! Keeps the BMC's settings in the last two pages of flash (see `memory.x`),
+//! so they survive the BMC being reset or unplugged.
+//!
+//! Each page starts with an eight-byte header, followed by eight-byte
+//! records. Saving the settings appends a record for each value which has
+//! changed, so we only erase a page when one fills up - at which point we
+//! copy the latest values into the other page and start using that instead.
+//! Every header and record has a CRC, and a page's header is only written
+//! once the page is complete, so losing power part way through a write
+//! leaves us with the previous settings.
+
+use neotron_bmc_protocol as proto;
+use neotron_bmc_shell as shell;
+
+use crate::flash::{Error, Flash, PAGE_SIZE};
+
+/// Where the store starts. Must match the `CONFIG` region in `memory.x`.
+const STORE_START: usize = 0x0800_7800;
+/// We ping-pong between two pages
+const NUM_PAGES: usize = 2;
+/// Both headers and records are this long
+const RECORD_SIZE: usize = 8;
+/// Bumped whenever the meaning of the records changes
+const FORMAT_VERSION: u8 = 1;
+/// Marks a page header
+const MAGIC: [u8; 3] = *b"NBC";
+
+/// What the host can ask us to do, through the *Config Control* register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Operation {
+	/// Save the current settings
+	Save = 1,
+	/// Forget the saved settings, and go back to the factory defaults
+	FactoryReset = 2,
+}
+
+impl Operation {
+	/// Convert from the *Config Control* register.
+	pub fn from_bits(bits: u8) -> Option<Operation> {
+		match bits {
+			1 => Some(Operation::Save),
+			2 => Some(Operation::FactoryReset),
+			_ => None,
+		}
+	}
+}
+
+/// The result of the last [`Operation`], as read from the *Config Control*
+/// register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+	/// Nothing has gone wrong
+	Ok = 0,
+	/// An operation is waiting to be carried out
+	Busy = 1,
+	/// The last operation failed
+	Failed = 2,
+}
+
+impl Default for Status {
+	fn default() -> Self {
+		Status::Ok
+	}
+}
+
+/// Identifies each value in the store.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Key {
+	/// How many times the settings have been saved
+	SaveCount = 1,
+	KbShortcuts = 2,
+	PowerPolicy = 3,
+	WakeKey = 4,
+	InterruptControl = 5,
+	HostWatchdogAction = 6,
+	SpeakerFrequency = 7,
+	SpeakerDutyCycle = 8,
+	/// Whether the host was powered on (1) or off (0)
+	LastPowerOn = 9,
+}
+
+impl Key {
+	/// All the keys which hold a [`Settings`] field
+	const SETTINGS: [Key; 7] = [
+		Key::KbShortcuts,
+		Key::PowerPolicy,
+		Key::WakeKey,
+		Key::InterruptControl,
+		Key::HostWatchdogAction,
+		Key::SpeakerFrequency,
+		Key::SpeakerDutyCycle,
+	];
+
+	fn from_byte(byte: u8) -> Option<Key> {
+		match byte {
+			1 => Some(Key::SaveCount),
+			2 => Some(Key::KbShortcuts),
+			3 => Some(Key::PowerPolicy),
+			4 => Some(Key::WakeKey),
+			5 => Some(Key::InterruptControl),
+			6 => Some(Key::HostWatchdogAction),
+			7 => Some(Key::SpeakerFrequency),
+			8 => Some(Key::SpeakerDutyCycle),
+			9 => Some(Key::LastPowerOn),
+			_ => None,
+		}
+	}
+
+	fn from_shell(setting: shell::Setting) -> Key {
+		match setting {
+			shell::Setting::KbShortcuts => Key::KbShortcuts,
+			shell::Setting::PowerPolicy => Key::PowerPolicy,
+			shell::Setting::WakeKey => Key::WakeKey,
+			shell::Setting::InterruptControl => Key::InterruptControl,
+			shell::Setting::WatchdogAction => Key::HostWatchdogAction,
+			shell::Setting::SpeakerFrequency => Key::SpeakerFrequency,
+			shell::Setting::SpeakerDutyCycle => Key::SpeakerDutyCycle,
+		}
+	}
+}
+
+/// The settings we keep, as raw register values.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Settings {
+	/// The *PS/2 Keyboard Shortcuts* register
+	pub kb_shortcuts: u8,
+	/// The *Power Policy* register
+	pub power_policy: u8,
+	/// The *Wake Key* register
+	pub wake_key: u8,
+	/// The *Interrupt Control* register
+	pub interrupt_control: u16,
+	/// The *Host Watchdog Control* register
+	pub host_watchdog_action: u8,
+	/// The *Speaker Frequency* register
+	pub speaker_frequency: u16,
+	/// The *Speaker Duty Cycle* register
+	pub speaker_duty_cycle: u8,
+}
+
+impl Settings {
+	/// Get the factory default settings.
+	pub const fn new() -> Settings {
+		Settings {
+			kb_shortcuts: neotron_bmc_keyboard::ShortcutConfig::ALL,
+			power_policy: crate::power::PowerPolicy::new().bits(),
+			wake_key: crate::power::PowerPolicy::new().wake_key(),
+			interrupt_control: neotron_bmc_commands::interrupt::PS2_KB_RX_NOT_EMPTY,
+			host_watchdog_action: crate::host_watchdog::Action::Interrupt as u8,
+			speaker_frequency: 0,
+			speaker_duty_cycle: 0,
+		}
+	}
+
+	fn get(&self, key: Key) -> u32 {
+		match key {
+			Key::SaveCount | Key::LastPowerOn => 0,
+			Key::KbShortcuts => u32::from(self.kb_shortcuts),
+			Key::PowerPolicy => u32::from(self.power_policy),
+			Key::WakeKey => u32::from(self.wake_key),
+			Key::InterruptControl => u32::from(self.interrupt_control),
+			Key::HostWatchdogAction => u32::from(self.host_watchdog_action),
+			Key::SpeakerFrequency => u32::from(self.speaker_frequency),
+			Key::SpeakerDutyCycle => u32::from(self.speaker_duty_cycle),
+		}
+	}
+
+	fn set(&mut self, key: Key, value: u32) {
+		match key {
+			Key::SaveCount | Key::LastPowerOn => {}
+			Key::KbShortcuts => self.kb_shortcuts = value as u8,
+			Key::PowerPolicy => self.power_policy = value as u8,
+			Key::WakeKey => self.wake_key = value as u8,
+			Key::InterruptControl => self.interrupt_control = value as u16,
+			Key::HostWatchdogAction => self.host_watchdog_action = value as u8,
+			Key::SpeakerFrequency => self.speaker_frequency = value as u16,
+			Key::SpeakerDutyCycle => self.speaker_duty_cycle = value as u8,
+		}
+	}
+
+	/// Get a setting, as shown by the BMC shell's `config get` command.
+	pub fn shell_value(&self, setting: shell::Setting) -> u16 {
+		self.get(Key::from_shell(setting)) as u16
+	}
+
+	/// Change a setting, with the BMC shell's `config set` command.
+	pub fn set_shell_value(&mut self, setting: shell::Setting, value: u16) {
+		self.set(Key::from_shell(setting), u32::from(value));
+	}
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Settings::new()
+	}
+}
+
+/// Build a record (or a header), with its CRC.
+fn make_record(first: u8, value: u32) -> [u8; RECORD_SIZE] {
+	let mut record = [0u8; RECORD_SIZE];
+	record[0] = first;
+	record[1..5].copy_from_slice(&value.to_le_bytes());
+	record[RECORD_SIZE - 1] = proto::calculate_crc(&record[0..RECORD_SIZE - 1]);
+	record
+}
+
+/// Build a page header.
+fn make_header(generation: u8) -> [u8; RECORD_SIZE] {
+	let mut header = [0u8; RECORD_SIZE];
+	header[0..3].copy_from_slice(&MAGIC);
+	header[3] = FORMAT_VERSION;
+	header[4] = generation;
+	header[RECORD_SIZE - 1] = proto::calculate_crc(&header[0..RECORD_SIZE - 1]);
+	header
+}
+
+/// If this is a good page header, get its generation.
+fn check_header(header: &[u8]) -> Option<u8> {
+	if header[0..3] == MAGIC
+		&& header[3] == FORMAT_VERSION
+		&& proto::calculate_crc(&header[0..RECORD_SIZE - 1]) == header[RECORD_SIZE - 1]
+	{
+		Some(header[4])
+	} else {
+		None
+	}
+}
+
+/// Is `a` a later generation than `b`? Copes with wrapping.
+fn is_newer(a: u8, b: u8) -> bool {
+	(a.wrapping_sub(b) as i8) > 0
+}
+
+/// The settings store.
+pub struct Store {
+	/// Which page we're using, and its generation
+	active: Option<(usize, u8)>,
+	/// Where the next record goes in the active page
+	next_free: usize,
+	/// The values currently in flash
+	stored: Settings,
+	/// How many times the settings have been saved (0 = never)
+	save_count: u16,
+	/// Whether the host was on, when we last looked
+	last_power_on: bool,
+}
+
+impl Default for Store {
+	fn default() -> Self {
+		Store::new()
+	}
+}
+
+impl Store {
+	/// Find the latest settings in flash.
+	pub fn new() -> Store {
+		let mut store = Store {
+			active: None,
+			next_free: RECORD_SIZE,
+			stored: Settings::new(),
+			save_count: 0,
+			last_power_on: false,
+		};
+		for page in 0..NUM_PAGES {
+			if let Some(generation) = check_header(&Self::page(page)[0..RECORD_SIZE]) {
+				let newer = match store.active {
+					Some((_, active_gen)) => is_newer(generation, active_gen),
+					None => true,
+				};
+				if newer {
+					store.active = Some((page, generation));
+				}
+			}
+		}
+		if let Some((page, _)) = store.active {
+			store.replay(page);
+		}
+		store
+	}
+
+	/// Get the address of a page.
+	fn page_addr(page: usize) -> usize {
+		STORE_START + (page * PAGE_SIZE)
+	}
+
+	/// Get a page of flash, as a slice.
+	fn page(page: usize) -> &'static [u8] {
+		// Safety: this is flash, reserved for us in `memory.x`, and we only
+		// change it through `&mut self` methods.
+		unsafe { Flash::read(Self::page_addr(page), PAGE_SIZE) }
+	}
+
+	/// Read all the records in a page, to find the latest values.
+	fn replay(&mut self, page: usize) {
+		let data = Self::page(page);
+		self.next_free = PAGE_SIZE;
+		for offset in (RECORD_SIZE..PAGE_SIZE).step_by(RECORD_SIZE) {
+			let record = &data[offset..offset + RECORD_SIZE];
+			if record.iter().all(|b| *b == 0xFF) {
+				// Erased - this is the end of the records
+				self.next_free = offset;
+				break;
+			}
+			if proto::calculate_crc(&record[0..RECORD_SIZE - 1]) != record[RECORD_SIZE - 1] {
+				// Half-written (we lost power) - skip it
+				defmt::warn!("Bad config record at {}", offset);
+				continue;
+			}
+			let value = u32::from_le_bytes([record[1], record[2], record[3], record[4]]);
+			match Key::from_byte(record[0]) {
+				Some(Key::SaveCount) => self.save_count = value as u16,
+				Some(Key::LastPowerOn) => self.last_power_on = value != 0,
+				Some(key) => self.stored.set(key, value),
+				None => defmt::warn!("Unknown config key {}", record[0]),
+			}
+		}
+	}
+
+	/// Get the saved settings, or `None` if the store is empty.
+	pub fn load(&self) -> Option<Settings> {
+		self.active.map(|_| self.stored)
+	}
+
+	/// How many times the settings have been saved since the last factory
+	/// reset. This is the *Config Version* register.
+	pub fn save_count(&self) -> u16 {
+		self.save_count
+	}
+
+	/// Save these settings.
+	pub fn save(&mut self, flash: &mut Flash, settings: &Settings) -> Result<(), Error> {
+		// One record per setting, plus the save count
+		let mut records: heapless::Vec<(Key, u32), 8> = heapless::Vec::new();
+		for key in Key::SETTINGS.iter() {
+			let value = settings.get(*key);
+			if value != self.stored.get(*key) {
+				let _ = records.push((*key, value));
+			}
+		}
+		let save_count = self.save_count.saturating_add(1);
+		// This goes last, so it only counts if everything else was written
+		let _ = records.push((Key::SaveCount, u32::from(save_count)));
+
+		let old_settings = self.stored;
+		let old_save_count = self.save_count;
+		self.stored = *settings;
+		self.save_count = save_count;
+		let result = self.write(flash, &records);
+		if result.is_err() {
+			self.stored = old_settings;
+			self.save_count = old_save_count;
+		}
+		result
+	}
+
+	/// Was the host powered on, when we last recorded it?
+	pub fn last_power_on(&self) -> bool {
+		self.last_power_on
+	}
+
+	/// Record whether the host is powered on.
+	pub fn set_last_power_on(
+		&mut self,
+		flash: &mut Flash,
+		last_power_on: bool,
+	) -> Result<(), Error> {
+		if self.active.is_some() && last_power_on == self.last_power_on {
+			// Nothing to do
+			return Ok(());
+		}
+		self.last_power_on = last_power_on;
+		let result = self.write(flash, &[(Key::LastPowerOn, u32::from(last_power_on))]);
+		if result.is_err() {
+			self.last_power_on = !last_power_on;
+		}
+		result
+	}
+
+	/// Forget the saved settings.
+	pub fn factory_reset(&mut self, flash: &mut Flash) -> Result<(), Error> {
+		let result = (0..NUM_PAGES).try_for_each(|page| flash.erase_page(Self::page_addr(page)));
+		self.active = None;
+		self.next_free = RECORD_SIZE;
+		self.stored = Settings::new();
+		self.save_count = 0;
+		self.last_power_on = false;
+		result
+	}
+
+	/// Write some records to the end of the active page. If they won't fit,
+	/// everything (which must already be updated) goes into the other page.
+	fn write(&mut self, flash: &mut Flash, records: &[(Key, u32)]) -> Result<(), Error> {
+		match self.active {
+			Some((page, _)) if self.next_free + (records.len() * RECORD_SIZE) <= PAGE_SIZE => {
+				records.iter().try_for_each(|(key, value)| {
+					flash.program(
+						Self::page_addr(page) + self.next_free,
+						&make_record(*key as u8, *value),
+					)?;
+					self.next_free += RECORD_SIZE;
+					Ok(())
+				})
+			}
+			_ => self.compact(flash),
+		}
+	}
+
+	/// Write all the values to a freshly erased page, and switch to it.
+	fn compact(&mut self, flash: &mut Flash) -> Result<(), Error> {
+		let (page, generation) = match self.active {
+			Some((page, generation)) => ((page + 1) % NUM_PAGES, generation.wrapping_add(1)),
+			None => (0, 0),
+		};
+		defmt::info!("Compacting config into page {}", page);
+		flash.erase_page(Self::page_addr(page))?;
+		let mut offset = RECORD_SIZE;
+		let others = [
+			(Key::LastPowerOn, u32::from(self.last_power_on)),
+			(Key::SaveCount, u32::from(self.save_count)),
+		];
+		let settings = Key::SETTINGS
+			.iter()
+			.map(|key| (*key, self.stored.get(*key)));
+		for (key, value) in settings.chain(others.iter().cloned()) {
+			flash.program(
+				Self::page_addr(page) + offset,
+				&make_record(key as u8, value),
+			)?;
+			offset += RECORD_SIZE;
+		}
+		// The header goes last, so the page only counts once it's complete
+		flash.program(Self::page_addr(page), &make_header(generation))?;
+		self.active = Some((page, generation));
+		self.next_free = offset;
+		Ok(())
+	}
+}
@@ -0,0 +1,133 @@
+//! # Crash Records
+//!
+//! If the firmware panics, we note where it happened and then reset, so the
+//! power button keeps working. The note is kept in a RAM section which isn't
+//! zeroed at start-up, so after the reset we can hand it to the host.
+
+use core::{fmt::Write, mem::MaybeUninit, ptr::addr_of_mut};
+
+/// What we know about the last crash.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, defmt::Format)]
+#[repr(C)]
+pub struct CrashRecord {
+	/// The line number of the panic (0 if unknown)
+	pub line: u32,
+	/// The hash of the source file name of the panic (0 if unknown)
+	pub file_hash: u32,
+	/// The hash of the panic message
+	pub message_hash: u32,
+}
+
+impl CrashRecord {
+	/// The length of the *BMC Crash Record* register, in bytes
+	pub const LENGTH: usize = 12;
+
+	/// Get the register contents.
+	pub fn as_bytes(&self) -> [u8; Self::LENGTH] {
+		let mut result = [0u8; Self::LENGTH];
+		result[0..4].copy_from_slice(&self.line.to_le_bytes());
+		result[4..8].copy_from_slice(&self.file_hash.to_le_bytes());
+		result[8..12].copy_from_slice(&self.message_hash.to_le_bytes());
+		result
+	}
+
+	/// Check value, so we can tell a real record from random RAM contents.
+	fn check(&self) -> u32 {
+		!(self.line ^ self.file_hash.rotate_left(8) ^ self.message_hash.rotate_left(16))
+	}
+}
+
+/// How a record looks in RAM.
+#[repr(C)]
+struct Stored {
+	magic: u32,
+	record: CrashRecord,
+	check: u32,
+}
+
+impl Stored {
+	/// Marks a valid record
+	const MAGIC: u32 = 0xDEAD_C0DE;
+}
+
+/// Not initialised by the start-up code, so survives a reset.
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut STORED: MaybeUninit<Stored> = MaybeUninit::uninit();
+
+/// A 32-bit FNV-1a hash, which we can `write!` into.
+struct Hasher(u32);
+
+impl Hasher {
+	fn new() -> Hasher {
+		Hasher(0x811C_9DC5)
+	}
+}
+
+impl Write for Hasher {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+		for b in s.bytes() {
+			self.0 ^= u32::from(b);
+			self.0 = self.0.wrapping_mul(0x0100_0193);
+		}
+		Ok(())
+	}
+}
+
+/// Hash something printable.
+fn hash<T>(value: T) -> u32
+where
+	T: core::fmt::Display,
+{
+	let mut hasher = Hasher::new();
+	let _ = write!(hasher, "{}", value);
+	hasher.0
+}
+
+/// Note down a panic, for reading back after the reset.
+pub fn store(info: &core::panic::PanicInfo) {
+	let record = CrashRecord {
+		line: info.location().map(|l| l.line()).unwrap_or(0),
+		file_hash: info.location().map(|l| hash(l.file())).unwrap_or(0),
+		message_hash: hash(info.message()),
+	};
+	store_record(record);
+}
+
+/// Note down a panic we know nothing about (e.g. from `defmt::panic!`).
+pub fn store_unknown() {
+	store_record(CrashRecord::default());
+}
+
+fn store_record(record: CrashRecord) {
+	let stored = Stored {
+		magic: Stored::MAGIC,
+		check: record.check(),
+		record,
+	};
+	// Safety: we only get here from the panic handler, which never returns,
+	// and the other user (`take`) runs once at start-up.
+	unsafe {
+		addr_of_mut!(STORED).cast::<Stored>().write_volatile(stored);
+	}
+}
+
+/// Get the record of the crash which caused the last reset, if there was
+/// one. The record is wiped, so we don't report it twice.
+///
+/// Call this once, at start-up.
+pub fn take() -> Option<CrashRecord> {
+	// Safety: every bit pattern is a valid `Stored`, as it is just integers,
+	// and we check the magic number and check value before we trust it.
+	let stored = unsafe { addr_of_mut!(STORED).cast::<Stored>().read_volatile() };
+	let result = if stored.magic == Stored::MAGIC && stored.check == stored.record.check() {
+		Some(stored.record)
+	} else {
+		None
+	};
+	// Safety: as above - we're at start-up and nothing else touches this
+	unsafe {
+		// The magic number comes first
+		addr_of_mut!(STORED).cast::<u32>().write_volatile(0);
+	}
+	result
+}
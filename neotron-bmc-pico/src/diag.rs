@@ -0,0 +1,184 @@
+//! # Diagnostics
+//!
+//! Rather than panic when the host (or noise on the bus) gives us something
+//! we can't handle, we drop it, recover, and count what happened. The host
+//! can read the counts from the *Error Counters* register.
+//!
+//! We also count what goes right (and some more things that go wrong), for
+//! the *Statistics* register.
+
+/// The things that can go wrong, in the order they appear in the *Error
+/// Counters* register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// A message was dropped because the queue was full
+	QueueFull = 0,
+	/// A *Request* failed its CRC check
+	RequestCrc = 1,
+	/// A *Request* was malformed (e.g. the wrong length)
+	RequestMalformed = 2,
+	/// A *Long Write Payload* failed its CRC check
+	PayloadCrc = 3,
+	/// A *Long Write Payload* couldn't be received (e.g. an SPI overrun)
+	PayloadLost = 4,
+	/// A *Response* couldn't be sent
+	ResponseFailed = 5,
+	/// The SPI peripheral was reset to recover from an error
+	SpiReset = 6,
+}
+
+impl Error {
+	/// How many kinds of error there are
+	pub const COUNT: usize = 7;
+}
+
+/// How many times each [`Error`] has happened, since power-up or since the
+/// host last cleared them.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ErrorCounters {
+	counts: [u16; Error::COUNT],
+}
+
+impl ErrorCounters {
+	/// The length of the *Error Counters* register
+	pub const LEN: usize = Error::COUNT * 2;
+
+	pub const fn new() -> ErrorCounters {
+		ErrorCounters {
+			counts: [0; Error::COUNT],
+		}
+	}
+
+	/// Note that something went wrong. The counts saturate, rather than
+	/// wrapping.
+	pub fn increment(&mut self, error: Error) {
+		defmt::debug!("Error: {:?}", error);
+		let count = &mut self.counts[error as usize];
+		*count = count.saturating_add(1);
+	}
+
+	/// Zero all the counts.
+	pub fn clear(&mut self) {
+		self.counts = [0; Error::COUNT];
+	}
+
+	/// Render as the *Error Counters* register (each count as a `u16le`).
+	pub fn as_bytes(&self) -> [u8; Self::LEN] {
+		let mut bytes = [0u8; Self::LEN];
+		for (chunk, count) in bytes.chunks_exact_mut(2).zip(self.counts.iter()) {
+			chunk.copy_from_slice(&count.to_le_bytes());
+		}
+		bytes
+	}
+}
+
+/// The things we count, in the order they appear in the *Statistics*
+/// register (after the uptime).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Stat {
+	/// *Requests* received over SPI
+	SpiRequests = 0,
+	/// *Requests* which failed their CRC check
+	RequestCrcFailures = 1,
+	/// *Requests* which were retries of the previous one
+	Retries = 2,
+	/// *Requests* for a register we don't have
+	BadRegisters = 3,
+	/// Words received from the PS/2 keyboard port
+	Ps2KbWords = 4,
+	/// Bad words (e.g. parity errors) received from the PS/2 keyboard port
+	Ps2KbErrors = 5,
+	/// Words received from the PS/2 mouse port
+	Ps2MouseWords = 6,
+	/// Bad words (e.g. parity errors) received from the PS/2 mouse port
+	Ps2MouseErrors = 7,
+	/// Bytes dropped because a PS/2 FIFO was full
+	Ps2Overflows = 8,
+	/// Bytes lost because the UART overran
+	UartOverruns = 9,
+	/// Messages dropped because the queue was full
+	QueueDrops = 10,
+	/// Presses of the power and reset buttons
+	ButtonPresses = 11,
+	/// Times the host was powered on
+	PowerCycles = 12,
+}
+
+impl Stat {
+	/// How many statistics there are
+	pub const COUNT: usize = 13;
+}
+
+/// How many times each [`Stat`] has happened, since power-up or since the
+/// host last cleared them.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Statistics {
+	counts: [u32; Stat::COUNT],
+}
+
+impl Statistics {
+	/// The length of the *Statistics* register (the uptime, then each count)
+	pub const LEN: usize = (Stat::COUNT + 1) * 4;
+
+	pub const fn new() -> Statistics {
+		Statistics {
+			counts: [0; Stat::COUNT],
+		}
+	}
+
+	/// Count one more. The counts wrap, so the host should look at the
+	/// difference between two reads.
+	pub fn increment(&mut self, stat: Stat) {
+		let count = &mut self.counts[stat as usize];
+		*count = count.wrapping_add(1);
+	}
+
+	/// Zero all the counts.
+	pub fn clear(&mut self) {
+		self.counts = [0; Stat::COUNT];
+	}
+
+	/// Render as the *Statistics* register (the uptime in seconds, then each
+	/// count, all as `u32le`).
+	pub fn as_bytes(&self, uptime_secs: u32) -> [u8; Self::LEN] {
+		let mut bytes = [0u8; Self::LEN];
+		bytes[0..4].copy_from_slice(&uptime_secs.to_le_bytes());
+		for (chunk, count) in bytes[4..].chunks_exact_mut(4).zip(self.counts.iter()) {
+			chunk.copy_from_slice(&count.to_le_bytes());
+		}
+		bytes
+	}
+}
+
+/// Everything we count.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Diagnostics {
+	/// For the *Error Counters* register
+	pub errors: ErrorCounters,
+	/// For the *Statistics* register
+	pub stats: Statistics,
+}
+
+impl Diagnostics {
+	pub const fn new() -> Diagnostics {
+		Diagnostics {
+			errors: ErrorCounters::new(),
+			stats: Statistics::new(),
+		}
+	}
+
+	/// Note that something went wrong. Some errors are statistics too.
+	pub fn error(&mut self, error: Error) {
+		self.errors.increment(error);
+		match error {
+			Error::QueueFull => self.stats.increment(Stat::QueueDrops),
+			Error::RequestCrc => self.stats.increment(Stat::RequestCrcFailures),
+			_ => {}
+		}
+	}
+
+	/// Count something.
+	pub fn count(&mut self, stat: Stat) {
+		self.stats.increment(stat);
+	}
+}
@@ -0,0 +1,128 @@
+//! # Event Log
+//!
+//! Without a debug probe attached, our `defmt` output goes nowhere. So we
+//! also note down the important things in a small log in RAM, which the host
+//! can read through the *Event Log* register and copy into its own logs.
+//!
+//! If the host doesn't keep up, the oldest entries are dropped, and an
+//! [`Event::EventsLost`] entry takes their place.
+//!
+//! There's no event for a power rail fault yet, as nothing watches the rails.
+//! They're only measured when the shell's `rails` command asks.
+
+/// How many entries the log holds
+pub const CAPACITY: usize = 32;
+
+/// The things we log
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Event {
+	/// The BMC started. The argument is the *BMC Reset Reason*.
+	BmcStart = 1,
+	/// The host was powered on. The argument is a
+	/// [`HostReason`](crate::reset::HostReason).
+	PowerOn = 2,
+	/// The host was powered off. The argument is a
+	/// [`HostReason`](crate::reset::HostReason).
+	PowerOff = 3,
+	/// The host was reset. The argument is a
+	/// [`HostReason`](crate::reset::HostReason).
+	HostReset = 4,
+	/// The host watchdog expired. The argument is the
+	/// [`Action`](crate::host_watchdog::Action) taken.
+	HostWatchdog = 5,
+	/// A bad word arrived on a PS/2 port. The argument is the port (0 for the
+	/// keyboard, 1 for the mouse).
+	Ps2Error = 6,
+	/// A PS/2 FIFO overflowed. The argument is the port.
+	Ps2Overflow = 7,
+	/// Something went wrong on SPI. The argument is a
+	/// [`diag::Error`](crate::diag::Error).
+	SpiError = 8,
+	/// Some entries were dropped because the log was full. The argument is
+	/// how many (saturating at 255).
+	EventsLost = 9,
+}
+
+/// One entry in the log
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Entry {
+	/// Milliseconds since the BMC started (wrapping)
+	pub timestamp_ms: u32,
+	/// What happened
+	pub event: Event,
+	/// More detail - the meaning depends on the event
+	pub arg: u8,
+}
+
+impl Entry {
+	/// The length of an entry in the *Event Log* register
+	pub const LEN: usize = 6;
+
+	/// Render as it appears in the *Event Log* register.
+	pub fn as_bytes(&self) -> [u8; Self::LEN] {
+		let t = self.timestamp_ms.to_le_bytes();
+		[t[0], t[1], t[2], t[3], self.event as u8, self.arg]
+	}
+}
+
+/// The log itself
+#[derive(Debug, Default)]
+pub struct EventLog {
+	/// Oldest entry first
+	entries: heapless::Deque<Entry, CAPACITY>,
+	/// Covers the entries we dropped, if any
+	lost: Option<Entry>,
+}
+
+impl EventLog {
+	pub fn new() -> EventLog {
+		EventLog {
+			entries: heapless::Deque::new(),
+			lost: None,
+		}
+	}
+
+	/// Add an entry, dropping the oldest one if the log is full.
+	pub fn push(&mut self, timestamp_ms: u32, event: Event, arg: u8) {
+		defmt::debug!("Event {:?} ({})", event, arg);
+		if self.entries.is_full() {
+			if let Some(dropped) = self.entries.pop_front() {
+				let count = self.lost.map(|e| e.arg).unwrap_or(0);
+				self.lost = Some(Entry {
+					timestamp_ms: dropped.timestamp_ms,
+					event: Event::EventsLost,
+					arg: count.saturating_add(1),
+				});
+			}
+		}
+		let _ = self.entries.push_back(Entry {
+			timestamp_ms,
+			event,
+			arg,
+		});
+	}
+
+	/// How many entries are waiting to be read?
+	pub fn len(&self) -> usize {
+		self.entries.len() + if self.lost.is_some() { 1 } else { 0 }
+	}
+
+	/// Is the log empty?
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Take the oldest entry.
+	pub fn pop(&mut self) -> Option<Entry> {
+		self.lost.take().or_else(|| self.entries.pop_front())
+	}
+
+	/// Look at an entry without taking it (0 is the oldest).
+	pub fn get(&self, index: usize) -> Option<Entry> {
+		match (self.lost, index) {
+			(Some(lost), 0) => Some(lost),
+			(Some(_), n) => self.entries.iter().nth(n - 1).copied(),
+			(None, n) => self.entries.iter().nth(n).copied(),
+		}
+	}
+}
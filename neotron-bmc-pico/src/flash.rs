@@ -0,0 +1,115 @@
+//! # Flash Driver
+//!
+//! Erases and programs the STM32F030's internal flash, for the settings store
+//! and for firmware updates.
+//!
+//! The CPU stalls whilst the flash is busy (as that's where our code lives),
+//! so erasing a page holds everything up for tens of milliseconds.
+
+use stm32f0xx_hal::pac::FLASH;
+
+/// The STM32F030x6 has 1 KiB flash pages
+pub const PAGE_SIZE: usize = 1024;
+
+/// The flash unlock sequence
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// Something went wrong writing to flash.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The flash controller reported a programming error
+	Programming,
+	/// The flash is write-protected
+	WriteProtected,
+	/// What we read back wasn't what we wrote
+	Verify,
+}
+
+/// Owns the flash controller.
+pub struct Flash(FLASH);
+
+impl Flash {
+	/// Take the flash controller.
+	pub fn new(flash: FLASH) -> Flash {
+		Flash(flash)
+	}
+
+	/// Get some flash, as a slice.
+	///
+	/// # Safety
+	///
+	/// The given range must be in flash, and nothing else must be changing
+	/// it.
+	pub unsafe fn read(addr: usize, len: usize) -> &'static [u8] {
+		core::slice::from_raw_parts(addr as *const u8, len)
+	}
+
+	fn unlock(&mut self) {
+		if self.0.cr.read().lock().bit_is_set() {
+			self.0.keyr.write(|w| unsafe { w.fkeyr().bits(KEY1) });
+			self.0.keyr.write(|w| unsafe { w.fkeyr().bits(KEY2) });
+		}
+	}
+
+	fn lock(&mut self) {
+		self.0.cr.modify(|_r, w| w.lock().set_bit());
+	}
+
+	/// Wait for the flash controller to finish, and check how it went.
+	fn wait(&mut self) -> Result<(), Error> {
+		while self.0.sr.read().bsy().bit_is_set() {}
+		let sr = self.0.sr.read();
+		// These flags are cleared by writing a 1
+		self.0
+			.sr
+			.write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+		if sr.wrprt().bit_is_set() {
+			Err(Error::WriteProtected)
+		} else if sr.pgerr().bit_is_set() {
+			Err(Error::Programming)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Erase the page starting at the given address.
+	pub fn erase_page(&mut self, addr: usize) -> Result<(), Error> {
+		self.unlock();
+		self.0.cr.modify(|_r, w| w.per().set_bit());
+		self.0.ar.write(|w| unsafe { w.far().bits(addr as u32) });
+		self.0.cr.modify(|_r, w| w.strt().set_bit());
+		let result = self.wait();
+		self.0.cr.modify(|_r, w| w.per().clear_bit());
+		self.lock();
+		result
+	}
+
+	/// Write some bytes into erased flash, a half-word at a time.
+	///
+	/// The address must be half-word aligned, and there must be an even
+	/// number of bytes.
+	pub fn program(&mut self, addr: usize, data: &[u8]) -> Result<(), Error> {
+		self.unlock();
+		self.0.cr.modify(|_r, w| w.pg().set_bit());
+		let mut result = Ok(());
+		for (idx, pair) in data.chunks_exact(2).enumerate() {
+			let ptr = (addr + (idx * 2)) as *mut u16;
+			// Safety: the flash controller is in programming mode, and our
+			// caller has given us an address in flash.
+			unsafe { ptr.write_volatile(u16::from_le_bytes([pair[0], pair[1]])) };
+			result = self.wait();
+			if result.is_err() {
+				break;
+			}
+		}
+		self.0.cr.modify(|_r, w| w.pg().clear_bit());
+		self.lock();
+		result?;
+		// Safety: we just wrote this
+		if unsafe { Self::read(addr, data.len()) } != data {
+			return Err(Error::Verify);
+		}
+		Ok(())
+	}
+}
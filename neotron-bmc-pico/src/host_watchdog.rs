@@ -0,0 +1,106 @@
+//! # Host Watchdog
+//!
+//! The host OS has to keep writing to the *Watchdog Kick* register. If it
+//! stops (because it has hung), we take the action it asked for.
+//!
+//! The timing is done by an RTIC task in `main.rs` - this is just the
+//! register state.
+
+/// What happens when the host stops kicking the watchdog?
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Action {
+	/// Raise the Host Watchdog interrupt
+	Interrupt = 0,
+	/// Pulse the system reset line
+	Reset = 1,
+	/// Turn the power off, then on again
+	PowerCycle = 2,
+}
+
+impl Action {
+	/// Convert from the *Watchdog Control* register.
+	pub fn from_bits(bits: u8) -> Option<Action> {
+		match bits {
+			0 => Some(Action::Interrupt),
+			1 => Some(Action::Reset),
+			2 => Some(Action::PowerCycle),
+			_ => None,
+		}
+	}
+}
+
+/// The watchdog registers, as accessible via SPI reads and writes.
+#[derive(Debug)]
+pub struct RegisterState {
+	/// How long the host has between kicks, in seconds (0 = disabled)
+	timeout_secs: u8,
+	/// What we do if the host doesn't kick us in time
+	action: Action,
+	/// How many times we have reset or power-cycled the host
+	reset_count: u8,
+	/// Whether the config is dirty (the timer needs restarting)
+	needs_update: bool,
+}
+
+impl Default for RegisterState {
+	fn default() -> Self {
+		RegisterState {
+			timeout_secs: 0,
+			action: Action::Interrupt,
+			reset_count: 0,
+			needs_update: false,
+		}
+	}
+}
+
+impl RegisterState {
+	pub fn timeout_secs(&self) -> u8 {
+		self.timeout_secs
+	}
+
+	/// Set the timeout. This also counts as a kick.
+	pub fn set_timeout_secs(&mut self, timeout_secs: u8) {
+		self.timeout_secs = timeout_secs;
+		self.needs_update = true;
+	}
+
+	pub fn action(&self) -> Action {
+		self.action
+	}
+
+	pub fn set_action(&mut self, action: Action) {
+		self.action = action;
+	}
+
+	pub fn reset_count(&self) -> u8 {
+		self.reset_count
+	}
+
+	/// Restart the timer.
+	pub fn kick(&mut self) {
+		self.needs_update = true;
+	}
+
+	/// Turn the watchdog off (e.g. because the host has been powered off).
+	pub fn disable(&mut self) {
+		self.set_timeout_secs(0);
+	}
+
+	/// The watchdog has gone off. It is disabled until the host turns it
+	/// back on, and we note if the host is about to be reset.
+	pub fn expired(&mut self) -> Action {
+		if self.action != Action::Interrupt {
+			self.reset_count = self.reset_count.saturating_add(1);
+		}
+		self.disable();
+		self.action
+	}
+
+	pub fn needs_update(&self) -> bool {
+		self.needs_update
+	}
+
+	pub fn set_needs_update(&mut self, needs_update: bool) {
+		self.needs_update = needs_update;
+	}
+}
@@ -0,0 +1,586 @@
+//! # I²C Bridge
+//!
+//! Lets the host run transactions on the I²C bus (I2C1, on PB6 and PB7),
+//! through the I²C registers.
+//!
+//! The register state is kept in a [`Bridge`], which the SPI handler
+//! updates. The idle loop then calls [`Controller::poll`] to move the bytes,
+//! so we never wait for the bus whilst the host waits for a *Response*.
+
+use stm32f0xx_hal::{pac, prelude::*, rcc::Rcc};
+
+pub use neotron_bmc_commands::i2c::{operation, status, FIFO_LEN};
+
+/// How long a transfer can take before we give up on it (e.g. because a
+/// target is holding SCL low).
+pub const TIMEOUT_MS: u32 = 100;
+
+/// The clock rate we start with, in Hz.
+pub const DEFAULT_BAUD_RATE: u32 = 100_000;
+
+/// The first address a scan probes (those below are reserved).
+pub const SCAN_FIRST: u8 = 0x08;
+
+/// The last address a scan probes (those above are reserved).
+pub const SCAN_LAST: u8 = 0x77;
+
+/// What kind of transfer to run
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Operation {
+	/// Write the TX FIFO to the target
+	Write = operation::WRITE as isize,
+	/// Read from the target into the RX FIFO
+	Read = operation::READ as isize,
+	/// Write the TX FIFO, then a repeated START, then read into the RX FIFO
+	WriteRead = operation::WRITE_READ as isize,
+	/// Probe each address from [`SCAN_FIRST`] to [`SCAN_LAST`], and put a
+	/// bitmap of the targets that answer into the RX FIFO
+	Scan = operation::SCAN as isize,
+}
+
+impl Operation {
+	/// Convert from the *I²C Control* register.
+	pub fn from_bits(bits: u8) -> Option<Operation> {
+		match bits {
+			operation::WRITE => Some(Operation::Write),
+			operation::READ => Some(Operation::Read),
+			operation::WRITE_READ => Some(Operation::WriteRead),
+			operation::SCAN => Some(Operation::Scan),
+			_ => None,
+		}
+	}
+}
+
+/// A transfer, as set up in the *I²C Control* register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Transfer {
+	/// 7-bit target address
+	pub address: u8,
+	/// What to do
+	pub operation: Operation,
+	/// How many bytes to read (ignored for [`Operation::Write`] and
+	/// [`Operation::Scan`])
+	pub read_len: u8,
+}
+
+impl Transfer {
+	/// The length of the *I²C Control* register
+	pub const LENGTH: usize = 3;
+
+	/// Parse the *I²C Control* register, checking it makes sense.
+	pub fn from_bytes(bytes: &[u8]) -> Option<Transfer> {
+		if bytes.len() != Self::LENGTH || bytes[0] > 0x7F {
+			return None;
+		}
+		let transfer = Transfer {
+			address: bytes[0],
+			operation: Operation::from_bits(bytes[1])?,
+			read_len: bytes[2],
+		};
+		let read_ok = match transfer.operation {
+			Operation::Write | Operation::Scan => true,
+			Operation::Read | Operation::WriteRead => {
+				transfer.read_len > 0 && usize::from(transfer.read_len) <= FIFO_LEN
+			}
+		};
+		if read_ok {
+			Some(transfer)
+		} else {
+			None
+		}
+	}
+
+	/// Render as the *I²C Control* register.
+	pub fn as_bytes(&self) -> [u8; Self::LENGTH] {
+		[self.address, self.operation as u8, self.read_len]
+	}
+}
+
+/// Why a request to the bridge was refused
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// A transfer is already waiting or running
+	Busy,
+	/// The FIFO doesn't have room
+	Full,
+	/// The settings don't make sense
+	Invalid,
+}
+
+/// The I²C registers, as accessible via SPI reads and writes.
+#[derive(Debug)]
+pub struct Bridge {
+	/// Bytes to be written
+	tx: heapless::Deque<u8, FIFO_LEN>,
+	/// Bytes which have been read
+	rx: heapless::Deque<u8, FIFO_LEN>,
+	/// The last transfer the host asked for
+	transfer: Option<Transfer>,
+	/// The *I²C Status* register (apart from [`status::BUSY`])
+	status: u8,
+	/// The clock rate, in Hz
+	baud_rate: u32,
+	/// Is `transfer` waiting to be started?
+	pending: bool,
+	/// Is a transfer running?
+	active: bool,
+	/// Has the clock rate changed?
+	needs_config: bool,
+	/// Has a transfer finished since we last checked?
+	completed: bool,
+}
+
+impl Default for Bridge {
+	fn default() -> Self {
+		Bridge {
+			tx: heapless::Deque::new(),
+			rx: heapless::Deque::new(),
+			transfer: None,
+			status: 0,
+			baud_rate: DEFAULT_BAUD_RATE,
+			pending: false,
+			active: false,
+			needs_config: false,
+			completed: false,
+		}
+	}
+}
+
+impl Bridge {
+	fn is_busy(&self) -> bool {
+		self.pending || self.active
+	}
+
+	/// Add bytes to the TX FIFO. Either they all fit, or none are added.
+	pub fn queue(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		if self.is_busy() {
+			return Err(Error::Busy);
+		}
+		if bytes.len() > self.tx.capacity() - self.tx.len() {
+			return Err(Error::Full);
+		}
+		for b in bytes {
+			let _ = self.tx.push_back(*b);
+		}
+		Ok(())
+	}
+
+	/// How many bytes are waiting to be written?
+	pub fn tx_len(&self) -> usize {
+		self.tx.len()
+	}
+
+	/// How many bytes are waiting to be read by the host?
+	pub fn rx_len(&self) -> usize {
+		self.rx.len()
+	}
+
+	/// Take a byte the target sent us.
+	pub fn pop_rx(&mut self) -> Option<u8> {
+		self.rx.pop_front()
+	}
+
+	/// Empty the TX FIFO (bit 0) and/or the RX FIFO (bit 1).
+	pub fn flush(&mut self, bits: u8) -> Result<(), Error> {
+		if self.is_busy() {
+			return Err(Error::Busy);
+		}
+		if (bits & 0x01) != 0 {
+			self.tx.clear();
+		}
+		if (bits & 0x02) != 0 {
+			self.rx.clear();
+		}
+		Ok(())
+	}
+
+	/// Get the last transfer the host asked for.
+	pub fn transfer(&self) -> Option<Transfer> {
+		self.transfer
+	}
+
+	/// Start a transfer. Any bytes left in the RX FIFO are thrown away.
+	pub fn start(&mut self, transfer: Transfer) -> Result<(), Error> {
+		if self.is_busy() {
+			return Err(Error::Busy);
+		}
+		self.rx.clear();
+		self.transfer = Some(transfer);
+		self.status = 0;
+		self.pending = true;
+		Ok(())
+	}
+
+	/// Get the *I²C Status* register.
+	pub fn status(&self) -> u8 {
+		if self.is_busy() {
+			self.status | status::BUSY
+		} else {
+			self.status
+		}
+	}
+
+	/// Clear bits in the *I²C Status* register.
+	pub fn clear_status(&mut self, bits: u8) {
+		self.status &= !(bits & status::CLEARABLE);
+	}
+
+	pub fn baud_rate(&self) -> u32 {
+		self.baud_rate
+	}
+
+	/// Set the clock rate. We only support a few, so it is rounded down to
+	/// one of those (and [`Self::baud_rate`] says which).
+	pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error> {
+		if self.is_busy() {
+			return Err(Error::Busy);
+		}
+		self.baud_rate = match baud_rate {
+			0..=9_999 => return Err(Error::Invalid),
+			10_000..=99_999 => 10_000,
+			100_000..=399_999 => 100_000,
+			_ => 400_000,
+		};
+		self.needs_config = true;
+		Ok(())
+	}
+
+	/// Has a transfer finished since we last asked?
+	pub fn take_completed(&mut self) -> bool {
+		core::mem::replace(&mut self.completed, false)
+	}
+
+	/// Note that the transfer has finished.
+	fn finish(&mut self, status: u8) {
+		self.active = false;
+		self.status = status::DONE | status;
+		self.completed = true;
+	}
+}
+
+/// Register bits, from RM0360 Section 22.7 (the PAC names vary between
+/// versions, so we use the bits).
+mod reg {
+	/// CR1: Peripheral enable
+	pub const CR1_PE: u32 = 1 << 0;
+	/// CR2: Transfer direction (1 = read)
+	pub const CR2_RD_WRN: u32 = 1 << 10;
+	/// CR2: Generate a (repeated) START
+	pub const CR2_START: u32 = 1 << 13;
+	/// CR2: Generate a STOP
+	pub const CR2_STOP: u32 = 1 << 14;
+	/// CR2: Send a STOP when NBYTES have been sent
+	pub const CR2_AUTOEND: u32 = 1 << 25;
+	/// CR2: Number of bytes
+	pub const CR2_NBYTES_SHIFT: u32 = 16;
+	/// ISR: Transmit data register empty, send the next byte
+	pub const ISR_TXIS: u32 = 1 << 1;
+	/// ISR: Receive data register not empty
+	pub const ISR_RXNE: u32 = 1 << 2;
+	/// ISR: NACK received
+	pub const ISR_NACKF: u32 = 1 << 4;
+	/// ISR: STOP detected
+	pub const ISR_STOPF: u32 = 1 << 5;
+	/// ISR: Transfer complete (without AUTOEND)
+	pub const ISR_TC: u32 = 1 << 6;
+	/// ISR: Bus error
+	pub const ISR_BERR: u32 = 1 << 8;
+	/// ISR: Arbitration lost
+	pub const ISR_ARLO: u32 = 1 << 9;
+	/// ICR: Clear all the flags we use
+	pub const ICR_ALL: u32 = ISR_NACKF | ISR_STOPF | ISR_BERR | ISR_ARLO;
+}
+
+/// Where a transfer has got to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Phase {
+	/// No transfer running
+	Idle,
+	/// Sending the TX FIFO
+	Writing,
+	/// Filling the RX FIFO
+	Reading,
+	/// Waiting for the STOP to go out, after a NACK
+	Stopping,
+	/// Probing an address during a scan
+	Scanning,
+}
+
+/// The I²C pins, on GPIOB
+mod pins {
+	/// PB6 is SCL
+	pub const SCL: u32 = 6;
+	/// PB7 is SDA
+	pub const SDA: u32 = 7;
+	/// MODER value for a general purpose output
+	pub const MODE_OUTPUT: u32 = 0b01;
+	/// MODER value for an alternate function
+	pub const MODE_ALTERNATE: u32 = 0b10;
+	/// Half an SCL period at 100 kHz, in 48 MHz CPU clocks
+	pub const HALF_PERIOD_CLOCKS: u32 = 240;
+}
+
+/// Drives the I2C1 peripheral.
+pub struct Controller {
+	dev: pac::I2C1,
+	/// Where the current transfer has got to
+	phase: Phase,
+	/// The transfer we're running
+	transfer: Option<Transfer>,
+	/// When the transfer started, in milliseconds
+	started_ms: u32,
+	/// What to report when the transfer stops
+	result: u8,
+	/// The address a scan is probing
+	scan_address: u8,
+	/// Which addresses have answered during a scan (one bit per address)
+	scan_found: [u8; FIFO_LEN],
+}
+
+impl Controller {
+	/// Take over I2C1.
+	pub fn new<SCLPIN, SDAPIN>(dev: pac::I2C1, pins: (SCLPIN, SDAPIN), rcc: &mut Rcc) -> Controller
+	where
+		SCLPIN: stm32f0xx_hal::i2c::SclPin<pac::I2C1>,
+		SDAPIN: stm32f0xx_hal::i2c::SdaPin<pac::I2C1>,
+	{
+		// The HAL turns on the clock and checks the pins. Then we take it
+		// apart, as it would wait for the bus whilst the host waits for us.
+		let hal_i2c = stm32f0xx_hal::i2c::I2c::i2c1(dev, pins, 100.khz(), rcc);
+		let (dev, _pins) = hal_i2c.release();
+		let mut controller = Controller {
+			dev,
+			phase: Phase::Idle,
+			transfer: None,
+			started_ms: 0,
+			result: 0,
+			scan_address: 0,
+			scan_found: [0u8; FIFO_LEN],
+		};
+		controller.configure(DEFAULT_BAUD_RATE);
+		controller
+	}
+
+	/// Set the clock rate, and (re-)enable the peripheral.
+	///
+	/// The timings are from RM0360 Table 74, for an 8 MHz I2CCLK (HSI).
+	fn configure(&mut self, baud_rate: u32) {
+		let timing = match baud_rate {
+			10_000 => 0x1042_C3C7,
+			100_000 => 0x1042_0F13,
+			_ => 0x0031_0309,
+		};
+		self.disable();
+		// Safety: these are valid timings
+		self.dev.timingr.write(|w| unsafe { w.bits(timing) });
+		self.dev.cr1.write(|w| unsafe { w.bits(reg::CR1_PE) });
+	}
+
+	/// Turn the peripheral off, which also resets its state machine.
+	fn disable(&mut self) {
+		self.dev.cr1.write(|w| unsafe { w.bits(0) });
+		// PE must be low for at least three APB clocks
+		cortex_m::asm::delay(3);
+	}
+
+	/// Start the next part of a transfer: `nbytes` in the given direction,
+	/// with a STOP afterwards if `last`.
+	fn begin(&mut self, address: u8, read: bool, nbytes: u8, last: bool) {
+		let mut cr2 = (u32::from(address) << 1)
+			| (u32::from(nbytes) << reg::CR2_NBYTES_SHIFT)
+			| reg::CR2_START;
+		if read {
+			cr2 |= reg::CR2_RD_WRN;
+		}
+		if last {
+			cr2 |= reg::CR2_AUTOEND;
+		}
+		// Safety: any combination of these bits is valid
+		self.dev.cr2.write(|w| unsafe { w.bits(cr2) });
+	}
+
+	/// Move the current transfer along, or start the next one. Call this
+	/// often.
+	pub fn poll(&mut self, bridge: &mut Bridge, now_ms: u32) {
+		if bridge.needs_config && self.phase == Phase::Idle {
+			bridge.needs_config = false;
+			self.configure(bridge.baud_rate);
+		}
+
+		if self.phase == Phase::Idle {
+			if bridge.pending {
+				bridge.pending = false;
+				if let Some(transfer) = bridge.transfer {
+					self.start(bridge, transfer, now_ms);
+				}
+			}
+			return;
+		}
+
+		let isr = self.dev.isr.read().bits();
+		if (isr & reg::ISR_ARLO) != 0 {
+			// The peripheral has already let go of the bus
+			defmt::warn!("I2C arbitration lost");
+			self.abort(bridge, status::ARBITRATION_LOST);
+		} else if (isr & reg::ISR_BERR) != 0 {
+			defmt::warn!("I2C bus error");
+			self.abort(bridge, status::BUS_ERROR);
+		} else if (isr & reg::ISR_STOPF) != 0 && self.phase == Phase::Scanning {
+			// Did anyone acknowledge the address?
+			if (isr & reg::ISR_NACKF) == 0 {
+				let address = usize::from(self.scan_address);
+				self.scan_found[address / 8] |= 1 << (address % 8);
+			}
+			// Safety: clearing flags is harmless
+			self.dev.icr.write(|w| unsafe { w.bits(reg::ICR_ALL) });
+			if self.scan_address < SCAN_LAST {
+				self.scan_address += 1;
+				self.started_ms = now_ms;
+				self.begin(self.scan_address, false, 0, true);
+			} else {
+				for b in self.scan_found {
+					let _ = bridge.rx.push_back(b);
+				}
+				self.phase = Phase::Idle;
+				self.transfer = None;
+				bridge.finish(0);
+			}
+		} else if (isr & reg::ISR_STOPF) != 0 {
+			// All done (or a NACK ended it early)
+			let result = if (isr & reg::ISR_NACKF) != 0 {
+				status::NACK
+			} else {
+				self.result
+			};
+			// Safety: clearing flags is harmless
+			self.dev.icr.write(|w| unsafe { w.bits(reg::ICR_ALL) });
+			self.phase = Phase::Idle;
+			self.transfer = None;
+			bridge.finish(result);
+		} else if (isr & reg::ISR_NACKF) != 0 {
+			// During a scan, the STOP follows by itself
+			if self.phase != Phase::Stopping && self.phase != Phase::Scanning {
+				defmt::debug!("I2C NACK");
+				self.result = status::NACK;
+				self.phase = Phase::Stopping;
+				// Without AUTOEND, we must send the STOP ourselves
+				self.dev
+					.cr2
+					.modify(|r, w| unsafe { w.bits(r.bits() | reg::CR2_STOP) });
+			}
+		} else if (isr & reg::ISR_TXIS) != 0 {
+			let byte = bridge.tx.pop_front().unwrap_or(0xFF);
+			// Safety: any byte is valid
+			self.dev.txdr.write(|w| unsafe { w.bits(u32::from(byte)) });
+		} else if (isr & reg::ISR_RXNE) != 0 {
+			let byte = self.dev.rxdr.read().bits() as u8;
+			let _ = bridge.rx.push_back(byte);
+		} else if (isr & reg::ISR_TC) != 0 {
+			// The write half of a write-then-read is done, so send a
+			// repeated START and read.
+			if let Some(transfer) = self.transfer {
+				self.phase = Phase::Reading;
+				self.begin(transfer.address, true, transfer.read_len, true);
+			}
+		} else if now_ms.wrapping_sub(self.started_ms) > TIMEOUT_MS {
+			defmt::warn!("I2C timeout");
+			self.abort(bridge, status::TIMEOUT);
+		}
+	}
+
+	/// Start a transfer.
+	fn start(&mut self, bridge: &mut Bridge, transfer: Transfer, now_ms: u32) {
+		defmt::debug!("I2C start {:?}", transfer);
+		bridge.active = true;
+		self.transfer = Some(transfer);
+		self.started_ms = now_ms;
+		self.result = 0;
+		// Safety: clearing flags is harmless
+		self.dev.icr.write(|w| unsafe { w.bits(reg::ICR_ALL) });
+		let tx_len = bridge.tx.len() as u8;
+		match transfer.operation {
+			Operation::Write => {
+				self.phase = Phase::Writing;
+				self.begin(transfer.address, false, tx_len, true);
+			}
+			Operation::Read => {
+				self.phase = Phase::Reading;
+				self.begin(transfer.address, true, transfer.read_len, true);
+			}
+			Operation::WriteRead => {
+				self.phase = Phase::Writing;
+				self.begin(transfer.address, false, tx_len, false);
+			}
+			Operation::Scan => {
+				if !self.recover_bus(bridge.baud_rate) {
+					defmt::warn!("I2C bus stuck");
+					self.abort(bridge, status::BUS_STUCK);
+					return;
+				}
+				// Probe each address with an address-only write
+				self.phase = Phase::Scanning;
+				self.scan_address = SCAN_FIRST;
+				self.scan_found = [0u8; FIFO_LEN];
+				self.begin(self.scan_address, false, 0, true);
+			}
+		}
+	}
+
+	/// If a target is holding SDA low (e.g. because we were reset half way
+	/// through a read), clock SCL up to nine times until it lets go, and
+	/// then send a STOP.
+	///
+	/// Returns `true` if SDA is now high.
+	fn recover_bus(&mut self, baud_rate: u32) -> bool {
+		// Safety: nothing else uses PB6 or PB7, and we only touch their bits
+		let gpiob = unsafe { &*pac::GPIOB::ptr() };
+		let sda_is_high = || (gpiob.idr.read().bits() & (1 << pins::SDA)) != 0;
+		let set_mode = |mode: u32| {
+			let mask = (0b11 << (pins::SCL * 2)) | (0b11 << (pins::SDA * 2));
+			let bits = (mode << (pins::SCL * 2)) | (mode << (pins::SDA * 2));
+			gpiob
+				.moder
+				.modify(|r, w| unsafe { w.bits((r.bits() & !mask) | bits) });
+		};
+		// Drive a pin (which is open-drain, so high means let go)
+		let drive = |pin: u32, high: bool| {
+			let bit = if high { 1 << pin } else { 1 << (pin + 16) };
+			gpiob.bsrr.write(|w| unsafe { w.bits(bit) });
+			cortex_m::asm::delay(pins::HALF_PERIOD_CLOCKS);
+		};
+
+		if sda_is_high() {
+			return true;
+		}
+		defmt::warn!("I2C SDA held low - clocking the bus");
+		self.disable();
+		drive(pins::SCL, true);
+		drive(pins::SDA, true);
+		set_mode(pins::MODE_OUTPUT);
+		for _ in 0..9 {
+			if sda_is_high() {
+				break;
+			}
+			drive(pins::SCL, false);
+			drive(pins::SCL, true);
+		}
+		// A STOP is SDA rising whilst SCL is high
+		drive(pins::SCL, false);
+		drive(pins::SDA, false);
+		drive(pins::SCL, true);
+		drive(pins::SDA, true);
+		set_mode(pins::MODE_ALTERNATE);
+		self.configure(baud_rate);
+		sda_is_high()
+	}
+
+	/// Give up on the transfer, and reset the peripheral so it lets go of
+	/// the bus.
+	fn abort(&mut self, bridge: &mut Bridge, status: u8) {
+		self.configure(bridge.baud_rate);
+		self.phase = Phase::Idle;
+		self.transfer = None;
+		bridge.tx.clear();
+		bridge.finish(status);
+	}
+}
@@ -3,18 +3,41 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use defmt_rtt as _; // global logger
-use panic_probe as _;
-use stm32f0xx_hal as _; // memory layout // panic handler
+use stm32f0xx_hal as _; // memory layout
 
+pub mod config;
+pub mod crash;
+pub mod diag;
+pub mod event_log;
+pub mod flash;
+pub mod host_watchdog;
+pub mod i2c;
+pub mod power;
 pub mod ps2;
+pub mod rails;
+pub mod reset;
+pub mod rom_boot;
+pub mod rtc;
 pub mod speaker;
 pub mod spi;
+pub mod uart;
+pub mod update;
 
-// same panicking *behavior* as `panic-probe` but doesn't print a panic message
-// this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+// Rather than hang on a panic (which leaves the system with no working power
+// button), we note down what happened and reset.
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+	defmt::error!("{}", defmt::Display2Format(info));
+	crash::store(info);
+	cortex_m::peripheral::SCB::sys_reset()
+}
+
+// Doesn't print a panic message - this prevents the panic message being
+// printed *twice* when `defmt::panic` is invoked
 #[defmt::panic_handler]
-fn panic() -> ! {
-	cortex_m::asm::udf()
+fn defmt_panic() -> ! {
+	crash::store_unknown();
+	cortex_m::peripheral::SCB::sys_reset()
 }
 
 static COUNT: AtomicUsize = AtomicUsize::new(0);
@@ -12,6 +12,8 @@
 #![no_std]
 
 use core::convert::TryFrom;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use heapless::spsc::{Consumer, Producer, Queue};
 use rtic::app;
@@ -22,16 +24,35 @@ use stm32f0xx_hal::{
 	gpio::{Alternate, Floating, Input, Output, PullDown, PullUp, PushPull, AF1},
 	pac,
 	prelude::*,
-	rcc, serial,
+	rcc, serial, watchdog,
 };
 
-use neotron_bmc_commands::Command;
-use neotron_bmc_pico::{self as _, speaker};
+use neotron_bmc_commands::{interrupt, power_control, Command};
+use neotron_bmc_console as console;
+use neotron_bmc_keyboard as keyboard;
+use neotron_bmc_pico::{
+	self as _, config, crash, diag,
+	event_log::{self, Event},
+	flash, host_watchdog, i2c, power, rails,
+	reset::{self, HostReason},
+	rom_boot, rtc, speaker, spi, uart, update,
+};
 use neotron_bmc_protocol as proto;
+use neotron_bmc_shell as shell;
 
 /// Version string auto-generated by git.
 static VERSION: [u8; 32] = *include_bytes!(concat!(env!("OUT_DIR"), "/version.txt"));
 
+/// Is the host powered (i.e. not [`DcPowerState::Off`])?
+///
+/// The EXTI interrupt checks this before arming the SPI engine, as it can't
+/// wait for the idle loop to let go of `state_dc_power_enabled`.
+static HOST_POWERED: AtomicBool = AtomicBool::new(false);
+
+/// The idle loop must feed the independent watchdog at least this often, or
+/// the BMC resets itself.
+const IWDG_FREQUENCY_HZ: u32 = 1;
+
 /// At what rate do we blink the status LED when we're running?
 const LED_PERIOD_MS: u64 = 1000;
 
@@ -41,6 +62,23 @@ const DEBOUNCE_POLL_INTERVAL_MS: u64 = 75;
 /// Length of a reset pulse, in milliseconds
 const RESET_DURATION_MS: u64 = 250;
 
+/// How big the SPI driver's transmit buffer is
+const SPI_TX_LEN: usize = 64;
+
+/// The longest FIFO read we accept. The *Response* has a result byte and a
+/// CRC as well, and it all has to fit in the SPI driver's transmit buffer.
+const MAX_FIFO_READ_LEN: usize = SPI_TX_LEN - 2;
+
+/// How much room the shell needs to print a line of a [`ShellJob`]
+const SHELL_LINE_LEN: usize = 40;
+
+/// How long we wait before resetting to install new firmware (or to start
+/// the ROM bootloader), in milliseconds
+const FIRMWARE_INSTALL_DELAY_MS: u64 = 100;
+
+/// How long the power stays off during a power cycle, in milliseconds
+const POWER_CYCLE_OFF_MS: u64 = 2000;
+
 /// The states we can be in controlling the DC power
 #[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
@@ -53,6 +91,68 @@ pub enum DcPowerState {
 	Off = 0,
 }
 
+/// Shell output which is printed a line at a time, as there's room
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ShellJob {
+	/// Printing the help, from this line in [`shell::HELP`]
+	Help(usize),
+	/// Printing the event log, from this entry
+	Log(usize),
+	/// Printing the current (`false`) or previous (`true`) UART history, from
+	/// this byte
+	History(bool, usize),
+	/// Printing the settings, from this one in [`shell::Setting::ALL`]
+	Config(usize),
+	/// Waiting to say whether the settings were saved
+	Save,
+}
+
+/// Something the host asked for with the *Power Control* register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+enum HostRequest {
+	/// Turn the DC/DC off
+	PowerOff,
+	/// Pulse the reset line
+	Reset,
+}
+
+/// Holds a FIFO read's *Response* data, so we can re-send it if required.
+///
+/// `[u8; N]` only implements `Default` up to `N = 32`, so we wrap it.
+#[derive(Debug)]
+struct Scratch([u8; MAX_FIFO_READ_LEN]);
+
+impl Default for Scratch {
+	fn default() -> Self {
+		Scratch([0u8; MAX_FIFO_READ_LEN])
+	}
+}
+
+impl core::ops::Deref for Scratch {
+	type Target = [u8; MAX_FIFO_READ_LEN];
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl core::ops::DerefMut for Scratch {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.0
+	}
+}
+
+/// A write we've actioned, so we can spot the host retrying it.
+#[derive(Debug)]
+struct LastWrite {
+	/// The *Request* (for a *Long Write*, the one before the payload)
+	req: proto::Request,
+	/// The CRC of the *Long Write Payload*, if there was one
+	payload_crc: Option<u8>,
+	/// What we said
+	result: proto::ResponseResult,
+}
+
 /// This is our system state, as accessible via SPI reads and writes.
 #[derive(Debug, Default)]
 pub struct RegisterState {
@@ -61,13 +161,126 @@ pub struct RegisterState {
 	/// Bytes we've read from the keyboard, ready for sending to the host
 	ps2_kb_bytes: heapless::Deque<u8, 16>,
 	/// Used for holding our TX buffer, so we can re-send if required
-	scratch: [u8; 16],
+	scratch: Scratch,
 	/// A copy of the last request, so we can spot duplicates and re-send
 	/// without re-doing a FIFO read. This happens if our response gets a CRC
 	/// error.
 	last_req: Option<proto::Request>,
+	/// The last write, and what we said. If our response gets a CRC error,
+	/// the host sends exactly the same write again, and we mustn't do it
+	/// twice (e.g. send a UART byte twice).
+	last_write: Option<LastWrite>,
 	/// The config of the speaker
 	speaker: speaker::RegisterState,
+	/// Which keyboard shortcuts the BMC acts upon
+	kb_shortcuts: keyboard::ShortcutConfig,
+	/// When the BMC turns the power on by itself
+	power_policy: power::PowerPolicy,
+	/// Interrupts which have fired and not yet been cleared by the host
+	interrupts_latched: u16,
+	/// Which interrupts can drive the IRQ line
+	interrupt_control: u16,
+	/// A Long Write Request which is waiting for its payload
+	long_write: Option<proto::Request>,
+	/// The config of the Real-Time Clock
+	rtc: rtc::RegisterState,
+	/// The config of the host watchdog
+	host_watchdog: host_watchdog::RegisterState,
+	/// Why the host was last started and stopped
+	host_reset_reason: reset::HostResetReason,
+	/// Why the BMC last reset (see [`reset::bmc`])
+	bmc_reset_reason: u8,
+	/// Where the BMC firmware panicked, before the last reset
+	crash_record: crash::CrashRecord,
+	/// A save or factory reset the host has asked for
+	config_pending: Option<config::Operation>,
+	/// How the last save or factory reset went
+	config_status: config::Status,
+	/// How many times the settings have been saved
+	config_version: u16,
+	/// Receives new firmware from the host
+	update: update::Updater,
+	/// How much of the ROM bootloader key the host has written
+	rom_boot_key: rom_boot::KeySequence,
+	/// The host has asked us to start the ROM bootloader
+	rom_boot_requested: bool,
+	/// The host has asked us to turn it off, or reset it
+	host_request: Option<HostRequest>,
+	/// A copy of the error counters and statistics, taken before each request
+	diag: diag::Diagnostics,
+	/// How long the BMC has been running, noted before each request
+	uptime_secs: u32,
+	/// The host has asked us to clear the error counters
+	errors_clear_requested: bool,
+	/// The host has asked us to clear the statistics
+	stats_clear_requested: bool,
+	/// What's been happening, for the host to read
+	event_log: event_log::EventLog,
+	/// The I²C registers
+	i2c: i2c::Bridge,
+	/// The UART registers
+	uart: uart::Bridge,
+}
+
+impl RegisterState {
+	/// Add an entry to the event log, timestamped with the time now.
+	fn log_event(&mut self, event: Event, arg: u8) {
+		let now = app::monotonics::now().duration_since_epoch().to_millis();
+		self.event_log.push(now as u32, event, arg);
+	}
+
+	/// Get the *Interrupt Status* register value.
+	///
+	/// This is the latched interrupts, plus the ones that are active whilst
+	/// a FIFO has data in it.
+	fn interrupt_status(&self) -> u16 {
+		let mut status = self.interrupts_latched;
+		if !self.ps2_kb_bytes.is_empty() {
+			status |= interrupt::PS2_KB_RX_NOT_EMPTY;
+		}
+		if self.i2c.rx_len() > 0 {
+			status |= interrupt::I2C_RX_NOT_EMPTY;
+		}
+		if self.uart.rx_len() > 0 {
+			status |= interrupt::UART_RX_NOT_EMPTY;
+		}
+		if self.uart.tx_len() == 0 {
+			status |= interrupt::UART_TX_EMPTY;
+		}
+		status
+	}
+
+	/// Get the settings we keep in flash.
+	fn settings(&self) -> config::Settings {
+		config::Settings {
+			kb_shortcuts: self.kb_shortcuts.bits(),
+			power_policy: self.power_policy.bits(),
+			wake_key: self.power_policy.wake_key(),
+			interrupt_control: self.interrupt_control,
+			host_watchdog_action: self.host_watchdog.action() as u8,
+			speaker_frequency: self.speaker.frequency_hz(),
+			speaker_duty_cycle: self.speaker.duty_cycle(),
+		}
+	}
+
+	/// Apply some settings (e.g. those loaded from flash).
+	fn apply_settings(&mut self, settings: &config::Settings) {
+		self.kb_shortcuts = keyboard::ShortcutConfig::new(settings.kb_shortcuts);
+		self.power_policy.set_bits(settings.power_policy);
+		self.power_policy.set_wake_key(settings.wake_key);
+		self.interrupt_control = settings.interrupt_control;
+		if let Some(action) = host_watchdog::Action::from_bits(settings.host_watchdog_action) {
+			self.host_watchdog.set_action(action);
+		}
+		if self
+			.speaker
+			.set_frequency_hz(settings.speaker_frequency)
+			.is_err()
+		{
+			defmt::warn!("Bad speaker frequency {}", settings.speaker_frequency);
+		}
+		self.speaker.set_duty_cycle(settings.speaker_duty_cycle);
+	}
 }
 
 #[app(device = crate::pac, peripherals = true, dispatchers = [USB, USART3_4_5_6, TIM14, TIM15, TIM16, TIM17, PVD])]
@@ -80,24 +293,22 @@ mod app {
 		Ps2Data0(u16),
 		/// Word from PS/2 port 1
 		Ps2Data1(u16),
-		/// SPI driver has a Request for us
+		/// SPI driver has a Request (or Long Write Payload) for us
 		SpiRx,
-		/// SPI CS went low (active)
-		SpiEnable,
-		/// SPI CS went high (inactive)
-		SpiDisable,
-		/// The power button was given a press
-		PowerButtonShortPress,
-		/// The power button was held down
-		PowerButtonLongPress,
+		/// The power button was given a press (or something acted like it)
+		PowerButtonShortPress(HostReason),
+		/// The power button was held down (or something acted like it)
+		PowerButtonLongPress(HostReason),
 		/// The power button was released
 		PowerButtonRelease,
-		/// The reset button was given a tap
-		ResetButtonShortPress,
+		/// The reset button was given a tap (or something acted like it)
+		ResetButtonShortPress(HostReason),
 		/// The UART got some data
 		UartByte(u8),
 		/// The speaker's config should be reset
 		SpeakerDisable,
+		/// The host didn't kick the watchdog in time
+		HostWatchdogExpired,
 	}
 
 	#[shared]
@@ -108,7 +319,6 @@ mod app {
 		/// The speaker (J1006)
 		speaker: speaker::Hardware,
 		/// The FTDI UART header (J105)
-		#[lock_free]
 		serial: serial::Serial<pac::USART1, PA9<Alternate<AF1>>, PA10<Alternate<AF1>>>,
 		/// The Clear-To-Send line on the FTDI UART header (which the serial object can't handle)
 		#[lock_free]
@@ -151,11 +361,13 @@ mod app {
 		/// Write messages here
 		msg_q_in: Producer<'static, Message, 8>,
 		/// SPI Peripheral
-		spi: neotron_bmc_pico::spi::SpiPeripheral<5, 64>,
+		spi: neotron_bmc_pico::spi::SpiPeripheral<80, SPI_TX_LEN>,
 		/// CS pin
 		pin_cs: PA4<Input<PullDown>>,
 		/// Keyboard PS/2 decoder
 		kb_decoder: neotron_bmc_pico::ps2::Ps2Decoder,
+		/// Counts the things that happened (and went wrong)
+		diag: diag::Diagnostics,
 	}
 
 	#[local]
@@ -170,6 +382,22 @@ mod app {
 		rcc: Option<rcc::Rcc>,
 		/// IRQ pin
 		pin_irq: PA8<Output<PushPull>>,
+		/// The Real-Time Clock
+		rtc: rtc::Hardware,
+		/// Why the BMC last reset, read from RCC_CSR at boot
+		bmc_reset_reason: u8,
+		/// Where the BMC firmware panicked, before the last reset
+		crash_record: Option<crash::CrashRecord>,
+		/// Resets the BMC if the idle loop stalls
+		watchdog: watchdog::Watchdog,
+		/// The settings saved in flash
+		config_store: config::Store,
+		/// The flash controller
+		flash: flash::Flash,
+		/// Runs the host's I²C transfers
+		i2c: i2c::Controller,
+		/// Measures the power rails
+		rails: rails::Monitor,
 	}
 
 	#[monotonic(binds = SysTick, default = true)]
@@ -191,6 +419,20 @@ mod app {
 		let dp: pac::Peripherals = ctx.device;
 		let cp: cortex_m::Peripherals = ctx.core;
 
+		// This doesn't return if the host asked for the ROM bootloader
+		rom_boot::check_and_enter(&dp.RCC, &dp.SYSCFG);
+
+		// We were started by the bootloader, so we need our own vector table
+		update::use_ram_vector_table(&dp.RCC, &dp.SYSCFG);
+
+		// Find out why we reset, before the RCC is configured
+		let bmc_reset_reason = reset::read_and_clear_bmc_reason(&dp.RCC);
+		defmt::info!("BMC reset reason: 0x{:x}", bmc_reset_reason);
+		let crash_record = crash::take();
+		if let Some(record) = crash_record {
+			defmt::warn!("BMC crashed before reset: {:?}", record);
+		}
+
 		let mut flash = dp.FLASH;
 		let mut rcc = dp
 			.RCC
@@ -236,6 +478,10 @@ mod app {
 			pin_cipo,
 			pin_copi,
 			mut pin_irq,
+			pin_i2c_scl,
+			pin_i2c_sda,
+			pin_mon_3v3,
+			pin_mon_5v0,
 		) = cortex_m::interrupt::free(|cs| {
 			(
 				// uart_tx,
@@ -281,6 +527,14 @@ mod app {
 				gpioa.pa7.into_alternate_af0(cs),
 				// pin_irq
 				gpioa.pa8.into_push_pull_output(cs),
+				// pin_i2c_scl
+				gpiob.pb6.into_alternate_af1(cs).set_open_drain(cs),
+				// pin_i2c_sda
+				gpiob.pb7.into_alternate_af1(cs).set_open_drain(cs),
+				// pin_mon_3v3
+				gpioa.pa0.into_analog(cs),
+				// pin_mon_5v0
+				gpioa.pa1.into_analog(cs),
 			)
 		});
 
@@ -300,15 +554,25 @@ mod app {
 
 		serial.listen(serial::Event::Rxne);
 
-		// Put SPI into Peripheral mode (i.e. CLK is an input) and enable the RX interrupt.
+		// Put SPI into Peripheral mode (i.e. CLK is an input), driven by DMA.
 		let spi = neotron_bmc_pico::spi::SpiPeripheral::new(
 			dp.SPI1,
 			(pin_sck, pin_cipo, pin_copi),
 			&mut rcc,
 		);
 
+		// The host's I²C transfers are run from the idle loop
+		let i2c = i2c::Controller::new(dp.I2C1, (pin_i2c_scl, pin_i2c_sda), &mut rcc);
+
+		// The BMC shell can show the rail voltages
+		let rails = rails::Monitor::new(dp.ADC, (pin_mon_3v3, pin_mon_5v0), &mut rcc);
+
 		led_power.set_low().unwrap();
 
+		// This borrows TIM14 to calibrate the clock, so do it before the
+		// speaker gets it.
+		let rtc = rtc::Hardware::new(dp.RTC, &dp.PWR, &dp.TIM14, rcc.clocks.sysclk().0);
+
 		speaker::RegisterState::default().setup(&mut rcc, &dp.TIM14);
 
 		// Set EXTI15 to use PORT A (PA15) - button input
@@ -328,20 +592,44 @@ mod app {
 		dp.EXTI.ftsr.modify(|_r, w| w.tr4().set_bit());
 		dp.EXTI.rtsr.modify(|_r, w| w.tr4().set_bit());
 
+		let config_store = config::Store::new();
+		let flash = flash::Flash::new(flash);
+		let (mut msg_q_in, msg_q_out) = ctx.local.queue.split();
+
+		// Should we turn the system on straight away (e.g. we've just been
+		// plugged in and the host was on when we were unplugged)?
+		let mut power_policy = power::PowerPolicy::new();
+		if let Some(settings) = config_store.load() {
+			power_policy.set_bits(settings.power_policy);
+		}
+		if power_policy.power_on_at_start(config_store.last_power_on()) {
+			defmt::info!("Restoring power ({:?})", power_policy.ac_restore());
+			// Like a quick press-and-release of the power button. The queue
+			// is empty, so these can't fail.
+			let _ = msg_q_in.enqueue(Message::PowerButtonShortPress(HostReason::AcRestore));
+			let _ = msg_q_in.enqueue(Message::PowerButtonRelease);
+			// The LED doesn't need to blink
+			led_power.set_high().unwrap();
+		} else {
+			led_power_blink::spawn().unwrap();
+		}
+
 		// Spawn the tasks that run all the time
-		led_power_blink::spawn().unwrap();
 		button_poll::spawn().unwrap();
 
-		defmt::info!("Init complete!");
+		// Start the independent watchdog. The idle loop feeds it, and if that
+		// ever stops happening, the BMC resets.
+		let mut watchdog = watchdog::Watchdog::new(dp.IWDG);
+		watchdog.start(IWDG_FREQUENCY_HZ.hz());
 
-		let (msg_q_in, msg_q_out) = ctx.local.queue.split();
+		defmt::info!("Init complete!");
 
 		let shared_resources = Shared {
 			serial,
 			_pin_uart_cts,
 			_pin_uart_rts,
 			led_power,
-			speaker: speaker::Hardware::new(dp.TIM14),
+			speaker: speaker::Hardware::new(dp.TIM14, &rcc.clocks),
 			button_power,
 			button_reset,
 			state_dc_power_enabled: DcPowerState::Off,
@@ -357,6 +645,7 @@ mod app {
 			spi,
 			pin_cs,
 			kb_decoder: neotron_bmc_pico::ps2::Ps2Decoder::new(),
+			diag: diag::Diagnostics::new(),
 		};
 		let local_resources = Local {
 			press_button_power_short: debouncr::debounce_2(false),
@@ -364,6 +653,14 @@ mod app {
 			press_button_reset_short: debouncr::debounce_2(false),
 			rcc: Some(rcc),
 			pin_irq,
+			rtc,
+			bmc_reset_reason,
+			crash_record,
+			watchdog,
+			config_store,
+			flash,
+			i2c,
+			rails,
 		};
 		let init = init::Monotonics(mono);
 		(shared_resources, local_resources, init)
@@ -372,20 +669,39 @@ mod app {
 	/// Our idle task.
 	///
 	/// This task is called when there is nothing else to do.
-	#[idle(shared = [msg_q_out, msg_q_in, spi, state_dc_power_enabled, pin_dc_on, pin_sys_reset, speaker], local = [pin_irq, rcc, speaker_task_handle: Option<speaker_pwm_stop::MyMono::SpawnHandle> = None])]
+	#[idle(shared = [msg_q_out, msg_q_in, spi, diag, serial, state_dc_power_enabled, pin_dc_on, pin_sys_reset, speaker], local = [pin_irq, rcc, rtc, bmc_reset_reason, crash_record, watchdog, config_store, flash, i2c, rails, speaker_task_handle: Option<speaker_pwm_stop::MyMono::SpawnHandle> = None, host_watchdog_task_handle: Option<host_watchdog_expired::MyMono::SpawnHandle> = None])]
 	fn idle(mut ctx: idle::Context) -> ! {
 		// TODO: Get this from the VERSION static variable or from PKG_VERSION
 		let mut register_state = RegisterState {
 			firmware_version: VERSION,
+			interrupt_control: interrupt::PS2_KB_RX_NOT_EMPTY,
+			bmc_reset_reason: *ctx.local.bmc_reset_reason,
+			crash_record: ctx.local.crash_record.unwrap_or_default(),
+			config_version: ctx.local.config_store.save_count(),
 			..Default::default()
 		};
+		register_state.log_event(Event::BmcStart, register_state.bmc_reset_reason);
+		if let Some(settings) = ctx.local.config_store.load() {
+			defmt::info!("Loaded settings: {:?}", settings);
+			register_state.apply_settings(&settings);
+		}
 		// Take this out of the `local` object to avoid sharing issues.
 		let mut rcc = ctx.local.rcc.take().unwrap();
+		// Watches the keyboard for shortcuts like Ctrl-Alt-Del
+		let mut key_tracker = keyboard::KeyTracker::new();
+		// Shares the UART between the host and our shell
+		let mut console = console::Console::new();
+		// Output the shell hasn't printed yet
+		let mut shell_job: Option<ShellJob> = None;
 		defmt::info!("Idle is running...");
 		let mut irq_masked = true;
 		let mut is_high = false;
 		loop {
-			if !irq_masked && !register_state.ps2_kb_bytes.is_empty() {
+			ctx.local.watchdog.feed();
+
+			let irq_pending =
+				(register_state.interrupt_status() & register_state.interrupt_control) != 0;
+			if !irq_masked && irq_pending {
 				// We need service
 				ctx.local.pin_irq.set_low().unwrap();
 				if is_high {
@@ -405,26 +721,82 @@ mod app {
 				Some(Message::Ps2Data0(word)) => {
 					if let Some(byte) = neotron_bmc_pico::ps2::Ps2Decoder::check_word(word) {
 						defmt::info!("< KB 0x{:x}", byte);
-						if let Err(_x) = register_state.ps2_kb_bytes.push_back(byte) {
+						ctx.shared.diag.lock(|d| d.count(diag::Stat::Ps2KbWords));
+						let shortcut = key_tracker.handle_byte(byte);
+						if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::Off {
+							// The host is off so it can't have this byte, but
+							// the keypress might be a request to wake up.
+							let is_wake_key = key_tracker
+								.make_code()
+								.map(|code| register_state.power_policy.is_wake_key(code))
+								.unwrap_or(false);
+							if is_wake_key {
+								defmt::info!("Wake on keyboard");
+								// Like a quick press-and-release of the power button
+								send_messages(
+									&mut ctx.shared.msg_q_in,
+									&mut ctx.shared.diag,
+									[
+										Message::PowerButtonShortPress(HostReason::WakeOnKeyboard),
+										Message::PowerButtonRelease,
+									],
+								);
+							}
+						} else if let Err(_x) = register_state.ps2_kb_bytes.push_back(byte) {
 							defmt::warn!("KB overflow!");
+							ctx.shared.diag.lock(|d| d.count(diag::Stat::Ps2Overflows));
+							register_state.log_event(Event::Ps2Overflow, 0);
+						}
+						if let Some(action) = shortcut {
+							if register_state.kb_shortcuts.is_enabled(action) {
+								defmt::info!("KB shortcut {:?}", action);
+								// Pretend the appropriate button was pressed
+								let msg = match action {
+									keyboard::Action::Reset => {
+										Message::ResetButtonShortPress(HostReason::KeyboardShortcut)
+									}
+									keyboard::Action::PowerPress => {
+										Message::PowerButtonShortPress(HostReason::KeyboardShortcut)
+									}
+									keyboard::Action::PowerRelease => Message::PowerButtonRelease,
+									keyboard::Action::ForcePowerOff => {
+										Message::PowerButtonLongPress(HostReason::KeyboardShortcut)
+									}
+								};
+								send_messages(
+									&mut ctx.shared.msg_q_in,
+									&mut ctx.shared.diag,
+									[msg],
+								);
+							}
 						}
 					} else {
 						defmt::warn!("< Bad KB 0x{:x}", word);
+						ctx.shared.diag.lock(|d| d.count(diag::Stat::Ps2KbErrors));
+						register_state.log_event(Event::Ps2Error, 0);
 					}
 				}
 				Some(Message::Ps2Data1(word)) => {
 					if let Some(byte) = neotron_bmc_pico::ps2::Ps2Decoder::check_word(word) {
 						defmt::info!("< MS 0x{:x}", byte);
+						ctx.shared.diag.lock(|d| d.count(diag::Stat::Ps2MouseWords));
 					} else {
 						defmt::warn!("< Bad MS 0x{:x}", word);
+						ctx.shared
+							.diag
+							.lock(|d| d.count(diag::Stat::Ps2MouseErrors));
+						register_state.log_event(Event::Ps2Error, 1);
 					}
 				}
-				Some(Message::PowerButtonLongPress) => {
+				Some(Message::PowerButtonLongPress(reason)) => {
 					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::On {
-						defmt::info!("Power off requested!");
+						defmt::info!("Power off requested ({:?})!", reason);
+						register_state.host_reset_reason.last_stop = reason;
+						register_state.log_event(Event::PowerOff, reason as u8);
 						ctx.shared
 							.state_dc_power_enabled
 							.lock(|r| *r = DcPowerState::Off);
+						HOST_POWERED.store(false, Ordering::Relaxed);
 						// Stop any SPI stuff that's currently going on (the host is about to be powered off)
 						ctx.shared.spi.lock(|s| s.reset(&mut rcc));
 						// Put the host into reset
@@ -433,32 +805,59 @@ mod app {
 						ctx.shared.pin_dc_on.set_low().unwrap();
 						// Mask the IRQ to avoid back-powering the host
 						irq_masked = true;
-						// Start LED blinking again
-						led_power_blink::spawn().unwrap();
+						// The host can't kick the watchdog when it's off
+						register_state.host_watchdog.disable();
+						// Start LED blinking again. Returns an error if a blink is
+						// still scheduled from before power-up, but that one will
+						// see we're off and keep blinking (so we don't care).
+						let _ = led_power_blink::spawn();
+						// Whatever the policy, so it's right if the policy changes
+						// to "last state" and then the PSU is unplugged
+						if let Err(e) = ctx
+							.local
+							.config_store
+							.set_last_power_on(ctx.local.flash, false)
+						{
+							defmt::warn!("Failed to record power state: {:?}", e);
+						}
 					}
 				}
-				Some(Message::PowerButtonShortPress) => {
+				Some(Message::PowerButtonShortPress(reason)) => {
 					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::Off {
-						defmt::info!("Power up requested!");
+						defmt::info!("Power up requested ({:?})!", reason);
+						register_state.host_reset_reason.last_start = reason;
+						register_state.log_event(Event::PowerOn, reason as u8);
+						// Keep what the host sent last time, in case it crashed
+						register_state.uart.history_mut().host_started();
 						// Button pressed - power on system.
-						// Step 1 - enable speaker and play power-up tune
-						ctx.shared.speaker.lock(|speaker| speaker.enable());
-						speaker_init_tune::spawn().unwrap();
+						// Step 1 - play power-up tune
+						play_init_tune(&mut ctx.shared.speaker, ctx.local.speaker_task_handle);
 						// Step 2 - Note our new power state
 						ctx.shared
 							.state_dc_power_enabled
 							.lock(|r| *r = DcPowerState::Starting);
+						HOST_POWERED.store(true, Ordering::Relaxed);
+						ctx.shared.diag.lock(|d| d.count(diag::Stat::PowerCycles));
 						// Step 3 - Hold reset line (active) low
 						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
 						// Step 4 - Turn on PSU
 						ctx.shared.pin_dc_on.set_high().unwrap();
 						// Step 5 - Leave it in reset for a while.
-						// TODO: Start monitoring 3.3V and 5.0V rails here
-						// TODO: Take system out of reset when 3.3V and 5.0V are good
+						// TODO: Wait until `rails::Monitor` says the 3.3V and
+						// 5.0V rails are good, rather than for a fixed time
 						// Returns an error if it's already scheduled (but we don't care)
 						let _ = exit_reset::spawn_after(RESET_DURATION_MS.millis());
 						// Set 6 - unmask the IRQ
 						irq_masked = false;
+						// Whatever the policy, so it's right if the policy changes
+						// to "last state" and then the PSU is unplugged
+						if let Err(e) = ctx
+							.local
+							.config_store
+							.set_last_power_on(ctx.local.flash, true)
+						{
+							defmt::warn!("Failed to record power state: {:?}", e);
+						}
 					}
 				}
 				Some(Message::PowerButtonRelease) => {
@@ -473,15 +872,20 @@ mod app {
 							.lock(|r| *r = DcPowerState::On);
 					}
 				}
-				Some(Message::ResetButtonShortPress) => {
+				Some(Message::ResetButtonShortPress(reason)) => {
 					// Is the board powered on? Don't do a reset if it's powered off.
 					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::On {
-						defmt::info!("Reset!");
+						defmt::info!("Reset ({:?})!", reason);
+						register_state.host_reset_reason.last_start = reason;
+						register_state.log_event(Event::HostReset, reason as u8);
+						// The rebooted host must turn the watchdog back on
+						register_state.host_watchdog.disable();
+						// Keep what the host sent before, in case it crashed
+						register_state.uart.history_mut().host_started();
 						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
 
 						// play power-up tune
-						ctx.shared.speaker.lock(|speaker| speaker.enable());
-						speaker_init_tune::spawn().unwrap();
+						play_init_tune(&mut ctx.shared.speaker, ctx.local.speaker_task_handle);
 
 						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
 						ctx.shared.spi.lock(|s| s.reset(&mut rcc));
@@ -492,64 +896,482 @@ mod app {
 						let _ = exit_reset::spawn_after(RESET_DURATION_MS.millis());
 					}
 				}
-				Some(Message::SpiEnable) => {
-					if ctx.shared.state_dc_power_enabled.lock(|r| *r) != DcPowerState::Off {
-						// Turn on the SPI peripheral and expect four bytes (the
-						// length of a Request).
-						ctx.shared.spi.lock(|s| s.start(4));
-					} else {
-						// Ignore message - it'll be the CS line being pulled low when the host is powered off
-						defmt::info!("Ignoring spurious CS low");
-					}
-				}
-				Some(Message::SpiDisable) => {
-					// Turn off the SPI peripheral. Don't need to check power state for this.
-					ctx.shared.spi.lock(|s| s.stop());
-					defmt::trace!("SPI Disable");
-				}
 				Some(Message::SpiRx) => {
 					defmt::trace!("SpiRx");
-					// Look for something in the SPI bytes received buffer:
+					// Copy what we received out, so we don't hold the SPI lock
+					// for long.
 					let mut req = None;
+					let mut payload = [0u8; 64];
+					let mut payload_len = None;
+					let mut is_payload = false;
+					let mut error = None;
+					let mut transaction = 0;
 					ctx.shared.spi.lock(|spi| {
-						if let Some((data, crc)) = spi.get_received() {
-							use proto::Receivable;
-							match proto::Request::from_bytes_with_crc(data, crc) {
-								Ok(inner_req) => {
-									defmt::trace!("Got packet");
-									req = Some(inner_req);
-								}
-								Err(proto::Error::BadLength) => {
-									// This is a programming bug. We said
-									// start(4) earlier, so there should be four
-									// bytes here.
-									panic!("Wanted 4, got {}", data.len());
+						transaction = spi.transaction();
+						match spi.get_received() {
+							Some((spi::Received::Request, data, crc)) => {
+								use proto::Receivable;
+								match proto::Request::from_bytes_with_crc(data, crc) {
+									Ok(inner_req) => {
+										defmt::trace!("Got packet");
+										req = Some(inner_req);
+									}
+									Err(e) => {
+										defmt::warn!("Bad Req {:?} ({=[u8]:x}", e, data);
+										error = Some(e);
+									}
 								}
-								Err(e) => {
-									defmt::warn!("Bad Req {:?} ({=[u8]:x}", e, data);
+							}
+							Some((spi::Received::Payload, data, crc)) => {
+								is_payload = true;
+								// It's a quirk of CRC-8 that including the CRC
+								// always produces a result of zero.
+								if crc == 0 && !data.is_empty() {
+									let len = data.len() - 1;
+									payload[0..len].copy_from_slice(&data[0..len]);
+									payload_len = Some(len);
+								} else {
+									defmt::warn!("Bad payload CRC ({=[u8]:x})", data);
 								}
 							}
+							None => {
+								// Chip select went high before we got here,
+								// so the host has given up on it.
+								defmt::trace!("SPI transaction cancelled");
+							}
 						}
 					});
 
-					// If we got a valid message, queue it so we can look at it next time around
-					if let Some(req) = req {
-						process_command(req, &mut register_state, |rsp| {
-							ctx.shared.spi.lock(|spi| {
-								spi.set_transmit_sendable(rsp).unwrap();
-							});
+					let fault = match error {
+						None if is_payload && payload_len.is_none() => {
+							Some(diag::Error::PayloadCrc)
+						}
+						None => None,
+						Some(proto::Error::BadCrc) => Some(diag::Error::RequestCrc),
+						Some(_) => Some(diag::Error::RequestMalformed),
+					};
+					if let Some(fault) = fault {
+						ctx.shared.diag.lock(|d| d.error(fault));
+						register_state.log_event(Event::SpiError, fault as u8);
+					}
+					if !is_payload && (req.is_some() || error.is_some()) {
+						let is_retry = req.is_some()
+							&& (register_state.last_req == req
+								|| register_state.last_write.as_ref().map(|w| &w.req)
+									== req.as_ref());
+						ctx.shared.diag.lock(|d| {
+							d.count(diag::Stat::SpiRequests);
+							if is_retry {
+								d.count(diag::Stat::Retries);
+							}
+						});
+					}
+
+					if req.is_some() {
+						register_state.rtc.now = ctx.local.rtc.now();
+						register_state.diag = ctx.shared.diag.lock(|d| *d);
+						register_state.uptime_secs =
+							monotonics::now().duration_since_epoch().to_secs() as u32;
+					}
+
+					if !is_payload {
+						// A Request starts afresh, so any Long Write we were
+						// expecting has been cancelled (the host raised chip
+						// select instead of sending the payload).
+						register_state.long_write = None;
+					}
+
+					// Only answer if the host is still listening. If it raised
+					// chip select, this response belongs to nobody.
+					let mut outcome = Ok(());
+					let mut respond = |rsp: &proto::Response, payload_len: Option<usize>| {
+						if rsp.result == proto::ResponseResult::BadRegister {
+							ctx.shared.diag.lock(|d| d.count(diag::Stat::BadRegisters));
+						}
+						outcome = ctx.shared.spi.lock(|spi| {
+							if !spi.is_current(transaction) {
+								defmt::trace!("SPI transaction cancelled");
+								return Ok(());
+							}
+							if let Some(num_bytes) = payload_len {
+								// We said OK, so the payload (and its
+								// CRC) will follow our response.
+								if spi.expect_payload(num_bytes).is_err() {
+									// We've no room for it, so refuse it
+									spi.set_transmit_sendable(&proto::Response::new_without_data(
+										proto::ResponseResult::BadLength,
+									))?;
+									return Err(spi::Error::PayloadLost);
+								}
+							}
+							spi.set_transmit_sendable(rsp)
 						});
+					};
+
+					let mut spi_reset_needed = false;
+					match error {
+						None => {}
+						Some(proto::Error::BadCrc) => {
+							respond(
+								&proto::Response::new_without_data(
+									proto::ResponseResult::CrcFailure,
+								),
+								None,
+							);
+						}
+						Some(proto::Error::BadRequestType) => {
+							respond(
+								&proto::Response::new_without_data(
+									proto::ResponseResult::BadRequestType,
+								),
+								None,
+							);
+						}
+						Some(_) => {
+							// We said start(4) earlier, so there should be
+							// four bytes here. Something is very wrong.
+							spi_reset_needed = true;
+						}
+					}
+
+					if is_payload {
+						// This is the payload for the Long Write Request we OK'd.
+						if let Some(req) = register_state.long_write.take() {
+							let result = match payload_len {
+								Some(len) => {
+									let payload = &payload[0..len];
+									let payload_crc = Some(proto::calculate_crc(payload));
+									match &register_state.last_write {
+										Some(last)
+											if last.req == req
+												&& last.payload_crc == payload_crc =>
+										{
+											// A duplicate, so don't do it again
+											defmt::debug!("Detected a write retry");
+											last.result
+										}
+										_ => {
+											let result = process_long_write(
+												&req,
+												payload,
+												&mut register_state,
+											);
+											register_state.last_write = Some(LastWrite {
+												req,
+												payload_crc,
+												result,
+											});
+											result
+										}
+									}
+								}
+								None => proto::ResponseResult::CrcFailure,
+							};
+							respond(&proto::Response::new_without_data(result), None);
+						}
+					} else if let Some(req) = req {
+						process_command(req, &mut register_state, respond);
+					}
+
+					match outcome {
+						Ok(()) => {}
+						Err(spi::Error::PayloadLost) => {
+							// The host will time out waiting for our second
+							// response, and try again.
+							register_state.long_write = None;
+							ctx.shared.diag.lock(|d| d.error(diag::Error::PayloadLost));
+							register_state
+								.log_event(Event::SpiError, diag::Error::PayloadLost as u8);
+						}
+						Err(spi::Error::TooLarge) => {
+							defmt::warn!("Response didn't fit");
+							register_state.long_write = None;
+							spi_reset_needed = true;
+							ctx.shared
+								.diag
+								.lock(|d| d.error(diag::Error::ResponseFailed));
+							register_state
+								.log_event(Event::SpiError, diag::Error::ResponseFailed as u8);
+						}
+					}
+
+					if spi_reset_needed {
+						// Drop the transaction. The host will time out, and
+						// the next falling edge on chip select starts afresh.
+						ctx.shared.spi.lock(|s| s.reset(&mut rcc));
+						ctx.shared.diag.lock(|d| d.error(diag::Error::SpiReset));
+						register_state.log_event(Event::SpiError, diag::Error::SpiReset as u8);
 					}
 				}
 				Some(Message::UartByte(rx_byte)) => {
-					defmt::info!("UART RX {:?}", rx_byte);
-					// TODO: Copy byte to software buffer and turn UART RX
-					// interrupt off if buffer is full
+					defmt::trace!("UART RX {:?}", rx_byte);
+					match console.handle_byte(rx_byte) {
+						console::Action::None => {}
+						console::Action::ToHost(first, second) => {
+							register_state.uart.receive(first);
+							if let Some(b) = second {
+								register_state.uart.receive(b);
+							}
+						}
+						console::Action::BadFrame(e) => {
+							defmt::warn!("Bad frame: {:?}", e);
+						}
+						console::Action::EnterShell => {
+							defmt::info!("Console in shell mode");
+						}
+						console::Action::LeaveShell => {
+							defmt::info!("Console in pass-through mode");
+							shell_job = None;
+						}
+						console::Action::Line => {
+							let command = shell::parse(console.line());
+							defmt::info!("Shell command {:?}", command);
+							match command {
+								Ok(shell::Command::Help) => {
+									// Printed a bit at a time, below
+									shell_job = Some(ShellJob::Help(0));
+								}
+								Ok(shell::Command::Status) => {
+									let state = match ctx.shared.state_dc_power_enabled.lock(|r| *r)
+									{
+										DcPowerState::Off => "Off",
+										DcPowerState::Starting => "Starting",
+										DcPowerState::On => "On",
+									};
+									let reason = register_state.host_reset_reason;
+									let _ = write!(
+										console,
+										"Host: {}\r\nLast start: {:?}\r\nLast stop: {:?}\r\nUptime: {} s\r\n",
+										state, reason.last_start, reason.last_stop, register_state.uptime_secs
+									);
+								}
+								Ok(shell::Command::PowerOn) => {
+									// Like a quick press-and-release of the power button
+									let sent = send_messages(
+										&mut ctx.shared.msg_q_in,
+										&mut ctx.shared.diag,
+										[
+											Message::PowerButtonShortPress(HostReason::Console),
+											Message::PowerButtonRelease,
+										],
+									);
+									console.print(if sent {
+										"OK\r\n"
+									} else {
+										"Busy - try again\r\n"
+									});
+								}
+								Ok(shell::Command::PowerOff) => {
+									// Like holding down the power button
+									let sent = send_messages(
+										&mut ctx.shared.msg_q_in,
+										&mut ctx.shared.diag,
+										[Message::PowerButtonLongPress(HostReason::Console)],
+									);
+									console.print(if sent {
+										"OK\r\n"
+									} else {
+										"Busy - try again\r\n"
+									});
+								}
+								Ok(shell::Command::Reset) => {
+									// Like pressing the reset button
+									let sent = send_messages(
+										&mut ctx.shared.msg_q_in,
+										&mut ctx.shared.diag,
+										[Message::ResetButtonShortPress(HostReason::Console)],
+									);
+									console.print(if sent {
+										"OK\r\n"
+									} else {
+										"Busy - try again\r\n"
+									});
+								}
+								Ok(shell::Command::Rails) => {
+									let v = ctx.local.rails.read();
+									let _ = write!(
+										console,
+										"3V3: {} mV\r\n5V0: {} mV\r\n",
+										v.rail_3v3_mv, v.rail_5v0_mv
+									);
+								}
+								Ok(shell::Command::Log) => {
+									// Printed a bit at a time, below
+									shell_job = Some(ShellJob::Log(0));
+								}
+								Ok(shell::Command::History(previous)) => {
+									// Printed a bit at a time, below
+									shell_job = Some(ShellJob::History(previous, 0));
+								}
+								Ok(shell::Command::Version) => {
+									let version = VERSION.split(|c| *c == 0).next().unwrap();
+									console.print(core::str::from_utf8(version).unwrap_or("?"));
+									console.print("\r\n");
+								}
+								Ok(shell::Command::Frames) => {
+									console.enter_frames();
+								}
+								Ok(shell::Command::Exit) => {
+									console.toggle();
+								}
+								Ok(shell::Command::ConfigGet(Some(setting))) => {
+									let value = register_state.settings().shell_value(setting);
+									let _ = write!(console, "{} = {}\r\n", setting.name(), value);
+								}
+								Ok(shell::Command::ConfigGet(None)) => {
+									// Printed a bit at a time, below
+									shell_job = Some(ShellJob::Config(0));
+								}
+								Ok(shell::Command::ConfigSet(setting, value)) => {
+									let mut settings = register_state.settings();
+									settings.set_shell_value(setting, value);
+									register_state.apply_settings(&settings);
+									console.print("OK - use 'config save' to keep it\r\n");
+								}
+								Ok(shell::Command::ConfigSave) => {
+									// Saved below, like a write to the Config
+									// Control register
+									register_state.config_pending = Some(config::Operation::Save);
+									shell_job = Some(ShellJob::Save);
+								}
+								Err(shell::Error::UnknownCommand) => {
+									console.print("Unknown command - try 'help'\r\n");
+								}
+								Err(shell::Error::BadArguments) => {
+									console.print("Bad arguments - try 'help'\r\n");
+								}
+								Err(shell::Error::UnknownSetting) => {
+									console.print("Unknown setting - try 'config get'\r\n");
+								}
+								Err(shell::Error::BadValue) => {
+									console.print("Bad value\r\n");
+								}
+							}
+							if shell_job.is_none() {
+								console.prompt();
+							}
+						}
+						action @ (console::Action::Request | console::Action::Payload) => {
+							// Copy it out, as our response goes to the console
+							let mut frame = [0u8; console::FRAME_LEN];
+							let frame_len = console.frame().len();
+							frame[0..frame_len].copy_from_slice(console.frame());
+							let frame = &frame[0..frame_len];
+
+							register_state.rtc.now = ctx.local.rtc.now();
+							register_state.diag = ctx.shared.diag.lock(|d| *d);
+							register_state.uptime_secs =
+								monotonics::now().duration_since_epoch().to_secs() as u32;
+
+							// What to say, if it isn't up to `process_command`
+							let mut result = None;
+							let mut req = None;
+							if action == console::Action::Payload {
+								// This is the payload for the Long Write Request we OK'd.
+								if let Some(long_write) = register_state.long_write.take() {
+									// Including the CRC gives a CRC of zero
+									result = Some(if proto::calculate_crc(frame) == 0 {
+										process_long_write(
+											&long_write,
+											&frame[0..frame_len - 1],
+											&mut register_state,
+										)
+									} else {
+										ctx.shared.diag.lock(|d| d.error(diag::Error::PayloadCrc));
+										proto::ResponseResult::CrcFailure
+									});
+								}
+							} else {
+								use proto::Receivable;
+								// Any Long Write we were expecting has been
+								// abandoned.
+								register_state.long_write = None;
+								match proto::Request::from_bytes(frame) {
+									Ok(inner_req) => req = Some(inner_req),
+									Err(proto::Error::BadCrc) => {
+										ctx.shared.diag.lock(|d| d.error(diag::Error::RequestCrc));
+										result = Some(proto::ResponseResult::CrcFailure);
+									}
+									Err(proto::Error::BadRequestType) => {
+										result = Some(proto::ResponseResult::BadRequestType);
+									}
+									Err(_) => {
+										// Not a Request at all, so ignore it
+										ctx.shared
+											.diag
+											.lock(|d| d.error(diag::Error::RequestMalformed));
+									}
+								}
+							}
+
+							let mut respond =
+								|rsp: &proto::Response, payload_len: Option<usize>| {
+									use proto::Sendable;
+									if rsp.result == proto::ResponseResult::BadRegister {
+										ctx.shared.diag.lock(|d| d.count(diag::Stat::BadRegisters));
+									}
+									let mut buffer = [0u8; console::FRAME_LEN];
+									if let Ok(len) = rsp.render_to_buffer(&mut buffer) {
+										if !console.send_frame(&buffer[0..len]) {
+											defmt::warn!("Frame lost");
+										}
+										if let Some(num_bytes) = payload_len {
+											console.expect_payload(num_bytes);
+										}
+									}
+								};
+							if let Some(req) = req {
+								process_command(req, &mut register_state, respond);
+							} else if let Some(result) = result {
+								respond(&proto::Response::new_without_data(result), None);
+							}
+						}
+					}
 				}
 				Some(Message::SpeakerDisable) => {
-					defmt::trace!("Speaker disabled");
-					ctx.shared.speaker.lock(|speaker| speaker.disable());
-					register_state.speaker.set_duration(0);
+					// The note has finished. The next one in the queue is
+					// started by the PWM update below.
+					if !register_state.speaker.play_next() {
+						defmt::trace!("Speaker disabled");
+						ctx.shared.speaker.lock(|speaker| speaker.disable());
+						if register_state.speaker.duration() != 0 {
+							// That was the last note the host asked for
+							register_state.interrupts_latched |= interrupt::SPEAKER_DONE;
+						}
+						register_state.speaker.set_duration(0);
+					}
+				}
+				Some(Message::HostWatchdogExpired) => {
+					// The task has run, so the handle is no longer valid
+					ctx.local.host_watchdog_task_handle.take();
+					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::On {
+						let action = register_state.host_watchdog.expired();
+						defmt::warn!("Host watchdog expired: {:?}", action);
+						register_state.log_event(Event::HostWatchdog, action as u8);
+						match action {
+							host_watchdog::Action::Interrupt => {
+								register_state.interrupts_latched |= interrupt::HOST_WATCHDOG;
+							}
+							host_watchdog::Action::Reset => {
+								// Like pressing the reset button
+								send_messages(
+									&mut ctx.shared.msg_q_in,
+									&mut ctx.shared.diag,
+									[Message::ResetButtonShortPress(HostReason::HostWatchdog)],
+								);
+							}
+							host_watchdog::Action::PowerCycle => {
+								// Like holding down the power button, and
+								// then pressing it again a little later.
+								send_messages(
+									&mut ctx.shared.msg_q_in,
+									&mut ctx.shared.diag,
+									[Message::PowerButtonLongPress(HostReason::HostWatchdog)],
+								);
+								let _ = power_cycle_on::spawn_after(POWER_CYCLE_OFF_MS.millis());
+							}
+						}
+					}
 				}
 				None => {
 					// No messages
@@ -562,28 +1384,232 @@ mod app {
 				register_state.speaker.set_needs_update(false);
 
 				let register = &mut register_state.speaker;
-				let task_handle = ctx.local.speaker_task_handle.take();
-
-				let keep_playing = ctx.shared.speaker.lock(|speaker| {
-					speaker.update(register, || {
-						if let Some(h) = task_handle {
-							// if there's a running "stop" task, reschedule it
-							defmt::trace!("Speaker task cancelled!");
-							h.cancel().unwrap_or_default();
-						}
-					})
-				});
+				// Whatever was playing has been replaced, so it mustn't stop
+				// (or skip) this note
+				if let Some(h) = ctx.local.speaker_task_handle.take() {
+					defmt::trace!("Speaker task cancelled!");
+					h.cancel().unwrap_or_default();
+				}
+
+				let keep_playing = ctx.shared.speaker.lock(|speaker| speaker.update(register));
 
 				if keep_playing {
-					// otherwise, spawn a new one
 					defmt::trace!("Speaker task spawned!");
-					ctx.local.speaker_task_handle.replace(
-						speaker_pwm_stop::spawn_after((register.duration() as u64).millis())
-							.unwrap(),
+					*ctx.local.speaker_task_handle = start_speaker_stop(register.duration());
+				}
+			}
+			// The host wants to be turned off or reset. Like the buttons, this
+			// goes through the message queue.
+			if let Some(request) = register_state.host_request.take() {
+				let msg = match request {
+					HostRequest::PowerOff => Message::PowerButtonLongPress(HostReason::HostRequest),
+					HostRequest::Reset => Message::ResetButtonShortPress(HostReason::HostRequest),
+				};
+				send_messages(&mut ctx.shared.msg_q_in, &mut ctx.shared.diag, [msg]);
+			}
+			// The host wants the settings saving (or wiping). We do this
+			// after we've replied, as erasing flash takes a while.
+			if let Some(op) = register_state.config_pending.take() {
+				defmt::info!("Config {:?}", op);
+				let result = match op {
+					config::Operation::Save => ctx
+						.local
+						.config_store
+						.save(ctx.local.flash, &register_state.settings()),
+					config::Operation::FactoryReset => {
+						let result = ctx.local.config_store.factory_reset(ctx.local.flash);
+						register_state.apply_settings(&config::Settings::new());
+						result
+					}
+				};
+				register_state.config_status = match result {
+					Ok(()) => config::Status::Ok,
+					Err(e) => {
+						defmt::warn!("Config {:?} failed: {:?}", op, e);
+						config::Status::Failed
+					}
+				};
+				register_state.config_version = ctx.local.config_store.save_count();
+			}
+
+			// Do any slow flash work for a firmware update
+			if register_state.update.poll(ctx.local.flash) == update::Next::Reset {
+				defmt::info!("Resetting to install new firmware");
+				// Give the response a chance to go out first
+				let _ = firmware_install::spawn_after(FIRMWARE_INSTALL_DELAY_MS.millis());
+			}
+
+			// Apply any new UART settings, and send what the host gave us
+			if register_state.uart.take_needs_config() {
+				let pclk = rcc.clocks.pclk().0;
+				ctx.shared
+					.serial
+					.lock(|_serial| register_state.uart.configure(pclk));
+			}
+			// The shell takes over the console whilst the host is off
+			if console.set_host_powered(HOST_POWERED.load(Ordering::Relaxed))
+				== console::Action::LeaveShell
+			{
+				shell_job = None;
+			}
+			// Print the shell's longer output a line at a time, as there's
+			// room
+			while let Some(job) = shell_job {
+				if console.output_space() < SHELL_LINE_LEN {
+					break;
+				}
+				shell_job = match job {
+					ShellJob::Help(index) => shell::HELP.get(index).map(|line| {
+						console.print(line);
+						console.print("\r\n");
+						ShellJob::Help(index + 1)
+					}),
+					ShellJob::Log(index) => register_state.event_log.get(index).map(|entry| {
+						let _ = write!(
+							console,
+							"{:>10} ms {:?} {}\r\n",
+							entry.timestamp_ms, entry.event, entry.arg
+						);
+						ShellJob::Log(index + 1)
+					}),
+					ShellJob::History(previous, index) => {
+						let history = register_state.uart.history().get(previous);
+						let mut sent = 0;
+						for byte in history.iter().skip(index).take(SHELL_LINE_LEN) {
+							console.print_byte(*byte);
+							sent += 1;
+						}
+						if sent > 0 {
+							Some(ShellJob::History(previous, index + sent))
+						} else {
+							// It might not have ended with a newline
+							console.print("\r\n");
+							None
+						}
+					}
+					ShellJob::Config(index) => shell::Setting::ALL.get(index).map(|setting| {
+						let value = register_state.settings().shell_value(*setting);
+						let _ = write!(console, "{} = {}\r\n", setting.name(), value);
+						ShellJob::Config(index + 1)
+					}),
+					ShellJob::Save => {
+						// The save happens before we get here
+						if register_state.config_status == config::Status::Ok {
+							console.print("Saved\r\n");
+						} else {
+							console.print("Save failed\r\n");
+						}
+						None
+					}
+				};
+				if shell_job.is_none() {
+					console.prompt();
+				}
+			}
+			// Our shell's output goes first, and the host's waits until we've
+			// left the shell.
+			while let Some(byte) = console.next_output() {
+				if ctx.shared.serial.lock(|serial| serial.write(byte)).is_err() {
+					console.unsend_output(byte);
+					break;
+				}
+			}
+			while let Some(byte) = register_state.uart.peek_tx() {
+				if !console.host_can_send() {
+					break;
+				}
+				if ctx.shared.serial.lock(|serial| serial.write(byte)).is_err() {
+					// No room - try again next time around
+					break;
+				}
+				register_state.uart.pop_tx();
+			}
+
+			// Move any I²C transfer along
+			let now_ms = app::monotonics::now().duration_since_epoch().to_millis() as u32;
+			ctx.local.i2c.poll(&mut register_state.i2c, now_ms);
+			if register_state.i2c.take_completed() {
+				register_state.interrupts_latched |= interrupt::I2C_COMPLETE;
+			}
+
+			if register_state.errors_clear_requested {
+				register_state.errors_clear_requested = false;
+				ctx.shared.diag.lock(|d| d.errors.clear());
+			}
+
+			if register_state.stats_clear_requested {
+				register_state.stats_clear_requested = false;
+				ctx.shared.diag.lock(|d| d.stats.clear());
+			}
+
+			if register_state.rom_boot_requested {
+				register_state.rom_boot_requested = false;
+				defmt::info!("Resetting into the ROM bootloader");
+				// Give the response a chance to go out first
+				let _ = rom_bootloader::spawn_after(FIRMWARE_INSTALL_DELAY_MS.millis());
+			}
+
+			// The host watchdog needs (re-)starting (register was updated)
+			if register_state.host_watchdog.needs_update() {
+				register_state.host_watchdog.set_needs_update(false);
+				if let Some(h) = ctx.local.host_watchdog_task_handle.take() {
+					h.cancel().unwrap_or_default();
+				}
+				let timeout = register_state.host_watchdog.timeout_secs();
+				// We only watch the host when it is fully on
+				if timeout > 0 && ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::On
+				{
+					ctx.local.host_watchdog_task_handle.replace(
+						host_watchdog_expired::spawn_after((timeout as u64).secs()).unwrap(),
 					);
 				}
 			}
-			// TODO: Read ADC for 3.3V and 5.0V rails and check good
+
+			// Has the RTC alarm gone off?
+			if ctx.local.rtc.alarm_fired() {
+				let action = register_state.rtc.alarm_action;
+				defmt::info!("RTC alarm: {:?}", action);
+				// The alarm is one-shot
+				register_state.rtc.alarm_action = rtc::AlarmAction::Disabled;
+				match action {
+					rtc::AlarmAction::PowerOn => {
+						// Like a quick press-and-release of the power button
+						send_messages(
+							&mut ctx.shared.msg_q_in,
+							&mut ctx.shared.diag,
+							[
+								Message::PowerButtonShortPress(HostReason::RtcAlarm),
+								Message::PowerButtonRelease,
+							],
+						);
+					}
+					rtc::AlarmAction::ShutdownRequest => {
+						register_state.interrupts_latched |= interrupt::RTC_ALARM;
+					}
+					rtc::AlarmAction::PowerOff => {
+						// Like holding down the power button
+						send_messages(
+							&mut ctx.shared.msg_q_in,
+							&mut ctx.shared.diag,
+							[Message::PowerButtonLongPress(HostReason::RtcAlarm)],
+						);
+					}
+					rtc::AlarmAction::Disabled => {}
+				}
+			}
+
+			// The RTC needs to be updated (register was updated)
+			if let Some(time) = register_state.rtc.new_time.take() {
+				defmt::info!("RTC set to {:?}", time);
+				ctx.local.rtc.set(&time);
+			}
+			if register_state.rtc.needs_update {
+				register_state.rtc.needs_update = false;
+				ctx.local.rtc.update(&register_state.rtc);
+			}
+
+			// TODO: Check the 3.3V and 5.0V rails here too. So far only the
+			// shell's `rails` command reads them.
 		}
 	}
 
@@ -592,10 +1618,12 @@ mod app {
 	/// It handles PS/2 clock edges, and SPI chip select edges.
 	///
 	/// It is very high priority, as we can't afford to miss a PS/2 clock edge.
+	/// It also means the SPI engine is armed before the host starts clocking,
+	/// however busy the idle loop is.
 	#[task(
 		binds = EXTI4_15,
 		priority = 4,
-		shared = [ps2_clk0, msg_q_in, ps2_dat0, exti, pin_cs, kb_decoder],
+		shared = [ps2_clk0, msg_q_in, ps2_dat0, exti, pin_cs, kb_decoder, spi, diag],
 	)]
 	fn exti4_15_interrupt(mut ctx: exti4_15_interrupt::Context) {
 		let pr = ctx.shared.exti.pr.read();
@@ -611,7 +1639,8 @@ mod app {
 					.lock(|q| q.enqueue(Message::Ps2Data0(data)))
 					.is_err()
 				{
-					panic!("queue full");
+					// Drop the word - the keyboard will have to live without it
+					ctx.shared.diag.lock(|d| d.error(diag::Error::QueueFull));
 				};
 			}
 			// Clear the pending flag for this pin
@@ -619,15 +1648,23 @@ mod app {
 		}
 
 		if pr.pr4().bit_is_set() {
-			let msg = if ctx.shared.pin_cs.lock(|pin| pin.is_low().unwrap()) {
-				// If incoming Chip Select is low, tell the main thread to turn on the SPI engine
-				Message::SpiEnable
+			if ctx.shared.pin_cs.lock(|pin| pin.is_low().unwrap()) {
+				if HOST_POWERED.load(Ordering::Relaxed) {
+					// Turn on the SPI peripheral and expect four bytes (the
+					// length of a Request).
+					if ctx.shared.spi.lock(|s| s.start(4)).is_err() {
+						ctx.shared
+							.diag
+							.lock(|d| d.error(diag::Error::RequestMalformed));
+					}
+				} else {
+					// It'll be the CS line being pulled low when the host is powered off
+					defmt::info!("Ignoring spurious CS low");
+				}
 			} else {
-				// If incoming Chip Select is high, tell the main thread to turn off the SPI engine
-				Message::SpiDisable
-			};
-			if ctx.shared.msg_q_in.lock(|q| q.enqueue(msg)).is_err() {
-				panic!("queue full");
+				// Turn off the SPI peripheral, cancelling the transaction.
+				// Don't need to check power state for this.
+				ctx.shared.spi.lock(|s| s.stop());
 			}
 			// Clear the pending flag for this pin
 			ctx.shared.exti.pr.write(|w| w.pr4().set_bit());
@@ -638,48 +1675,145 @@ mod app {
 	///
 	/// It fires whenever there is new data received on USART1. We should flag to the host
 	/// that data is available.
-	#[task(binds = USART1, shared = [serial, msg_q_in])]
+	#[task(binds = USART1, shared = [serial, msg_q_in, diag])]
 	fn usart1_interrupt(mut ctx: usart1_interrupt::Context) {
 		// Reading the register clears the RX-Not-Empty-Interrupt flag.
-		if let Ok(b) = ctx.shared.serial.read() {
-			let _ = ctx
-				.shared
-				.msg_q_in
-				.lock(|q| q.enqueue(Message::UartByte(b)));
+		match ctx.shared.serial.lock(|serial| serial.read()) {
+			Ok(b) => {
+				if ctx
+					.shared
+					.msg_q_in
+					.lock(|q| q.enqueue(Message::UartByte(b)))
+					.is_err()
+				{
+					ctx.shared.diag.lock(|d| d.error(diag::Error::QueueFull));
+				}
+			}
+			Err(nb::Error::Other(serial::Error::Overrun)) => {
+				defmt::warn!("UART overrun");
+				ctx.shared.diag.lock(|d| d.count(diag::Stat::UartOverruns));
+			}
+			Err(_) => {}
+		}
+	}
+
+	/// Put messages on the queue for the idle loop, in order.
+	///
+	/// If the queue is full, the rest are dropped and counted as a
+	/// [`diag::Error::QueueFull`], and you get `false`.
+	fn send_messages(
+		msg_q_in: &mut impl rtic::Mutex<T = Producer<'static, Message, 8>>,
+		diag: &mut impl rtic::Mutex<T = diag::Diagnostics>,
+		messages: impl IntoIterator<Item = Message>,
+	) -> bool {
+		for msg in messages {
+			if msg_q_in.lock(|q| q.enqueue(msg)).is_err() {
+				defmt::warn!("Message queue full");
+				diag.lock(|d| d.error(diag::Error::QueueFull));
+				return false;
+			}
 		}
+		true
 	}
 
-	/// Initialization melody, played directly by the BMC
-	#[task(shared = [speaker, msg_q_in])]
-	fn speaker_init_tune(mut ctx: speaker_init_tune::Context) {
+	/// Initialization melody, played directly by the BMC.
+	///
+	/// This replaces whatever the host was playing. Its stop task takes the
+	/// place of the host's, so when it stops, the host's tune carries on
+	/// with its next note.
+	fn play_init_tune(
+		speaker: &mut impl rtic::Mutex<T = speaker::Hardware>,
+		task_handle: &mut Option<speaker_pwm_stop::MyMono::SpawnHandle>,
+	) {
 		defmt::trace!("Playing startup tone");
 
-		ctx.shared.speaker.lock(|speaker| {
+		if let Some(h) = task_handle.take() {
+			h.cancel().unwrap_or_default();
+		}
+		speaker.lock(|speaker| {
 			// F4
-			speaker.set_note(100, 137, 10);
+			speaker.set_note(349, 10);
+			speaker.enable();
 		});
+		*task_handle = start_speaker_stop(100);
+	}
 
-		speaker_pwm_stop::spawn_after(100.millis()).unwrap();
+	/// Stop the speaker in `duration_ms`. There can only be one of these
+	/// waiting, so cancel the last one first.
+	fn start_speaker_stop(duration_ms: u16) -> Option<speaker_pwm_stop::MyMono::SpawnHandle> {
+		match speaker_pwm_stop::spawn_after(u64::from(duration_ms).millis()) {
+			Ok(handle) => Some(handle),
+			Err(_) => {
+				defmt::warn!("Speaker stop already pending");
+				None
+			}
+		}
 	}
 	/// Task which stops the speaker from playing
-	#[task(shared = [msg_q_in])]
+	#[task(shared = [msg_q_in, diag])]
 	fn speaker_pwm_stop(mut ctx: speaker_pwm_stop::Context) {
 		defmt::trace!("Speaker stopped");
-		let _ = ctx
-			.shared
-			.msg_q_in
-			.lock(|q| q.enqueue(Message::SpeakerDisable));
+		send_messages(
+			&mut ctx.shared.msg_q_in,
+			&mut ctx.shared.diag,
+			[Message::SpeakerDisable],
+		);
+	}
+
+	/// Task which fires if the host stops kicking the watchdog
+	#[task(shared = [msg_q_in, diag])]
+	fn host_watchdog_expired(mut ctx: host_watchdog_expired::Context) {
+		send_messages(
+			&mut ctx.shared.msg_q_in,
+			&mut ctx.shared.diag,
+			[Message::HostWatchdogExpired],
+		);
 	}
 
-	/// This is the SPI1 task.
+	/// Task which resets the BMC, so the bootloader can install new firmware
+	#[task]
+	fn firmware_install(_ctx: firmware_install::Context) {
+		cortex_m::peripheral::SCB::sys_reset();
+	}
+
+	/// Task which resets the BMC into the STM32's ROM bootloader
+	#[task]
+	fn rom_bootloader(_ctx: rom_bootloader::Context) {
+		rom_boot::reboot();
+	}
+
+	/// Task which turns the power back on, at the end of a power cycle
+	#[task(shared = [msg_q_in, diag])]
+	fn power_cycle_on(mut ctx: power_cycle_on::Context) {
+		defmt::info!("Power cycle - turning back on");
+		// Like a quick press-and-release of the power button
+		send_messages(
+			&mut ctx.shared.msg_q_in,
+			&mut ctx.shared.diag,
+			[
+				Message::PowerButtonShortPress(HostReason::HostWatchdog),
+				Message::PowerButtonRelease,
+			],
+		);
+	}
+
+	/// This is the SPI1 DMA task.
 	///
-	/// It fires whenever there is new data received on SPI1. We should flag to the host
-	/// that data is available.
-	#[task(binds = SPI1, shared = [spi, msg_q_in])]
-	fn spi1_interrupt(mut ctx: spi1_interrupt::Context) {
-		let has_message = ctx.shared.spi.lock(|spi| spi.handle_isr());
-		if has_message {
-			let _ = ctx.shared.msg_q_in.lock(|q| q.enqueue(Message::SpiRx));
+	/// It fires when the DMA has received a whole Request (or Long Write
+	/// Payload) from SPI1, or has finished loading our Response. It's high
+	/// priority, as the SPI FIFOs are only four bytes deep.
+	#[task(binds = DMA1_CH2_3, priority = 3, shared = [spi, msg_q_in, diag])]
+	fn dma1_ch2_3_interrupt(mut ctx: dma1_ch2_3_interrupt::Context) {
+		let has_message = ctx.shared.spi.lock(|spi| spi.handle_dma_isr());
+		if has_message
+			&& ctx
+				.shared
+				.msg_q_in
+				.lock(|q| q.enqueue(Message::SpiRx))
+				.is_err()
+		{
+			// We'll never answer it, so the host will time out and retry
+			ctx.shared.diag.lock(|d| d.error(diag::Error::QueueFull));
 		}
 	}
 
@@ -715,7 +1849,7 @@ mod app {
 	/// interrupt.
 	#[task(
 		shared = [
-			led_power, button_power, button_reset, msg_q_in, kb_decoder
+			led_power, button_power, button_reset, msg_q_in, kb_decoder, diag
 		],
 		local = [ press_button_power_short, press_button_power_long, press_button_reset_short ]
 	)]
@@ -755,26 +1889,30 @@ mod app {
 
 		if pwr_long_edge == Some(debouncr::Edge::Rising) {
 			// They pressed it a really long time
-			let _ = ctx
-				.shared
-				.msg_q_in
-				.lock(|q| q.enqueue(Message::PowerButtonLongPress));
+			send_messages(
+				&mut ctx.shared.msg_q_in,
+				&mut ctx.shared.diag,
+				[Message::PowerButtonLongPress(HostReason::PowerButton)],
+			);
 		}
 
 		match pwr_short_edge {
 			Some(debouncr::Edge::Rising) => {
 				// They pressed the power button (could be a short press, could be a long press)
-				let _ = ctx
-					.shared
-					.msg_q_in
-					.lock(|q| q.enqueue(Message::PowerButtonShortPress));
+				ctx.shared.diag.lock(|d| d.count(diag::Stat::ButtonPresses));
+				send_messages(
+					&mut ctx.shared.msg_q_in,
+					&mut ctx.shared.diag,
+					[Message::PowerButtonShortPress(HostReason::PowerButton)],
+				);
 			}
 			Some(debouncr::Edge::Falling) => {
 				// They released the power button
-				let _ = ctx
-					.shared
-					.msg_q_in
-					.lock(|q| q.enqueue(Message::PowerButtonRelease));
+				send_messages(
+					&mut ctx.shared.msg_q_in,
+					&mut ctx.shared.diag,
+					[Message::PowerButtonRelease],
+				);
 			}
 			_ => {
 				// Ignore
@@ -783,10 +1921,12 @@ mod app {
 
 		if rst_long_edge == Some(debouncr::Edge::Rising) {
 			// They pressed the reset button.
-			let _ = ctx
-				.shared
-				.msg_q_in
-				.lock(|q| q.enqueue(Message::ResetButtonShortPress));
+			ctx.shared.diag.lock(|d| d.count(diag::Stat::ButtonPresses));
+			send_messages(
+				&mut ctx.shared.msg_q_in,
+				&mut ctx.shared.diag,
+				[Message::ResetButtonShortPress(HostReason::ResetButton)],
+			);
 		}
 
 		// Re-schedule the timer interrupt
@@ -807,22 +1947,45 @@ mod app {
 /// Process an incoming command, converting a request into a response.
 fn process_command<F>(req: proto::Request, register_state: &mut RegisterState, rsp_handler: F)
 where
-	F: FnOnce(&proto::Response),
+	F: FnOnce(&proto::Response, Option<usize>),
 {
 	if register_state.last_req.as_ref() == Some(&req) {
 		// A duplicate! Resend what we sent last time (so we don't affect FIFOs with a duplicate read).
 		let length = req.length_or_data as usize;
 		let rsp = proto::Response::new_ok_with_data(&register_state.scratch[0..length]);
 		defmt::debug!("Detected a retry");
-		rsp_handler(&rsp);
+		rsp_handler(&rsp, None);
 		return;
 	}
 
 	// We were not sent what we were sent last time, so forget the previous request.
 	register_state.last_req = None;
 
+	match &register_state.last_write {
+		Some(last) if last.req == req => {
+			if last.payload_crc.is_none() {
+				// A duplicate Short Write! Say what we said last time, without doing it again.
+				let rsp = proto::Response::new_without_data(last.result);
+				defmt::debug!("Detected a write retry");
+				rsp_handler(&rsp, None);
+				return;
+			}
+			// A duplicate Long Write - we check the payload when it arrives
+		}
+		_ => {
+			register_state.last_write = None;
+		}
+	}
+
+	// Keep a Short Write, so we can spot it being retried
+	let short_write = if req.request_type.flatten() == proto::RequestType::ShortWrite {
+		Some(req.clone())
+	} else {
+		None
+	};
+
 	// temporary buffer to hold serialized data while the response is generated
-	let mut data = [0u8; 1];
+	let mut data = [0u8; 64];
 
 	// What do they want?
 	let rsp = match (req.request_type.flatten(), Command::try_from(req.register)) {
@@ -852,11 +2015,11 @@ where
 		(proto::RequestType::Read, Ok(Command::Ps2KbBuffer)) => {
 			defmt::trace!("Reading Ps2KbBuffer");
 			let length = req.length_or_data as usize;
-			if length > 0 && length <= register_state.scratch.len() {
+			if length > 0 && length <= MAX_FIFO_READ_LEN {
 				// First byte is the # bytes in the FIFO
 				register_state.scratch[0] = register_state.ps2_kb_bytes.len() as u8;
 				// Then as many of those FIFO bytes as fit
-				for slot in &mut register_state.scratch[1..] {
+				for slot in &mut register_state.scratch[1..length] {
 					if let Some(x) = register_state.ps2_kb_bytes.pop_front() {
 						*slot = x;
 					} else {
@@ -872,49 +2035,532 @@ where
 				proto::Response::new_without_data(proto::ResponseResult::BadLength)
 			}
 		}
+		(proto::RequestType::Read, Ok(Command::EventLog)) => {
+			defmt::trace!("Reading EventLog");
+			let length = req.length_or_data as usize;
+			if length > 0 && length <= MAX_FIFO_READ_LEN {
+				// First byte is the # entries in the log
+				register_state.scratch[0] = register_state.event_log.len() as u8;
+				// Then as many whole entries as fit
+				for chunk in register_state.scratch[1..length].chunks_mut(event_log::Entry::LEN) {
+					let entry = if chunk.len() == event_log::Entry::LEN {
+						register_state.event_log.pop()
+					} else {
+						None
+					};
+					match entry {
+						Some(entry) => chunk.copy_from_slice(&entry.as_bytes()),
+						None => chunk.fill(0),
+					}
+				}
+				// OK, cache this one because FIFO reads are damaging.
+				register_state.last_req = Some(req);
+				// Send the response
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::UartBuffer)) => {
+			defmt::trace!("Reading UartBuffer");
+			let length = req.length_or_data as usize;
+			if length > 0 && length <= MAX_FIFO_READ_LEN {
+				// First byte is the # bytes in the FIFO
+				register_state.scratch[0] = register_state.uart.rx_len() as u8;
+				// Then as many of those FIFO bytes as fit
+				for slot in &mut register_state.scratch[1..length] {
+					*slot = register_state.uart.pop_rx().unwrap_or(0);
+				}
+				// OK, cache this one because FIFO reads are damaging.
+				register_state.last_req = Some(req);
+				// Send the response
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::UartBuffer)) => {
+			defmt::trace!("Writing UartBuffer ({})", req.length_or_data);
+			match register_state.uart.queue(&[req.length_or_data]) {
+				Ok(()) => proto::Response::new_without_data(proto::ResponseResult::Ok),
+				Err(_) => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::UartFifoControl)) => {
+			defmt::trace!("Reading UART FIFO control");
+			data[0] = register_state.uart.tx_len() as u8;
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::UartFifoControl)) => {
+			defmt::debug!("Writing UART FIFO control ({})", req.length_or_data);
+			register_state.uart.flush(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::UartControl)) => {
+			defmt::debug!("Reading UART control");
+			data[0] = register_state.uart.control();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::UartControl)) => {
+			defmt::debug!("Writing UART control ({})", req.length_or_data);
+			match register_state.uart.set_control(req.length_or_data) {
+				Ok(()) => proto::Response::new_without_data(proto::ResponseResult::Ok),
+				Err(_) => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::UartHistoryControl)) => {
+			defmt::trace!("Reading UART history control");
+			data[0] = register_state.uart.history().unread() as u8;
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::UartHistoryControl)) => {
+			defmt::debug!("Writing UART history control ({})", req.length_or_data);
+			match register_state.uart.history_mut().select(req.length_or_data) {
+				Ok(()) => proto::Response::new_without_data(proto::ResponseResult::Ok),
+				Err(_) => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::UartHistory)) => {
+			defmt::trace!("Reading UART history");
+			let length = req.length_or_data as usize;
+			if length > 0 && length <= MAX_FIFO_READ_LEN {
+				let history = register_state.uart.history_mut();
+				// First byte is the # bytes left to read
+				register_state.scratch[0] = history.unread() as u8;
+				// Then as many of those bytes as fit
+				for slot in &mut register_state.scratch[1..length] {
+					*slot = history.read().unwrap_or(0);
+				}
+				// OK, cache this one because reading moves us along.
+				register_state.last_req = Some(req);
+				// Send the response
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::UartStatus)) => {
+			defmt::trace!("Reading UART status");
+			data[0] = register_state.uart.status();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::UartStatus)) => {
+			defmt::debug!("Clearing UART status ({})", req.length_or_data);
+			register_state.uart.clear_status(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::UartBaudRate)) => {
+			defmt::debug!("Reading UART baud rate");
+			let length = req.length_or_data as usize;
+			if length == 4 {
+				data[0..4].copy_from_slice(&register_state.uart.baud_rate().to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::I2cBuffer)) => {
+			defmt::trace!("Reading I2cBuffer");
+			let length = req.length_or_data as usize;
+			if length > 0 && length <= MAX_FIFO_READ_LEN {
+				// First byte is the # bytes in the FIFO
+				register_state.scratch[0] = register_state.i2c.rx_len() as u8;
+				// Then as many of those FIFO bytes as fit
+				for slot in &mut register_state.scratch[1..length] {
+					*slot = register_state.i2c.pop_rx().unwrap_or(0);
+				}
+				// OK, cache this one because FIFO reads are damaging.
+				register_state.last_req = Some(req);
+				// Send the response
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::I2cBuffer)) => {
+			defmt::debug!("Writing I2cBuffer ({})", req.length_or_data);
+			match register_state.i2c.queue(&[req.length_or_data]) {
+				Ok(()) => proto::Response::new_without_data(proto::ResponseResult::Ok),
+				Err(_) => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::I2cFifoControl)) => {
+			defmt::debug!("Reading I2C FIFO control");
+			data[0] = register_state.i2c.tx_len() as u8;
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::I2cFifoControl)) => {
+			defmt::debug!("Writing I2C FIFO control ({})", req.length_or_data);
+			match register_state.i2c.flush(req.length_or_data) {
+				Ok(()) => proto::Response::new_without_data(proto::ResponseResult::Ok),
+				Err(_) => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::I2cControl)) => {
+			defmt::debug!("Reading I2C control");
+			let length = req.length_or_data as usize;
+			if length == i2c::Transfer::LENGTH {
+				if let Some(transfer) = register_state.i2c.transfer() {
+					data[0..length].copy_from_slice(&transfer.as_bytes());
+				}
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::I2cStatus)) => {
+			defmt::debug!("Reading I2C status");
+			data[0] = register_state.i2c.status();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::I2cStatus)) => {
+			defmt::debug!("Clearing I2C status ({})", req.length_or_data);
+			register_state.i2c.clear_status(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::I2cBaudRate)) => {
+			defmt::debug!("Reading I2C baud rate");
+			let length = req.length_or_data as usize;
+			if length == 4 {
+				data[0..4].copy_from_slice(&register_state.i2c.baud_rate().to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::InterruptStatus)) => {
+			defmt::debug!("Reading interrupt status");
+			let length = req.length_or_data as usize;
+			if length == 1 || length == 2 {
+				data[0..2].copy_from_slice(&register_state.interrupt_status().to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::InterruptStatus)) => {
+			defmt::debug!("Clearing interrupts ({})", req.length_or_data);
+			register_state.interrupts_latched &= !u16::from(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::InterruptControl)) => {
+			defmt::debug!("Reading interrupt control");
+			let length = req.length_or_data as usize;
+			if length == 1 || length == 2 {
+				data[0..2].copy_from_slice(&register_state.interrupt_control.to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::InterruptControl)) => {
+			defmt::debug!("Writing interrupt control ({})", req.length_or_data);
+			register_state.interrupt_control =
+				(register_state.interrupt_control & 0xFF00) | u16::from(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::PowerControl)) => {
+			defmt::debug!("Reading power control");
+			data[0] = u8::from(HOST_POWERED.load(Ordering::Relaxed));
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::PowerControl)) => {
+			defmt::debug!("Writing power control ({})", req.length_or_data);
+			if req.length_or_data & power_control::DC_ON == 0 {
+				register_state.host_request = Some(HostRequest::PowerOff);
+			} else if req.length_or_data & power_control::RESET != 0 {
+				register_state.host_request = Some(HostRequest::Reset);
+			}
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::PowerPolicy)) => {
+			defmt::debug!("Reading power policy");
+			data[0] = register_state.power_policy.bits();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::PowerPolicy)) => {
+			defmt::debug!("Writing power policy ({})", req.length_or_data);
+			register_state.power_policy.set_bits(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::WakeKey)) => {
+			defmt::debug!("Reading wake key");
+			data[0] = register_state.power_policy.wake_key();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::WakeKey)) => {
+			defmt::debug!("Writing wake key ({})", req.length_or_data);
+			register_state.power_policy.set_wake_key(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::Ps2KbShortcuts)) => {
+			defmt::debug!("Reading keyboard shortcuts");
+			data[0] = register_state.kb_shortcuts.bits();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::Ps2KbShortcuts)) => {
+			defmt::debug!("Writing keyboard shortcuts ({})", req.length_or_data);
+			register_state.kb_shortcuts = keyboard::ShortcutConfig::new(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
 		(proto::RequestType::Read, Ok(Command::SpeakerDuration)) => {
 			defmt::debug!("Reading speaker duration");
-			data[0] = (register_state.speaker.duration() / 10) as u8;
-			proto::Response::new_ok_with_data(&data)
+			let length = req.length_or_data as usize;
+			if length == 1 || length == 2 {
+				data[0..2].copy_from_slice(&register_state.speaker.duration().to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
 		}
 		(proto::RequestType::ShortWrite, Ok(Command::SpeakerDuration)) => {
-			defmt::debug!("Writing speaker duration ({})", req.length_or_data);
+			defmt::debug!("Writing speaker duration ({} ms)", req.length_or_data);
+			// A single note replaces any tune
+			register_state.speaker.clear_queue();
 			// This update actually causes the speaker to beep
 			register_state
 				.speaker
-				.set_duration(req.length_or_data as u16 * 10);
+				.set_duration(u16::from(req.length_or_data));
 			proto::Response::new_without_data(proto::ResponseResult::Ok)
 		}
-		(proto::RequestType::Read, Ok(Command::SpeakerPeriodHigh)) => {
-			defmt::debug!("Reading speaker period (high)");
-			data[0] = register_state.speaker.period_high();
-			proto::Response::new_ok_with_data(&data)
-		}
-		(proto::RequestType::ShortWrite, Ok(Command::SpeakerPeriodHigh)) => {
-			defmt::debug!("Writing speaker period (high = {})", req.length_or_data);
-			register_state.speaker.set_period_low(req.length_or_data);
-			proto::Response::new_without_data(proto::ResponseResult::Ok)
-		}
-		(proto::RequestType::Read, Ok(Command::SpeakerPeriodLow)) => {
-			defmt::debug!("Reading speaker period (low)");
-			data[0] = register_state.speaker.period_low();
-			proto::Response::new_ok_with_data(&data)
-		}
-		(proto::RequestType::ShortWrite, Ok(Command::SpeakerPeriodLow)) => {
-			defmt::debug!("Writing speaker period (low = {})", req.length_or_data);
-			register_state.speaker.set_period_high(req.length_or_data);
-			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		(proto::RequestType::Read, Ok(Command::SpeakerFrequency)) => {
+			defmt::debug!("Reading speaker frequency");
+			let length = req.length_or_data as usize;
+			if length == 2 {
+				data[0..2].copy_from_slice(&register_state.speaker.frequency_hz().to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
 		}
 		(proto::RequestType::Read, Ok(Command::SpeakerDutyCycle)) => {
 			defmt::debug!("Reading speaker duty cycle");
 			data[0] = register_state.speaker.duty_cycle();
-			proto::Response::new_ok_with_data(&data)
+			proto::Response::new_ok_with_data(&data[0..1])
 		}
 		(proto::RequestType::ShortWrite, Ok(Command::SpeakerDutyCycle)) => {
 			defmt::debug!("Writing speaker duty cycle ({})", req.length_or_data);
 			register_state.speaker.set_duty_cycle(req.length_or_data);
 			proto::Response::new_without_data(proto::ResponseResult::Ok)
 		}
+		(proto::RequestType::Read, Ok(Command::SpeakerStatus)) => {
+			defmt::debug!("Reading speaker status");
+			data[0] = register_state.speaker.status();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::Read, Ok(Command::RtcDateTime)) => {
+			defmt::debug!("Reading RTC date/time");
+			let length = req.length_or_data as usize;
+			if length == rtc::DateTime::LENGTH {
+				data[0..length].copy_from_slice(&register_state.rtc.now.as_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::RtcAlarm)) => {
+			defmt::debug!("Reading RTC alarm");
+			let length = req.length_or_data as usize;
+			if length == rtc::DateTime::LENGTH {
+				data[0..length].copy_from_slice(&register_state.rtc.alarm.as_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::RtcAlarmControl)) => {
+			defmt::debug!("Reading RTC alarm control");
+			data[0] = register_state.rtc.alarm_action as u8;
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::RtcAlarmControl)) => {
+			defmt::debug!("Writing RTC alarm control ({})", req.length_or_data);
+			register_state
+				.rtc
+				.set_alarm_action(rtc::AlarmAction::from_bits(req.length_or_data));
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostResetReason)) => {
+			defmt::debug!("Reading host reset reason");
+			let length = usize::from(req.length_or_data);
+			if length > 0 && length <= reset::HostResetReason::LENGTH {
+				data[0..reset::HostResetReason::LENGTH]
+					.copy_from_slice(&register_state.host_reset_reason.as_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::BmcResetReason)) => {
+			defmt::debug!("Reading BMC reset reason");
+			data[0] = register_state.bmc_reset_reason;
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::Read, Ok(Command::BmcCrashRecord)) => {
+			defmt::debug!("Reading BMC crash record");
+			let length = req.length_or_data as usize;
+			if length == crash::CrashRecord::LENGTH {
+				data[0..length].copy_from_slice(&register_state.crash_record.as_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::ConfigControl)) => {
+			defmt::debug!("Reading config status");
+			data[0] = if register_state.config_pending.is_some() {
+				config::Status::Busy as u8
+			} else {
+				register_state.config_status as u8
+			};
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ConfigControl)) => {
+			defmt::debug!("Writing config control ({})", req.length_or_data);
+			match config::Operation::from_bits(req.length_or_data) {
+				Some(op) => {
+					register_state.config_pending = Some(op);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::ConfigVersion)) => {
+			defmt::debug!("Reading config version");
+			let length = req.length_or_data as usize;
+			if length == 2 {
+				data[0..2].copy_from_slice(&register_state.config_version.to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::FirmwareUpdateControl)) => {
+			defmt::debug!("Reading firmware update status");
+			data[0] = register_state.update.status() as u8;
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::FirmwareUpdateControl)) => {
+			defmt::debug!("Writing firmware update control ({})", req.length_or_data);
+			match update::Operation::from_bits(req.length_or_data) {
+				Some(op) => {
+					register_state.update.request(op);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::FirmwareUpdateProgress)) => {
+			defmt::debug!("Reading firmware update progress");
+			let length = req.length_or_data as usize;
+			if length == 4 {
+				data[0..4].copy_from_slice(&register_state.update.received().to_le_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::RomBootloaderEntry)) => {
+			defmt::debug!("Writing ROM bootloader key ({})", req.length_or_data);
+			match register_state.rom_boot_key.push(req.length_or_data) {
+				rom_boot::Key::Wrong => {
+					proto::Response::new_without_data(proto::ResponseResult::BadLength)
+				}
+				rom_boot::Key::More => proto::Response::new_without_data(proto::ResponseResult::Ok),
+				rom_boot::Key::Complete => {
+					register_state.rom_boot_requested = true;
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::ErrorCounters)) => {
+			defmt::debug!("Reading error counters");
+			let length = req.length_or_data as usize;
+			if length == diag::ErrorCounters::LEN {
+				data[0..length].copy_from_slice(&register_state.diag.errors.as_bytes());
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ErrorCounters)) => {
+			defmt::debug!("Clearing error counters");
+			register_state.errors_clear_requested = true;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::Statistics)) => {
+			defmt::debug!("Reading statistics");
+			let length = req.length_or_data as usize;
+			if length == diag::Statistics::LEN {
+				data[0..length].copy_from_slice(
+					&register_state
+						.diag
+						.stats
+						.as_bytes(register_state.uptime_secs),
+				);
+				proto::Response::new_ok_with_data(&data[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::Statistics)) => {
+			defmt::debug!("Clearing statistics");
+			register_state.stats_clear_requested = true;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostWatchdogTimeout)) => {
+			defmt::debug!("Reading host watchdog timeout");
+			data[0] = register_state.host_watchdog.timeout_secs();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostWatchdogTimeout)) => {
+			defmt::debug!("Writing host watchdog timeout ({})", req.length_or_data);
+			register_state
+				.host_watchdog
+				.set_timeout_secs(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostWatchdogControl)) => {
+			defmt::debug!("Reading host watchdog control");
+			data[0] = register_state.host_watchdog.action() as u8;
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostWatchdogControl)) => {
+			defmt::debug!("Writing host watchdog control ({})", req.length_or_data);
+			match host_watchdog::Action::from_bits(req.length_or_data) {
+				Some(action) => {
+					register_state.host_watchdog.set_action(action);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostWatchdogKick)) => {
+			defmt::trace!("Host watchdog kicked");
+			register_state.host_watchdog.kick();
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostWatchdogResetCount)) => {
+			defmt::debug!("Reading host watchdog reset count");
+			data[0] = register_state.host_watchdog.reset_count();
+			proto::Response::new_ok_with_data(&data[0..1])
+		}
+		(proto::RequestType::LongWrite, Ok(command)) => {
+			let length = req.length_or_data as usize;
+			match long_write_lengths(command) {
+				Some(lengths) if lengths.contains(&length) => {
+					defmt::debug!("Long write of {} bytes to {:?}", length, req.register);
+					// The payload comes next
+					register_state.long_write = Some(req);
+					proto::Response::new_without_data(proto::ResponseResult::Ok)
+				}
+				Some(_) => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+				None => {
+					defmt::warn!("Long write not supported on 0x{:02x}", req.register);
+					proto::Response::new_without_data(proto::ResponseResult::BadRegister)
+				}
+			}
+		}
 		_ => {
 			// Sorry, that register / request type is not supported
 			defmt::warn!(
@@ -925,8 +2571,135 @@ where
 			proto::Response::new_without_data(proto::ResponseResult::BadRegister)
 		}
 	};
-	rsp_handler(&rsp);
+	if let Some(req) = short_write {
+		register_state.last_write = Some(LastWrite {
+			req,
+			payload_crc: None,
+			result: rsp.result,
+		});
+	}
+	// If we accepted a Long Write, the payload (and its CRC) comes next
+	let payload_len = register_state
+		.long_write
+		.as_ref()
+		.map(|req| req.length_or_data as usize + 1);
+	rsp_handler(&rsp, payload_len);
 	// defmt::debug!("Sent {:?}", rsp);
 }
 
+/// Which registers take a *Long Write*, and how many bytes they take.
+///
+/// The payload has to fit in the SPI driver's receive buffer, along with its
+/// CRC.
+fn long_write_lengths(command: Command) -> Option<core::ops::RangeInclusive<usize>> {
+	match command {
+		Command::InterruptStatus | Command::InterruptControl => Some(2..=2),
+		Command::SpeakerDuration | Command::SpeakerFrequency => Some(2..=2),
+		Command::RtcDateTime | Command::RtcAlarm => {
+			Some(rtc::DateTime::LENGTH..=rtc::DateTime::LENGTH)
+		}
+		Command::FirmwareUpdateData => Some(1..=update::MAX_CHUNK),
+		Command::UartBuffer => Some(1..=uart::FIFO_LEN),
+		Command::UartBaudRate => Some(4..=4),
+		Command::I2cBuffer => Some(1..=i2c::FIFO_LEN),
+		Command::I2cControl => Some(i2c::Transfer::LENGTH..=i2c::Transfer::LENGTH),
+		Command::I2cBaudRate => Some(4..=4),
+		Command::SpeakerNoteQueue => {
+			Some(speaker::NOTE_LEN..=speaker::NOTE_LEN * speaker::MAX_WRITE_NOTES)
+		}
+		_ => None,
+	}
+}
+
+/// Process the payload of a *Long Write*, once its CRC has been checked.
+fn process_long_write(
+	req: &proto::Request,
+	payload: &[u8],
+	register_state: &mut RegisterState,
+) -> proto::ResponseResult {
+	match Command::try_from(req.register) {
+		Ok(Command::InterruptStatus) => {
+			let bits = u16::from_le_bytes([payload[0], payload[1]]);
+			defmt::debug!("Clearing interrupts ({})", bits);
+			register_state.interrupts_latched &= !bits;
+			proto::ResponseResult::Ok
+		}
+		Ok(Command::InterruptControl) => {
+			let bits = u16::from_le_bytes([payload[0], payload[1]]);
+			defmt::debug!("Writing interrupt control ({})", bits);
+			register_state.interrupt_control = bits;
+			proto::ResponseResult::Ok
+		}
+		Ok(Command::RtcDateTime) => match rtc::DateTime::from_bytes(payload) {
+			Some(time) => {
+				register_state.rtc.set_time(time);
+				proto::ResponseResult::Ok
+			}
+			None => proto::ResponseResult::BadLength,
+		},
+		Ok(Command::RtcAlarm) => match rtc::DateTime::from_bytes(payload) {
+			Some(time) => {
+				register_state.rtc.set_alarm(time);
+				proto::ResponseResult::Ok
+			}
+			None => proto::ResponseResult::BadLength,
+		},
+		Ok(Command::FirmwareUpdateData) => match register_state.update.write(payload) {
+			Ok(()) => proto::ResponseResult::Ok,
+			Err(_) => proto::ResponseResult::BadLength,
+		},
+		Ok(Command::SpeakerDuration) => {
+			let duration = u16::from_le_bytes([payload[0], payload[1]]);
+			defmt::debug!("Writing speaker duration ({} ms)", duration);
+			register_state.speaker.clear_queue();
+			register_state.speaker.set_duration(duration);
+			proto::ResponseResult::Ok
+		}
+		Ok(Command::SpeakerFrequency) => {
+			let frequency_hz = u16::from_le_bytes([payload[0], payload[1]]);
+			defmt::debug!("Writing speaker frequency ({} Hz)", frequency_hz);
+			match register_state.speaker.set_frequency_hz(frequency_hz) {
+				Ok(()) => proto::ResponseResult::Ok,
+				Err(_) => proto::ResponseResult::BadLength,
+			}
+		}
+		Ok(Command::SpeakerNoteQueue) => match register_state.speaker.queue_notes(payload) {
+			Ok(()) => proto::ResponseResult::Ok,
+			Err(_) => proto::ResponseResult::BadLength,
+		},
+		Ok(Command::UartBuffer) => match register_state.uart.queue(payload) {
+			Ok(()) => proto::ResponseResult::Ok,
+			Err(_) => proto::ResponseResult::BadLength,
+		},
+		Ok(Command::UartBaudRate) => {
+			let baud_rate = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+			match register_state.uart.set_baud_rate(baud_rate) {
+				Ok(()) => proto::ResponseResult::Ok,
+				Err(_) => proto::ResponseResult::BadLength,
+			}
+		}
+		Ok(Command::I2cBuffer) => match register_state.i2c.queue(payload) {
+			Ok(()) => proto::ResponseResult::Ok,
+			Err(_) => proto::ResponseResult::BadLength,
+		},
+		Ok(Command::I2cControl) => {
+			let started = i2c::Transfer::from_bytes(payload)
+				.ok_or(i2c::Error::Invalid)
+				.and_then(|transfer| register_state.i2c.start(transfer));
+			match started {
+				Ok(()) => proto::ResponseResult::Ok,
+				Err(_) => proto::ResponseResult::BadLength,
+			}
+		}
+		Ok(Command::I2cBaudRate) => {
+			let baud_rate = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+			match register_state.i2c.set_baud_rate(baud_rate) {
+				Ok(()) => proto::ResponseResult::Ok,
+				Err(_) => proto::ResponseResult::BadLength,
+			}
+		}
+		_ => proto::ResponseResult::BadRegister,
+	}
+}
+
 // End of file
@@ -0,0 +1,133 @@
+//! # Power Policy
+//!
+//! Settings which control when the BMC turns the main DC power on and off by
+//! itself, as stored in the *Power Policy* register.
+
+/// When should a keypress on the PS/2 keyboard port turn the system on?
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum WakeOnKeyboard {
+	/// The keyboard cannot turn the system on.
+	Disabled,
+	/// Any key turns the system on.
+	AnyKey,
+	/// Only the key set in the *Wake Key* register turns the system on.
+	SpecificKey,
+}
+
+/// What should happen when the PSU is plugged in (and the BMC starts)?
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum AcRestore {
+	/// The system stays off until it is turned on.
+	StayOff,
+	/// The system turns on.
+	AlwaysOn,
+	/// The system goes back to how it was when the power was lost.
+	LastState,
+}
+
+/// The contents of the *Power Policy* and *Wake Key* registers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PowerPolicy {
+	/// The raw *Power Policy* register
+	bits: u8,
+	/// The Scan Code Set 2 make code that wakes the system
+	wake_key: u8,
+}
+
+impl PowerPolicy {
+	/// Bits 1..0 are the wake-on-keyboard mode
+	const WAKE_MASK: u8 = 0b0000_0011;
+	const WAKE_ANY_KEY: u8 = 0b01;
+	const WAKE_SPECIFIC_KEY: u8 = 0b10;
+	/// Bits 3..2 are the AC power-loss restore mode
+	const AC_RESTORE_MASK: u8 = 0b0000_1100;
+	const AC_RESTORE_ALWAYS_ON: u8 = 0b0100;
+	const AC_RESTORE_LAST_STATE: u8 = 0b1000;
+	/// All the bits that mean something
+	const ALL: u8 = Self::WAKE_MASK | Self::AC_RESTORE_MASK;
+
+	/// Create a default policy, where the keyboard cannot wake the system.
+	pub const fn new() -> PowerPolicy {
+		PowerPolicy {
+			bits: 0,
+			// The Space Bar
+			wake_key: 0x29,
+		}
+	}
+
+	/// Get the *Power Policy* register value
+	pub const fn bits(&self) -> u8 {
+		self.bits
+	}
+
+	/// Set the *Power Policy* register value.
+	///
+	/// Unknown bits and invalid modes are ignored.
+	pub fn set_bits(&mut self, bits: u8) {
+		let mut bits = bits & Self::ALL;
+		if (bits & Self::WAKE_MASK) == Self::WAKE_MASK {
+			// Not a valid mode
+			bits &= !Self::WAKE_MASK;
+			bits |= self.bits & Self::WAKE_MASK;
+		}
+		if (bits & Self::AC_RESTORE_MASK) == Self::AC_RESTORE_MASK {
+			// Not a valid mode
+			bits &= !Self::AC_RESTORE_MASK;
+			bits |= self.bits & Self::AC_RESTORE_MASK;
+		}
+		self.bits = bits;
+	}
+
+	/// Get the wake-on-keyboard mode
+	pub const fn wake_on_keyboard(&self) -> WakeOnKeyboard {
+		match self.bits & Self::WAKE_MASK {
+			Self::WAKE_ANY_KEY => WakeOnKeyboard::AnyKey,
+			Self::WAKE_SPECIFIC_KEY => WakeOnKeyboard::SpecificKey,
+			_ => WakeOnKeyboard::Disabled,
+		}
+	}
+
+	/// Get the AC power-loss restore mode
+	pub const fn ac_restore(&self) -> AcRestore {
+		match self.bits & Self::AC_RESTORE_MASK {
+			Self::AC_RESTORE_ALWAYS_ON => AcRestore::AlwaysOn,
+			Self::AC_RESTORE_LAST_STATE => AcRestore::LastState,
+			_ => AcRestore::StayOff,
+		}
+	}
+
+	/// Should the system be turned on when the BMC starts, given whether it
+	/// was on when the power was lost?
+	pub const fn power_on_at_start(&self, was_on: bool) -> bool {
+		match self.ac_restore() {
+			AcRestore::StayOff => false,
+			AcRestore::AlwaysOn => true,
+			AcRestore::LastState => was_on,
+		}
+	}
+
+	/// Get the *Wake Key* register value
+	pub const fn wake_key(&self) -> u8 {
+		self.wake_key
+	}
+
+	/// Set the *Wake Key* register value
+	pub fn set_wake_key(&mut self, wake_key: u8) {
+		self.wake_key = wake_key;
+	}
+
+	/// Should this make code from the keyboard turn the system on?
+	pub fn is_wake_key(&self, make_code: u8) -> bool {
+		match self.wake_on_keyboard() {
+			WakeOnKeyboard::Disabled => false,
+			WakeOnKeyboard::AnyKey => true,
+			WakeOnKeyboard::SpecificKey => make_code == self.wake_key,
+		}
+	}
+}
+
+impl Default for PowerPolicy {
+	fn default() -> Self {
+		PowerPolicy::new()
+	}
+}
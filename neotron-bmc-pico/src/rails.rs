@@ -0,0 +1,59 @@
+//! # Power Rail Monitor
+//!
+//! The 3.3V and 5.0V rails are fed to PA0 and PA1 through resistor dividers,
+//! which give 1.65V when the rail is at its nominal voltage.
+
+use stm32f0xx_hal::{
+	adc::Adc,
+	gpio::gpioa::{PA0, PA1},
+	gpio::Analog,
+	pac, rcc,
+};
+
+/// The voltage on a monitor pin when its rail is at the nominal voltage
+const NOMINAL_PIN_MV: u32 = 1650;
+
+/// The power rail voltages
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Voltages {
+	/// The 3.3V rail, in millivolts
+	pub rail_3v3_mv: u16,
+	/// The 5.0V rail, in millivolts
+	pub rail_5v0_mv: u16,
+}
+
+/// Reads the rail monitor pins with the ADC
+pub struct Monitor {
+	adc: Adc,
+	pin_3v3: PA0<Analog>,
+	pin_5v0: PA1<Analog>,
+}
+
+impl Monitor {
+	pub fn new(adc: pac::ADC, pins: (PA0<Analog>, PA1<Analog>), rcc: &mut rcc::Rcc) -> Monitor {
+		Monitor {
+			adc: Adc::new(adc, rcc),
+			pin_3v3: pins.0,
+			pin_5v0: pins.1,
+		}
+	}
+
+	/// Measure both rails.
+	///
+	/// This takes a few microseconds, so we only do it when asked.
+	pub fn read(&mut self) -> Voltages {
+		// `read_abs_mv` corrects for VDDA using the factory calibrated
+		// internal reference.
+		let pin_3v3_mv = u32::from(self.adc.read_abs_mv(&mut self.pin_3v3));
+		let pin_5v0_mv = u32::from(self.adc.read_abs_mv(&mut self.pin_5v0));
+		Voltages {
+			rail_3v3_mv: scale(pin_3v3_mv, 3300),
+			rail_5v0_mv: scale(pin_5v0_mv, 5000),
+		}
+	}
+}
+
+/// Convert a monitor pin voltage to the voltage on a rail.
+fn scale(pin_mv: u32, nominal_mv: u32) -> u16 {
+	(pin_mv * nominal_mv / NOMINAL_PIN_MV) as u16
+}
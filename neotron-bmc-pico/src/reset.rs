@@ -0,0 +1,117 @@
+//! # Reset Reasons
+//!
+//! Records why the host was last powered on, powered off or reset, and why
+//! the BMC itself last started, so the host can find out after it boots.
+
+use stm32f0xx_hal::pac::RCC;
+
+/// Why the host's power state last changed, as stored in the *Host Reset
+/// Reason* register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum HostReason {
+	/// The BMC has (re-)started and turned the host off.
+	BmcStart = 0,
+	/// The power button was pressed (or held down).
+	PowerButton = 1,
+	/// The reset button was pressed.
+	ResetButton = 2,
+	/// A keyboard shortcut (e.g. Ctrl-Alt-Del) was pressed.
+	KeyboardShortcut = 3,
+	/// A key was pressed whilst the system was off, with wake-on-keyboard
+	/// enabled.
+	WakeOnKeyboard = 4,
+	/// The RTC alarm went off.
+	RtcAlarm = 5,
+	/// The host watchdog expired.
+	HostWatchdog = 6,
+	/// The host asked for it, with the *Power Control* register.
+	HostRequest = 7,
+	/// The BMC started and restored the power, as set in the *Power Policy*.
+	AcRestore = 8,
+	/// A command was typed into the BMC shell on the UART console.
+	Console = 9,
+}
+
+/// The *Host Reset Reason* register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HostResetReason {
+	/// Why the host was last powered on or reset
+	pub last_start: HostReason,
+	/// Why the host was last powered off
+	pub last_stop: HostReason,
+}
+
+impl HostResetReason {
+	/// The length of the register, in bytes
+	pub const LENGTH: usize = 2;
+
+	/// Nothing has happened since the BMC started.
+	pub const fn new() -> HostResetReason {
+		HostResetReason {
+			last_start: HostReason::BmcStart,
+			last_stop: HostReason::BmcStart,
+		}
+	}
+
+	/// Get the register contents.
+	pub fn as_bytes(&self) -> [u8; Self::LENGTH] {
+		[self.last_start as u8, self.last_stop as u8]
+	}
+}
+
+impl Default for HostResetReason {
+	fn default() -> Self {
+		HostResetReason::new()
+	}
+}
+
+/// Why the BMC itself last reset, as stored in the *BMC Reset Reason*
+/// register. More than one bit may be set.
+pub mod bmc {
+	/// The NRST pin was pulled low
+	pub const PIN: u8 = 1 << 0;
+	/// Power-on or brown-out reset
+	pub const POWER_ON: u8 = 1 << 1;
+	/// The firmware asked for a reset
+	pub const SOFTWARE: u8 = 1 << 2;
+	/// The Independent Watchdog expired
+	pub const INDEPENDENT_WATCHDOG: u8 = 1 << 3;
+	/// The Window Watchdog expired
+	pub const WINDOW_WATCHDOG: u8 = 1 << 4;
+	/// An illegal Stop or Standby mode entry
+	pub const LOW_POWER: u8 = 1 << 5;
+	/// The option bytes were reloaded
+	pub const OPTION_BYTE_LOAD: u8 = 1 << 6;
+}
+
+/// Read why the BMC last reset, from the RCC's Control/Status Register.
+///
+/// The flags are then cleared, so the next reset gets a clean slate. Call
+/// this before the RCC is configured.
+pub fn read_and_clear_bmc_reason(rcc: &RCC) -> u8 {
+	let csr = rcc.csr.read();
+	let mut reason = 0;
+	if csr.pinrstf().bit_is_set() {
+		reason |= bmc::PIN;
+	}
+	if csr.porrstf().bit_is_set() {
+		reason |= bmc::POWER_ON;
+	}
+	if csr.sftrstf().bit_is_set() {
+		reason |= bmc::SOFTWARE;
+	}
+	if csr.iwdgrstf().bit_is_set() {
+		reason |= bmc::INDEPENDENT_WATCHDOG;
+	}
+	if csr.wwdgrstf().bit_is_set() {
+		reason |= bmc::WINDOW_WATCHDOG;
+	}
+	if csr.lpwrrstf().bit_is_set() {
+		reason |= bmc::LOW_POWER;
+	}
+	if csr.oblrstf().bit_is_set() {
+		reason |= bmc::OPTION_BYTE_LOAD;
+	}
+	rcc.csr.modify(|_r, w| w.rmvf().set_bit());
+	reason
+}
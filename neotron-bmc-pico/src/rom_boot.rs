@@ -0,0 +1,113 @@
+//! # ROM Bootloader Entry
+//!
+//! The STM32F030 has a bootloader in ROM (*System Memory*) which can reflash
+//! us over USART1, using a tool like `stm32flash`. Normally it only runs if
+//! the BOOT0 pin is high, which it isn't on a Neotron Pico, so the host can
+//! ask us to jump to it instead.
+//!
+//! The ROM bootloader wants the chip as it comes out of reset, so we leave a
+//! note in a RAM section which isn't zeroed at start-up, reset, and then
+//! jump to it first thing in `init`.
+
+use core::{mem::MaybeUninit, ptr::addr_of_mut};
+
+use stm32f0xx_hal::pac::{NVIC, RCC, SYSCFG};
+
+/// The bytes the host must write to the *ROM Bootloader Entry* register, in
+/// order.
+pub const KEY: [u8; 4] = *b"BOOT";
+
+/// Where the ROM bootloader lives on an STM32F030x6 (see ST's AN2606)
+const SYSTEM_MEMORY: usize = 0x1FFF_EC00;
+
+/// Marks a request to enter the ROM bootloader
+const MAGIC: u32 = 0xB007_10AD;
+
+/// Not initialised by the start-up code, so survives a reset.
+#[link_section = ".uninit.ROM_BOOT"]
+static mut REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// How a write to the *ROM Bootloader Entry* register went.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Key {
+	/// That wasn't the next byte of [`KEY`], so start again
+	Wrong,
+	/// So far so good
+	More,
+	/// The whole key has been written
+	Complete,
+}
+
+/// Checks the host writes [`KEY`] to the *ROM Bootloader Entry* register, a
+/// byte at a time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct KeySequence {
+	/// How many bytes of the key have been written
+	matched: usize,
+}
+
+impl KeySequence {
+	/// Make a new key sequence, with no bytes written.
+	pub const fn new() -> KeySequence {
+		KeySequence { matched: 0 }
+	}
+
+	/// The host wrote a byte.
+	pub fn push(&mut self, byte: u8) -> Key {
+		if byte != KEY[self.matched] {
+			self.matched = 0;
+			return Key::Wrong;
+		}
+		self.matched += 1;
+		if self.matched == KEY.len() {
+			self.matched = 0;
+			Key::Complete
+		} else {
+			Key::More
+		}
+	}
+}
+
+/// Reset, and start the ROM bootloader instead of this firmware.
+pub fn reboot() -> ! {
+	// Safety: nothing else writes this, and we're about to reset
+	unsafe {
+		addr_of_mut!(REQUEST).cast::<u32>().write_volatile(MAGIC);
+	}
+	cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// If [`reboot`] asked for it, jump to the ROM bootloader.
+///
+/// Call this first thing in `init`, before any peripherals are configured.
+/// It only returns if the ROM bootloader wasn't asked for.
+pub fn check_and_enter(rcc: &RCC, syscfg: &SYSCFG) {
+	// Safety: any bit pattern is a valid `u32`, and we only trust the magic
+	// number. We wipe it so that resetting out of the ROM bootloader
+	// brings us back here.
+	let request = unsafe {
+		let request = addr_of_mut!(REQUEST).cast::<u32>().read_volatile();
+		addr_of_mut!(REQUEST).cast::<u32>().write_volatile(0);
+		request
+	};
+	if request != MAGIC {
+		return;
+	}
+	defmt::info!("Entering ROM bootloader");
+	rcc.apb2enr.modify(|_r, w| w.syscfgen().set_bit());
+	// Safety: 0b01 is "System Flash memory mapped at 0x0000_0000", which is
+	// what the ROM bootloader expects to see.
+	syscfg
+		.cfgr1
+		.modify(|_r, w| unsafe { w.mem_mode().bits(0b01) });
+	// Safety: RTIC runs `init` with interrupts off, but the ROM bootloader
+	// expects them on. Turn off anything RTIC enabled in the NVIC first, so
+	// none of our handlers can run.
+	unsafe {
+		let nvic = &*NVIC::PTR;
+		nvic.icer[0].write(0xFFFF_FFFF);
+		nvic.icpr[0].write(0xFFFF_FFFF);
+		cortex_m::interrupt::enable();
+		cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32)
+	}
+}
@@ -0,0 +1,332 @@
+//! # Real-Time Clock
+//!
+//! Drives the STM32F030's RTC, so the Neotron has wall-clock time which
+//! survives the main DC power being turned off. The RTC runs from the 32.768
+//! kHz LSE crystal if one is fitted, otherwise from the internal LSI RC
+//! oscillator, which we calibrate against the 48 MHz system clock.
+//!
+//! There is also one alarm, which can turn the system on or off at a given
+//! time.
+
+pub use neotron_bmc_rtc::DateTime;
+use neotron_bmc_rtc::{prescalers, to_bcd};
+use stm32f0xx_hal::pac::{PWR, RCC, RTC, TIM14};
+
+/// What should happen when the alarm goes off?
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum AlarmAction {
+	/// The alarm is off.
+	Disabled = 0,
+	/// Turn the system on (if it is off).
+	PowerOn = 1,
+	/// Ask the host to shut down, with the RTC Alarm interrupt.
+	ShutdownRequest = 2,
+	/// Turn the system off (if it is on).
+	PowerOff = 3,
+}
+
+impl AlarmAction {
+	/// Convert from the *RTC Alarm Control* register.
+	pub fn from_bits(bits: u8) -> AlarmAction {
+		match bits & 0b11 {
+			1 => AlarmAction::PowerOn,
+			2 => AlarmAction::ShutdownRequest,
+			3 => AlarmAction::PowerOff,
+			_ => AlarmAction::Disabled,
+		}
+	}
+}
+
+/// The RTC registers, as accessible via SPI reads and writes.
+#[derive(Debug)]
+pub struct RegisterState {
+	/// The current time, as last read from the hardware
+	pub now: DateTime,
+	/// A new time to be loaded into the hardware
+	pub new_time: Option<DateTime>,
+	/// When the alarm goes off
+	pub alarm: DateTime,
+	/// What happens when the alarm goes off
+	pub alarm_action: AlarmAction,
+	/// Whether the alarm config is dirty (needs to be sent to the RTC)
+	pub needs_update: bool,
+}
+
+impl Default for RegisterState {
+	fn default() -> Self {
+		RegisterState {
+			now: DateTime::default(),
+			new_time: None,
+			alarm: DateTime::default(),
+			alarm_action: AlarmAction::Disabled,
+			needs_update: false,
+		}
+	}
+}
+
+impl RegisterState {
+	pub fn set_time(&mut self, time: DateTime) {
+		self.now = time;
+		self.new_time = Some(time);
+	}
+
+	pub fn set_alarm(&mut self, alarm: DateTime) {
+		self.alarm = alarm;
+		self.needs_update = true;
+	}
+
+	pub fn set_alarm_action(&mut self, alarm_action: AlarmAction) {
+		self.alarm_action = alarm_action;
+		self.needs_update = true;
+	}
+}
+
+/// Which oscillator is driving the RTC?
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum ClockSource {
+	/// The 32.768 kHz crystal
+	Lse,
+	/// The internal RC oscillator, with its measured frequency in Hz
+	Lsi(u32),
+}
+
+pub struct Hardware {
+	dev: RTC,
+	/// The alarm we've loaded into the hardware, if any
+	alarm: Option<DateTime>,
+}
+
+impl Hardware {
+	/// LSE value for RCC_BDCR.RTCSEL
+	const RTCSEL_LSE: u8 = 0b01;
+	/// LSI value for RCC_BDCR.RTCSEL
+	const RTCSEL_LSI: u8 = 0b10;
+	/// How many milliseconds we wait for the LSE crystal to start
+	const LSE_TIMEOUT_MS: u32 = 1000;
+	/// How many LSI cycles we time when calibrating (this is the largest
+	/// input capture prescaler)
+	const LSI_CAL_CYCLES: u32 = 8;
+
+	/// Start the RTC.
+	///
+	/// If the RTC was already running (because only the BMC was reset, not
+	/// the whole board) we leave the time and clock source alone. Uses TIM14
+	/// to calibrate the LSI, so call this before the speaker is set up.
+	pub fn new(dev: RTC, pwr: &PWR, tim14: &TIM14, sysclk: u32) -> Hardware {
+		let rcc = unsafe { &*RCC::ptr() };
+
+		// Allow writes to the backup domain (which holds the RTC)
+		rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+		pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+		let already_running = rcc.bdcr.read().rtcen().bit_is_set();
+		let source = if already_running {
+			if rcc.bdcr.read().rtcsel().bits() == Self::RTCSEL_LSI {
+				// The LSI is turned off by a system reset, so turn it back
+				// on. We keep the prescaler we calibrated last time.
+				Self::start_lsi(rcc);
+				None
+			} else {
+				Some(ClockSource::Lse)
+			}
+		} else if Self::start_lse(rcc) {
+			rcc.bdcr
+				.modify(|_, w| unsafe { w.rtcsel().bits(Self::RTCSEL_LSE).rtcen().set_bit() });
+			Some(ClockSource::Lse)
+		} else {
+			Self::start_lsi(rcc);
+			rcc.bdcr
+				.modify(|_, w| unsafe { w.rtcsel().bits(Self::RTCSEL_LSI).rtcen().set_bit() });
+			Some(ClockSource::Lsi(Self::measure_lsi(rcc, tim14, sysclk)))
+		};
+
+		let mut rtc = Hardware { dev, alarm: None };
+
+		if already_running {
+			defmt::info!("RTC already running");
+		} else {
+			let (prediv_a, prediv_s) = match source {
+				Some(ClockSource::Lsi(hz)) => prescalers(hz),
+				_ => (127, 255),
+			};
+			defmt::info!(
+				"RTC clock {:?}, prescalers {}/{}",
+				source,
+				prediv_a,
+				prediv_s
+			);
+			rtc.with_init_mode(|dev| {
+				dev.prer
+					.write(|w| unsafe { w.prediv_a().bits(prediv_a).prediv_s().bits(prediv_s) });
+				// 24 hour format
+				dev.cr.modify(|_, w| w.fmt().clear_bit());
+			});
+		}
+
+		// Any old alarm belonged to the previous run of the firmware.
+		rtc.write_protect(false);
+		rtc.dev
+			.cr
+			.modify(|_, w| w.alrae().clear_bit().alraie().clear_bit());
+		rtc.dev.isr.modify(|_, w| w.alraf().clear_bit());
+		rtc.write_protect(true);
+
+		rtc
+	}
+
+	/// Try and start the LSE crystal. Returns false if it won't start.
+	fn start_lse(rcc: &stm32f0xx_hal::pac::rcc::RegisterBlock) -> bool {
+		rcc.bdcr.modify(|_, w| w.lseon().set_bit());
+		for _ in 0..Self::LSE_TIMEOUT_MS {
+			if rcc.bdcr.read().lserdy().bit_is_set() {
+				return true;
+			}
+			cortex_m::asm::delay(48_000);
+		}
+		defmt::warn!("No LSE crystal - using LSI");
+		rcc.bdcr.modify(|_, w| w.lseon().clear_bit());
+		false
+	}
+
+	/// Start the internal low-speed RC oscillator.
+	fn start_lsi(rcc: &stm32f0xx_hal::pac::rcc::RegisterBlock) {
+		rcc.csr.modify(|_, w| w.lsion().set_bit());
+		while rcc.csr.read().lsirdy().bit_is_clear() {}
+	}
+
+	/// Measure the LSI frequency in Hz.
+	///
+	/// TIM14 can capture the RTC clock on channel 1, so we count system clock
+	/// ticks across a few LSI cycles.
+	fn measure_lsi(
+		rcc: &stm32f0xx_hal::pac::rcc::RegisterBlock,
+		tim14: &TIM14,
+		sysclk: u32,
+	) -> u32 {
+		rcc.apb1enr.modify(|_, w| w.tim14en().set_bit());
+		// Route the RTC clock to TIM14 channel 1
+		tim14.or.write(|w| unsafe { w.bits(0b01) });
+		// Channel 1 is an input, capturing every 8th edge
+		tim14
+			.ccmr1_input()
+			.write(|w| unsafe { w.cc1s().bits(0b01).ic1psc().bits(0b11) });
+		tim14.ccer.write(|w| w.cc1e().set_bit());
+		tim14.psc.write(|w| w.psc().bits(0));
+		tim14.arr.write(|w| unsafe { w.bits(0xFFFF) });
+		tim14.cr1.write(|w| w.cen().set_bit());
+
+		let capture = || {
+			tim14.sr.modify(|_, w| w.cc1if().clear_bit());
+			while tim14.sr.read().cc1if().bit_is_clear() {}
+			tim14.ccr1.read().bits()
+		};
+		// Throw the first one away - the timer may have started mid-cycle
+		let _ = capture();
+		let start = capture();
+		let end = capture();
+		let ticks = end.wrapping_sub(start) & 0xFFFF;
+
+		// Put TIM14 back as we found it
+		tim14.cr1.reset();
+		tim14.ccer.reset();
+		tim14.ccmr1_input().reset();
+		tim14.or.reset();
+
+		if ticks == 0 {
+			// Nominal value
+			40_000
+		} else {
+			(sysclk * Self::LSI_CAL_CYCLES) / ticks
+		}
+	}
+
+	/// Enable or disable the RTC write protection.
+	fn write_protect(&mut self, enabled: bool) {
+		if enabled {
+			self.dev.wpr.write(|w| unsafe { w.key().bits(0xFF) });
+		} else {
+			self.dev.wpr.write(|w| unsafe { w.key().bits(0xCA) });
+			self.dev.wpr.write(|w| unsafe { w.key().bits(0x53) });
+		}
+	}
+
+	/// Stop the calendar, run the given function, then re-start the calendar.
+	fn with_init_mode<F>(&mut self, f: F)
+	where
+		F: FnOnce(&RTC),
+	{
+		self.write_protect(false);
+		self.dev.isr.modify(|_, w| w.init().set_bit());
+		while self.dev.isr.read().initf().bit_is_clear() {}
+		f(&self.dev);
+		self.dev
+			.isr
+			.modify(|_, w| w.init().clear_bit().rsf().clear_bit());
+		self.write_protect(true);
+	}
+
+	/// Get the current date and time.
+	pub fn now(&self) -> DateTime {
+		if self.dev.isr.read().rsf().bit_is_clear() {
+			// Calendar shadow registers aren't synchronised yet
+			return DateTime::default();
+		}
+		// Reading TR locks DR until it is read, so read TR first.
+		let tr = self.dev.tr.read().bits();
+		let dr = self.dev.dr.read().bits();
+		DateTime::from_registers(tr, dr)
+	}
+
+	/// Set the current date and time.
+	pub fn set(&mut self, time: &DateTime) {
+		self.with_init_mode(|dev| {
+			dev.tr.write(|w| unsafe { w.bits(time.time_register()) });
+			dev.dr.write(|w| unsafe { w.bits(time.date_register()) });
+		});
+	}
+
+	/// Load the alarm, or disable it if the action is
+	/// [`AlarmAction::Disabled`].
+	pub fn update(&mut self, register: &RegisterState) {
+		self.write_protect(false);
+		self.dev.cr.modify(|_, w| w.alrae().clear_bit());
+		if register.alarm_action == AlarmAction::Disabled {
+			self.alarm = None;
+		} else {
+			while self.dev.isr.read().alrawf().bit_is_clear() {}
+			// Match on day-of-month, hours, minutes and seconds. We check the
+			// month and year ourselves when it fires.
+			let alarm = register.alarm.time_register() | (to_bcd(register.alarm.day) << 24);
+			self.dev.alrmar.write(|w| unsafe { w.bits(alarm) });
+			self.dev.isr.modify(|_, w| w.alraf().clear_bit());
+			self.dev.cr.modify(|_, w| w.alrae().set_bit());
+			self.alarm = Some(register.alarm);
+		}
+		self.write_protect(true);
+	}
+
+	/// Has the alarm gone off?
+	///
+	/// The alarm is one-shot, so this only returns `true` once.
+	pub fn alarm_fired(&mut self) -> bool {
+		if self.dev.isr.read().alraf().bit_is_clear() {
+			return false;
+		}
+		self.write_protect(false);
+		self.dev.isr.modify(|_, w| w.alraf().clear_bit());
+		let now = self.now();
+		let fired = match self.alarm {
+			Some(alarm) if alarm.year == now.year && alarm.month == now.month => {
+				self.dev.cr.modify(|_, w| w.alrae().clear_bit());
+				self.alarm = None;
+				true
+			}
+			_ => {
+				// Right day, wrong month (or year). Keep waiting.
+				false
+			}
+		};
+		self.write_protect(true);
+		fired
+	}
+}
@@ -1,18 +1,66 @@
 use stm32f0xx_hal::{
 	pac::{RCC, TIM14},
-	rcc::Rcc,
+	rcc::{Clocks, Rcc},
 };
 
+pub use neotron_bmc_commands::speaker::{
+	is_valid_frequency, status, Pwm, MAX_WRITE_NOTES, NOTE_LEN, QUEUE_LEN,
+};
+
+/// Why notes couldn't be queued
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The queue doesn't have room
+	Full,
+	/// The notes don't make sense
+	Invalid,
+}
+
+/// A note waiting in the queue
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub struct Note {
+	/// The frequency, in Hz (0 = silent)
+	pub frequency_hz: u16,
+	/// The duty cycle (0 - 255)
+	pub duty_cycle: u8,
+	/// How long to play it for, in ms
+	pub duration: u16,
+}
+
+impl Note {
+	/// Decode a note written to the *Speaker Note Queue* register.
+	///
+	/// Notes with no duration are refused, as they'd never finish, as are
+	/// frequencies we can't play.
+	pub fn from_bytes(bytes: &[u8]) -> Option<Note> {
+		if bytes.len() != NOTE_LEN {
+			return None;
+		}
+		let note = Note {
+			frequency_hz: u16::from_le_bytes([bytes[0], bytes[1]]),
+			duty_cycle: bytes[2],
+			duration: u16::from_le_bytes([bytes[3], bytes[4]]),
+		};
+		if note.duration == 0 || !is_valid_frequency(note.frequency_hz) {
+			None
+		} else {
+			Some(note)
+		}
+	}
+}
+
 #[derive(Debug, Default)]
 pub struct RegisterState {
-	/// The duration of the current note (0 = off)
+	/// The duration of the current note, in ms (0 = off)
 	pub duration: u16,
-	/// The PWM period (in 48kHz ticks)
-	pub period: u16,
+	/// The frequency, in Hz (0 = silent)
+	pub frequency_hz: u16,
 	/// The duty cycle (0 - 255)
 	pub duty_cycle: u8,
 	/// Whether the speaker config is dirty (needs to be sent to the PWM device)
 	pub needs_update: bool,
+	/// Notes to play after this one
+	pub queue: heapless::Deque<Note, QUEUE_LEN>,
 }
 
 impl RegisterState {
@@ -24,28 +72,17 @@ impl RegisterState {
 		self.duty_cycle = duty_cycle;
 	}
 
-	pub fn period(&self) -> u16 {
-		self.period
-	}
-
-	pub fn set_period(&mut self, period: u16) {
-		self.period = period;
-	}
-
-	pub fn period_high(&self) -> u8 {
-		(self.period >> 8) as u8
-	}
-
-	pub fn set_period_high(&mut self, period_high: u8) {
-		self.period = (self.period & 0xff00) | period_high as u16;
-	}
-
-	pub fn period_low(&self) -> u8 {
-		(self.period & 0xff) as u8
+	pub fn frequency_hz(&self) -> u16 {
+		self.frequency_hz
 	}
 
-	pub fn set_period_low(&mut self, period_low: u8) {
-		self.period = (self.period() & 0xff) | ((period_low as u16) << 8);
+	/// Set the frequency, unless it's one we can't play.
+	pub fn set_frequency_hz(&mut self, frequency_hz: u16) -> Result<(), Error> {
+		if !is_valid_frequency(frequency_hz) {
+			return Err(Error::Invalid);
+		}
+		self.frequency_hz = frequency_hz;
+		Ok(())
 	}
 
 	pub fn duration(&self) -> u16 {
@@ -65,6 +102,62 @@ impl RegisterState {
 		self.needs_update = needs_update;
 	}
 
+	/// Add notes to the queue, as written to the *Speaker Note Queue*
+	/// register. If nothing is playing, the first one starts.
+	///
+	/// Either all the notes are queued, or none of them are.
+	pub fn queue_notes(&mut self, data: &[u8]) -> Result<(), Error> {
+		if data.len() % NOTE_LEN != 0 {
+			return Err(Error::Invalid);
+		}
+		if data
+			.chunks(NOTE_LEN)
+			.any(|bytes| Note::from_bytes(bytes).is_none())
+		{
+			return Err(Error::Invalid);
+		}
+		if self.queue.len() + data.len() / NOTE_LEN > QUEUE_LEN {
+			return Err(Error::Full);
+		}
+		for note in data.chunks(NOTE_LEN).filter_map(Note::from_bytes) {
+			let _ = self.queue.push_back(note);
+		}
+		if self.duration == 0 {
+			self.play_next();
+		}
+		Ok(())
+	}
+
+	/// Start the next note in the queue. Returns `false` if there isn't one.
+	pub fn play_next(&mut self) -> bool {
+		match self.queue.pop_front() {
+			Some(note) => {
+				self.frequency_hz = note.frequency_hz;
+				self.duty_cycle = note.duty_cycle;
+				self.set_duration(note.duration);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Throw away any queued notes.
+	pub fn clear_queue(&mut self) {
+		self.queue.clear();
+	}
+
+	/// Get the *Speaker Status* register value.
+	pub fn status(&self) -> u8 {
+		let mut bits = 0;
+		if self.queue.is_empty() {
+			bits |= status::QUEUE_EMPTY;
+		}
+		if self.duration != 0 {
+			bits |= status::PLAYING;
+		}
+		bits
+	}
+
 	pub fn setup(&self, _rcc: &mut Rcc, tim14: &TIM14) {
 		let rcc = RCC::ptr();
 		// enable and reset peripheral to a clean slate state
@@ -77,57 +170,74 @@ impl RegisterState {
 		tim14
 			.ccmr1_output()
 			.modify(|_, w| w.oc1pe().set_bit().oc1m().bits(6));
-
-		// prescale 1000 (48MHz -> 48 kHz)
-		tim14.psc.write(|w| w.psc().bits(1000));
 	}
 }
 
-pub struct Hardware(TIM14);
+pub struct Hardware {
+	tim14: TIM14,
+	/// The clock TIM14 counts, in Hz
+	timer_clock_hz: u32,
+}
 
 impl Hardware {
-	pub fn new(tim14: TIM14) -> Self {
-		Self(tim14)
+	pub fn new(tim14: TIM14, clocks: &Clocks) -> Self {
+		// The APB timers run at twice PCLK, unless PCLK is HCLK
+		let timer_clock_hz = if clocks.pclk().0 == clocks.hclk().0 {
+			clocks.pclk().0
+		} else {
+			clocks.pclk().0 * 2
+		};
+		Self {
+			tim14,
+			timer_clock_hz,
+		}
 	}
 
 	pub fn disable(&mut self) {
-		self.0.ccer.modify(|_, w| w.cc1e().clear_bit());
+		self.tim14.ccer.modify(|_, w| w.cc1e().clear_bit());
 	}
 
 	pub fn enable(&mut self) {
-		self.0.ccer.modify(|_, w| w.cc1e().set_bit());
+		self.tim14.ccer.modify(|_, w| w.cc1e().set_bit());
 	}
 
-	fn update_register(&self, config: &RegisterState) {
-		// period
-		self.0
-			.arr
-			.write(|w| unsafe { w.bits(config.period() as u32) });
+	/// Set up the PWM for a note. Returns `false` if it's silent.
+	fn update_register(&self, frequency_hz: u16, duty_cycle: u8) -> bool {
+		let pwm = match Pwm::new(self.timer_clock_hz, frequency_hz, duty_cycle) {
+			Some(pwm) => pwm,
+			None => return false,
+		};
 
-		// duty cycle (255 = whole period)
-		self.0.ccr1.write(|w| {
-			w.ccr()
-				.bits((config.period() * config.duty_cycle() as u16) / 255)
-		});
+		self.tim14.psc.write(|w| w.psc().bits(pwm.prescaler));
+		// period (the counter goes from 0 to ARR inclusive)
+		self.tim14
+			.arr
+			.write(|w| unsafe { w.bits(u32::from(pwm.period - 1)) });
+		// duty cycle (the output is high whilst the counter is below this)
+		self.tim14.ccr1.write(|w| w.ccr().bits(pwm.compare));
 
 		// enable auto-reload preload
-		self.0.cr1.modify(|_, w| w.arpe().set_bit());
+		self.tim14.cr1.modify(|_, w| w.arpe().set_bit());
 
 		// Trigger update event to load the registers
-		self.0.cr1.modify(|_, w| w.urs().set_bit());
-		self.0.egr.write(|w| w.ug().set_bit());
-		self.0.cr1.modify(|_, w| w.urs().clear_bit());
+		self.tim14.cr1.modify(|_, w| w.urs().set_bit());
+		self.tim14.egr.write(|w| w.ug().set_bit());
+		self.tim14.cr1.modify(|_, w| w.urs().clear_bit());
 
-		self.0.cr1.write(|w| w.cen().set_bit());
+		self.tim14.cr1.write(|w| w.cen().set_bit());
+		true
 	}
 
 	/// Update the status of the registers and PWM output. Return `true` if a note is to be played.
-	pub fn update<F: FnOnce()>(&mut self, register: &RegisterState, enable_cb: F) -> bool {
+	pub fn update(&mut self, register: &RegisterState) -> bool {
 		if register.duration > 0 {
-			// a note has to be played - enable PWM
-			self.update_register(register);
-			self.enable();
-			enable_cb();
+			// a note has to be played - enable PWM, unless it's a rest
+			if self.update_register(register.frequency_hz, register.duty_cycle) {
+				self.enable();
+			} else {
+				self.disable();
+			}
+			// a rest still has to end on time
 			true
 		} else {
 			// nothing to play (duration == 0), just disable PWM
@@ -136,13 +246,9 @@ impl Hardware {
 		}
 	}
 
-	pub fn set_note(&mut self, duration: u16, period: u16, duty_cycle: u8) {
-		let config = RegisterState {
-			duration,
-			period,
-			duty_cycle,
-			needs_update: true,
-		};
-		self.update_register(&config);
+	/// Set up the PWM for a note, which plays until [`Hardware::disable`]
+	/// is called.
+	pub fn set_note(&mut self, frequency_hz: u16, duty_cycle: u8) {
+		self.update_register(frequency_hz, duty_cycle);
 	}
 }
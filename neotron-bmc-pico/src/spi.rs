@@ -2,26 +2,178 @@
 //!
 //! Unlike the HAL, this implement 'SPI Peripheral Mode', i.e. for when the
 //! clock signal is an input and not an output.
+//!
+//! Bytes are moved between the SPI FIFOs and memory by DMA, so we only take
+//! an interrupt when a *Request* (or *Long Write Payload*) has arrived, or
+//! when a *Response* has been loaded. In between, the DMA clocks out padding
+//! and throws away whatever the host sends us.
+//!
+//! SPI is full-duplex, so the Nth byte we receive in a transaction was
+//! clocked in alongside the Nth byte we send. By counting how many padding
+//! bytes went out before a *Response*, we know exactly where the *Long Write
+//! Payload* which follows it will start.
+//!
+//! The EXTI interrupt calls [`SpiPeripheral::start`] and
+//! [`SpiPeripheral::stop`] on the chip select edges, so the engine is armed
+//! before the host's first clock edge. Each transaction gets a new number, so
+//! the main thread can tell if the one it's answering has been cancelled.
+
+use core::ptr::{addr_of, addr_of_mut};
 
 use stm32f0xx_hal::{pac, prelude::*, rcc::Rcc};
 
+/// The DMA channels wired to SPI1 (see RM0360, Table 26).
+///
+/// The PAC doesn't help us much here, so we use the registers directly.
+mod dma {
+	/// Base address of DMA1
+	const BASE: usize = 0x4002_0000;
+	/// DMA Interrupt Status Register
+	const ISR: *const u32 = BASE as *const u32;
+	/// DMA Interrupt Flag Clear Register
+	const IFCR: *mut u32 = (BASE + 0x04) as *mut u32;
+	/// Address of the SPI1 Data Register
+	const SPI1_DR: u32 = 0x4001_300C;
+
+	/// Channel enable
+	const EN: u32 = 1 << 0;
+	/// Transfer Complete interrupt enable
+	pub const TCIE: u32 = 1 << 1;
+	/// Read from memory (i.e. memory to peripheral)
+	pub const FROM_MEMORY: u32 = 1 << 4;
+	/// Memory increment mode
+	pub const MINC: u32 = 1 << 7;
+	/// Very High priority
+	const PL_VERY_HIGH: u32 = 0b11 << 12;
+
+	/// One of the seven DMA channels (numbered from 1)
+	pub struct Channel(usize);
+
+	/// Channel 2 takes bytes from SPI1
+	pub const RX: Channel = Channel(2);
+	/// Channel 3 gives bytes to SPI1
+	pub const TX: Channel = Channel(3);
+
+	impl Channel {
+		/// Get one of this channel's registers, by its offset from CCR.
+		fn reg(&self, offset: usize) -> *mut u32 {
+			(BASE + 0x08 + (20 * (self.0 - 1)) + offset) as *mut u32
+		}
+
+		/// Move `len` bytes between SPI1 and `mem`.
+		///
+		/// # Safety
+		///
+		/// `mem` must be valid for `len` bytes (or one byte, without `MINC`)
+		/// until the transfer completes or the channel is disabled.
+		pub unsafe fn start(&self, mem: *const u8, len: usize, flags: u32) {
+			self.disable();
+			self.clear_flags();
+			self.reg(0x04).write_volatile(len as u32);
+			self.reg(0x08).write_volatile(SPI1_DR);
+			self.reg(0x0C).write_volatile(mem as u32);
+			self.reg(0x00).write_volatile(flags | PL_VERY_HIGH | EN);
+		}
+
+		/// Stop the channel. Any transfer in progress is finished first.
+		pub fn disable(&self) {
+			// Safety: only this driver uses this channel
+			unsafe { self.reg(0x00).write_volatile(0) };
+		}
+
+		/// How many bytes are left to move.
+		pub fn remaining(&self) -> usize {
+			// Safety: reading the count is harmless
+			unsafe { self.reg(0x04).read_volatile() as usize }
+		}
+
+		/// Has the transfer completed?
+		pub fn is_complete(&self) -> bool {
+			let tcif = 1 << ((4 * (self.0 - 1)) + 1);
+			// Safety: reading the status is harmless
+			unsafe { (ISR.read_volatile() & tcif) != 0 }
+		}
+
+		/// Clear this channel's interrupt flags.
+		pub fn clear_flags(&self) {
+			// Safety: only this driver uses this channel
+			unsafe { IFCR.write_volatile(0xF << (4 * (self.0 - 1))) };
+		}
+	}
+}
+
+/// How many padding bytes one DMA transfer can send (or throw away). This is
+/// the longest the host can wait for a *Response*.
+pub const MAX_TURNAROUND: usize = 0xFFFF;
+
+/// What we send when we have nothing to say.
+///
+/// This is `mut` so that it's in RAM. The DMA can't read flash whilst the
+/// flash is being written, and we pad for longest then.
+static mut PADDING: u8 = 0xFF;
+
+/// Where we throw away bytes we don't want
+static mut SINK: u8 = 0;
+
+/// What the RX DMA is doing
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RxPhase {
+	/// Chip select is high
+	Idle,
+	/// Waiting for a *Request*
+	Request,
+	/// Throwing away bytes while we work on the *Response*
+	Turnaround,
+	/// Waiting for a *Long Write Payload*
+	Payload,
+}
+
+/// What has arrived from the host
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Received {
+	/// A *Request*
+	Request,
+	/// The *Long Write Payload* we said we wanted
+	Payload,
+}
+
+/// The ways the SPI driver can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// We were asked to receive or send more than fits in our buffers. Nothing
+	/// was done.
+	TooLarge,
+	/// The *Response* was sent, but the *Long Write Payload* which follows it
+	/// won't be received (e.g. because bytes were lost to an overrun).
+	PayloadLost,
+}
+
 pub struct SpiPeripheral<const RXC: usize, const TXC: usize> {
 	/// Our PAC object for register access
 	dev: pac::SPI1,
 	/// A space for bytes received from the host
 	rx_buffer: [u8; RXC],
-	/// How many bytes have been received?
-	rx_idx: usize,
+	/// What the RX DMA is doing
+	rx_phase: RxPhase,
+	/// How many bytes at the start of `rx_buffer` were received before the
+	/// payload started?
+	rx_skip: usize,
 	/// How many bytes do we want?
 	rx_want: usize,
+	/// How many bytes had been received when the current RX DMA transfer
+	/// started?
+	rx_base: usize,
+	/// How many bytes of Long Write Payload do we want, once our response has
+	/// been sent?
+	payload_want: usize,
 	/// A space for data we're about to send
 	tx_buffer: [u8; TXC],
-	/// How many bytes have been played from the TX buffer
-	tx_idx: usize,
-	/// How many bytes are loaded into the TX buffer
-	tx_ready: usize,
-	/// The in-progress RX CRC
-	rx_crc: neotron_bmc_protocol::CrcCalc,
+	/// How many bytes had been sent when the current padding started?
+	tx_base: usize,
+	/// What's waiting in `rx_buffer`, if anything
+	received: Option<Received>,
+	/// Counts chip select assertions
+	transaction: u32,
 }
 
 impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
@@ -47,15 +199,26 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		// Now disassemble the driver so we can set it into Peripheral mode instead
 		let (dev, _pins) = spi_controller.release();
 
+		// Turn on the DMA controller. This is OK as we have exclusive access
+		// to the RCC peripheral, and the HAL doesn't have a DMA driver.
+		let ahbenr = 0x4002_1014 as *mut u32;
+		let dma_bit = 1 << 0;
+		unsafe {
+			*ahbenr |= dma_bit;
+		}
+
 		let mut spi = SpiPeripheral {
 			dev,
 			rx_buffer: [0u8; RXC],
-			rx_idx: 0,
+			rx_phase: RxPhase::Idle,
+			rx_skip: 0,
 			rx_want: 0,
+			rx_base: 0,
+			payload_want: 0,
 			tx_buffer: [0u8; TXC],
-			tx_idx: 0,
-			tx_ready: 0,
-			rx_crc: neotron_bmc_protocol::CrcCalc::new(),
+			tx_base: 0,
+			received: None,
+			transaction: 0,
 		};
 
 		spi.config(Self::MODE);
@@ -124,11 +287,10 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			w.nssp().no_pulse();
 			// 3e. Configure the FIFO RX Threshold to 1/4 FIFO (8 bits)
 			w.frxth().quarter();
-			// 3f. Disable DMA mode
-			w.txdmaen().disabled();
-			w.rxdmaen().disabled();
-			// Extra: Turn on RX and Error interrupts, but not TX. We swap
-			// interrupts once the read phase is complete.
+			// 3f. Enable DMA mode
+			w.txdmaen().enabled();
+			w.rxdmaen().enabled();
+			// Extra: The DMA does all the work, so no SPI interrupts
 			w.rxneie().masked();
 			w.txeie().masked();
 			w.errie().masked();
@@ -137,47 +299,88 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 
 		// 4. SPI_CRCPR - not required
 
-		// 5. DMA registers - not required
+		// 5. DMA registers - set in `start`
 	}
 
 	/// Enable the SPI peripheral (i.e. when CS goes low).
 	///
 	/// We tell it how many bytes we are expecting, so it knows when to update
-	/// the main thread.
-	pub fn start(&mut self, num_bytes: usize) {
+	/// the main thread. If that's more than we have room for, the peripheral
+	/// is left off, and the host will time out.
+	pub fn start(&mut self, num_bytes: usize) -> Result<(), Error> {
+		self.transaction = self.transaction.wrapping_add(1);
 		if num_bytes > RXC {
-			panic!("Read too large");
+			self.stop();
+			return Err(Error::TooLarge);
 		}
-		self.rx_idx = 0;
-		self.rx_want = num_bytes as usize;
-		self.tx_idx = 0;
-		self.tx_ready = 0;
-		self.rx_crc.reset();
-		// Empty the receive register
+		self.stop_dma();
+		self.rx_skip = 0;
+		self.rx_want = num_bytes;
+		self.rx_base = num_bytes;
+		self.payload_want = 0;
+		self.tx_base = 0;
+		// Empty the receive register, and clear any overrun
 		while self.has_rx_data() {
 			let _ = self.raw_read();
 		}
-		// Turn on RX interrupt; turn off TX interrupt
-		self.dev.cr2.write(|w| {
-			w.rxneie().not_masked();
-			w.txeie().masked();
-			w
-		});
+		let _ = self.dev.sr.read();
+		// Safety: `rx_buffer` is ours, and big enough
+		unsafe {
+			dma::RX.start(
+				self.rx_buffer.as_mut_ptr(),
+				num_bytes,
+				dma::MINC | dma::TCIE,
+			);
+		}
+		self.rx_phase = RxPhase::Request;
+		// Fill the TX FIFO before the host starts clocking
+		self.start_padding();
 		// Tell the SPI engine it has a chip-select
 		self.dev.cr1.modify(|_r, w| {
 			w.ssi().slave_selected();
 			w.spe().enabled();
 			w
 		});
+		Ok(())
+	}
+
+	/// Expect a *Long Write Payload* of `num_bytes` (including the CRC byte)
+	/// to follow the next response we send.
+	///
+	/// Call this before [`Self::set_transmit_sendable`], whilst holding the
+	/// lock (so the DMA interrupt can't fire in between). If you get an
+	/// error, you should refuse the *Long Write*.
+	pub fn expect_payload(&mut self, num_bytes: usize) -> Result<(), Error> {
+		if num_bytes > RXC {
+			return Err(Error::TooLarge);
+		}
+		self.payload_want = num_bytes;
+		Ok(())
+	}
+
+	/// Which transaction are we in?
+	///
+	/// This changes every time [`Self::start`] is called.
+	pub fn transaction(&self) -> u32 {
+		self.transaction
+	}
+
+	/// Is `transaction` still going (i.e. chip select hasn't gone high since)?
+	pub fn is_current(&self, transaction: u32) -> bool {
+		self.rx_phase != RxPhase::Idle && self.transaction == transaction
 	}
 
 	/// Disable the SPI peripheral (i.e. when CS goes high)
+	///
+	/// Anything received but not yet collected is thrown away, as is any
+	/// *Long Write Payload* we were expecting.
 	pub fn stop(&mut self) {
 		self.dev.cr1.modify(|_r, w| {
 			w.ssi().slave_not_selected();
 			w.spe().disabled();
 			w
 		});
+		self.stop_dma();
 	}
 
 	/// Fully reset the SPI peripheral
@@ -190,6 +393,7 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			w.spe().disabled();
 			w
 		});
+		self.stop_dma();
 
 		// Reset the IP manually. This is OK as we have exclusive access to the
 		// RCC peripheral. But sadly the RCC peripheral doesn't let us reset
@@ -213,6 +417,36 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		self.stop();
 	}
 
+	/// Stop both DMA channels.
+	fn stop_dma(&mut self) {
+		dma::RX.disable();
+		dma::RX.clear_flags();
+		dma::TX.disable();
+		dma::TX.clear_flags();
+		self.rx_phase = RxPhase::Idle;
+		self.received = None;
+		self.payload_want = 0;
+	}
+
+	/// Send padding until we have a *Response*.
+	fn start_padding(&mut self) {
+		// Safety: `PADDING` is static and never written, and we don't
+		// increment the address
+		unsafe {
+			dma::TX.start(addr_of!(PADDING), MAX_TURNAROUND, dma::FROM_MEMORY);
+		}
+	}
+
+	/// Throw away whatever the host sends, until we want it.
+	fn start_drain(&mut self) {
+		// Safety: `SINK` is static, we don't increment the address, and
+		// nothing reads it
+		unsafe {
+			dma::RX.start(addr_of_mut!(SINK), MAX_TURNAROUND, 0);
+		}
+		self.rx_phase = RxPhase::Turnaround;
+	}
+
 	/// Does the RX FIFO have any data in it?
 	fn has_rx_data(&self) -> bool {
 		self.dev.sr.read().rxne().is_not_empty()
@@ -224,96 +458,119 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		unsafe { core::ptr::read_volatile(&self.dev.dr as *const _ as *const u8) }
 	}
 
-	fn raw_write(&mut self, data: u8) {
-		// PAC only supports 16-bit read, but that pushes two bytes onto the FIFO.
-		// So force an 8-bit write.
-		unsafe { core::ptr::write_volatile(&self.dev.dr as *const _ as *mut u8, data) }
-	}
-
-	/// Get a slice of data received so far.
-	pub fn get_received(&self) -> Option<(&[u8], u8)> {
-		Some(((&self.rx_buffer[0..self.rx_idx]), self.rx_crc.get()))
+	/// Collect whatever has arrived, and its CRC.
+	///
+	/// You only get each *Request* or *Long Write Payload* once, and nothing
+	/// at all if chip select went high before you asked.
+	pub fn get_received(&mut self) -> Option<(Received, &[u8], u8)> {
+		let received = self.received.take()?;
+		let data = &self.rx_buffer[self.rx_skip..self.rx_skip + self.rx_want];
+		Some((received, data, neotron_bmc_protocol::calculate_crc(data)))
 	}
 
-	/// Call this when the SPI peripheral interrupt fires.
+	/// Call this when the DMA interrupt for channels 2 and 3 fires.
 	///
-	/// It will handle incoming bytes and/or outgoing bytes, depending on what
-	/// phase we are in.
-	pub fn handle_isr(&mut self) -> bool {
+	/// Returns `true` if a *Request* or *Long Write Payload* has arrived.
+	pub fn handle_dma_isr(&mut self) -> bool {
 		let mut have_packet = false;
-		let irq_status = self.dev.sr.read();
-		if irq_status.rxne().is_not_empty() {
-			if self.rx_isr() {
-				// We've got enough, turn the RX interrupt off. Everything else
-				// we receive is going to be garbage.
-				self.dev.cr2.write(|w| {
-					w.rxneie().masked();
-					w
-				});
+		if dma::RX.is_complete() {
+			dma::RX.clear_flags();
+			let received = match self.rx_phase {
+				RxPhase::Request => Some(Received::Request),
+				RxPhase::Payload => Some(Received::Payload),
+				RxPhase::Idle | RxPhase::Turnaround => None,
+			};
+			if received.is_some() {
+				// We've got enough. Keep the RX FIFO empty, so we can count
+				// what arrives until the response goes out.
+				self.start_drain();
+				self.received = received;
 				have_packet = true;
 			}
 		}
-		if irq_status.txe().is_empty() {
-			self.tx_isr();
+		if dma::TX.is_complete() {
+			dma::TX.clear_flags();
+			// The response is all in the TX FIFO, so go back to padding
+			self.start_padding();
 		}
 		have_packet
 	}
 
-	/// Try and read from the SPI FIFO
-	fn rx_isr(&mut self) -> bool {
-		let byte = self.raw_read();
-		if self.rx_want == 0 {
-			panic!("unwanted data 0x{:02x}", byte);
-		}
-		if self.rx_idx < self.rx_buffer.len() {
-			self.rx_buffer[self.rx_idx] = byte;
-			self.rx_crc.add(byte);
-			self.rx_idx += 1;
-		}
-		self.rx_idx == self.rx_want
-	}
-
-	/// Call this in the TXEIE interrupt. It will load the SPI FIFO with some
-	/// data, either from `tx_buffer` or a padding byte.
-	fn tx_isr(&mut self) {
-		if self.tx_idx < self.tx_ready {
-			// We have some data yet to send. This is safe as long as we do the
-			// bounds check when we set `self.tx_ready`.
-			let next_tx = unsafe { *self.tx_buffer.get_unchecked(self.tx_idx) };
-			self.raw_write(next_tx);
-			self.tx_idx += 1;
-		} else {
-			// No data - send 0x00
-			self.raw_write(0x00);
-		}
-	}
-
-	/// Render some message into the TX buffer.
+	/// Render some message into the TX buffer, and start sending it.
 	///
-	/// You get an error if you try to load too much.
+	/// You get an error if you try to load too much, or if the *Long Write
+	/// Payload* we said we wanted can't be received.
 	pub fn set_transmit_sendable(
 		&mut self,
 		message: &dyn neotron_bmc_protocol::Sendable,
-	) -> Result<(), ()> {
-		self.tx_ready = 0;
-		self.tx_idx = 0;
-
-		// SPI FIFO seems to corrupt the first byte we load. So load a dummy one
-		// we don't care about.
-		self.tx_buffer[0] = 0xFF;
-		match message.render_to_buffer(&mut self.tx_buffer[1..]) {
+	) -> Result<(), Error> {
+		// The TX DMA is only sending padding now, so `tx_buffer` is free. Do
+		// the slow part (rendering) whilst the padding keeps the FIFO full.
+		let rendered = message.render_to_buffer(&mut self.tx_buffer);
+		// Stop the padding, and work out where the response will start. It
+		// must be re-started before the TX FIFO empties, or the counts will
+		// be off, so set up the RX side afterwards.
+		dma::TX.disable();
+		let response_at = self.tx_base + (MAX_TURNAROUND - dma::TX.remaining());
+		match rendered {
 			Ok(n) => {
-				// We must never set this to be longer than `TXC` as we do an
-				// unchecked read from `self.tx_buffer` in [`Self::tx_isr`].
-				self.tx_ready = (n + 1).min(TXC);
-				// Turn on the TX interrupt
-				self.dev.cr2.write(|w| {
-					w.txeie().not_masked();
-					w
-				});
-				Ok(())
+				// Safety: `tx_buffer` is ours, and `n` bytes long
+				unsafe {
+					dma::TX.start(
+						self.tx_buffer.as_ptr(),
+						n,
+						dma::FROM_MEMORY | dma::MINC | dma::TCIE,
+					);
+				}
+				self.tx_base = response_at + n;
+				if self.payload_want != 0 {
+					self.receive_payload(response_at + n)
+				} else {
+					Ok(())
+				}
+			}
+			Err(_) => {
+				self.start_padding();
+				self.tx_base = response_at;
+				self.payload_want = 0;
+				Err(Error::TooLarge)
 			}
-			Err(_) => Err(()),
 		}
 	}
+
+	/// Set the RX DMA up to catch the *Long Write Payload*, which starts with
+	/// the `payload_at`th byte of the transaction.
+	fn receive_payload(&mut self, payload_at: usize) -> Result<(), Error> {
+		let payload_want = core::mem::replace(&mut self.payload_want, 0);
+		dma::RX.disable();
+		// Anything received but not yet moved is still in the RX FIFO, and
+		// will be moved into `rx_buffer` first.
+		let received = self.rx_base + (MAX_TURNAROUND - dma::RX.remaining());
+		if self.dev.sr.read().ovr().bit_is_set() {
+			// We lost some bytes (e.g. we were stalled writing to flash), so
+			// the count is wrong. The host will time out.
+			defmt::warn!("SPI overrun - ignoring payload");
+			self.start_drain();
+			return Err(Error::PayloadLost);
+		}
+		let skip = payload_at.saturating_sub(received);
+		if skip + payload_want > RXC {
+			defmt::warn!("Payload doesn't fit ({} + {})", skip, payload_want);
+			self.start_drain();
+			return Err(Error::PayloadLost);
+		}
+		self.rx_skip = skip;
+		self.rx_want = payload_want;
+		self.rx_base = received + skip + payload_want;
+		// Safety: `rx_buffer` is ours, and we checked it's big enough
+		unsafe {
+			dma::RX.start(
+				self.rx_buffer.as_mut_ptr(),
+				skip + payload_want,
+				dma::MINC | dma::TCIE,
+			);
+		}
+		self.rx_phase = RxPhase::Payload;
+		Ok(())
+	}
 }
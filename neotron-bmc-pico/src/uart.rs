@@ -0,0 +1,327 @@
+//! # UART Bridge
+//!
+//! Passes bytes between the FTDI UART header (USART1) and the host, through
+//! the UART registers.
+//!
+//! The USART1 interrupt hands received bytes to the idle loop, which puts
+//! them in the RX FIFO for the host to read. Bytes the host writes go into
+//! the TX FIFO, and the idle loop feeds them to USART1 as it has room.
+//!
+//! We also keep a copy of the last few bytes the host sent (the history), so
+//! they can be read back after the host has crashed and been restarted.
+
+use stm32f0xx_hal::pac;
+
+pub use neotron_bmc_commands::uart::{
+	control, fifo_control, history, status, FIFO_LEN, HISTORY_LEN,
+};
+
+/// The baud rate we start with, in bits per second.
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// The slowest baud rate we support, in bits per second.
+pub const MIN_BAUD_RATE: u32 = 1_200;
+
+/// The fastest baud rate we support, in bits per second.
+pub const MAX_BAUD_RATE: u32 = 921_600;
+
+/// Why a request to the bridge was refused
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The FIFO doesn't have room
+	Full,
+	/// The settings don't make sense
+	Invalid,
+}
+
+/// The UART registers, as accessible via SPI reads and writes.
+#[derive(Debug)]
+pub struct Bridge {
+	/// Bytes for the host to read
+	rx: heapless::Deque<u8, FIFO_LEN>,
+	/// Bytes for us to send
+	tx: heapless::Deque<u8, FIFO_LEN>,
+	/// The *UART Control* register
+	control: u8,
+	/// The *UART Status* register (apart from [`status::TX_EMPTY`])
+	status: u8,
+	/// The baud rate, in bits per second
+	baud_rate: u32,
+	/// Have the settings changed?
+	needs_config: bool,
+	/// What the host has sent
+	history: History,
+}
+
+impl Default for Bridge {
+	fn default() -> Self {
+		Bridge {
+			rx: heapless::Deque::new(),
+			tx: heapless::Deque::new(),
+			control: control::ENABLE,
+			status: 0,
+			baud_rate: DEFAULT_BAUD_RATE,
+			needs_config: false,
+			history: History::default(),
+		}
+	}
+}
+
+impl Bridge {
+	/// Is the bridge passing bytes?
+	pub fn is_enabled(&self) -> bool {
+		(self.control & control::ENABLE) != 0
+	}
+
+	/// A byte arrived on the UART. If the RX FIFO is full, it is dropped.
+	pub fn receive(&mut self, byte: u8) {
+		if !self.is_enabled() {
+			return;
+		}
+		if self.rx.push_back(byte).is_err() {
+			self.status |= status::RX_OVERFLOW;
+		}
+	}
+
+	/// How many bytes are waiting to be read by the host?
+	pub fn rx_len(&self) -> usize {
+		self.rx.len()
+	}
+
+	/// Take a byte for the host.
+	pub fn pop_rx(&mut self) -> Option<u8> {
+		self.rx.pop_front()
+	}
+
+	/// Add bytes to the TX FIFO. Either they all fit, or none are added.
+	pub fn queue(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		if bytes.len() > self.tx.capacity() - self.tx.len() {
+			return Err(Error::Full);
+		}
+		for b in bytes {
+			let _ = self.tx.push_back(*b);
+			self.history.push(*b);
+		}
+		Ok(())
+	}
+
+	/// How many bytes are waiting to be sent?
+	pub fn tx_len(&self) -> usize {
+		self.tx.len()
+	}
+
+	/// Look at the next byte to send, if we're enabled.
+	pub fn peek_tx(&self) -> Option<u8> {
+		if self.is_enabled() {
+			self.tx.front().copied()
+		} else {
+			None
+		}
+	}
+
+	/// The byte from [`Self::peek_tx`] has been sent.
+	pub fn pop_tx(&mut self) {
+		let _ = self.tx.pop_front();
+	}
+
+	/// Empty the FIFOs (see [`fifo_control`]).
+	pub fn flush(&mut self, bits: u8) {
+		if (bits & fifo_control::FLUSH_TX) != 0 {
+			self.tx.clear();
+		}
+		if (bits & fifo_control::FLUSH_RX) != 0 {
+			self.rx.clear();
+		}
+	}
+
+	/// Get the *UART Control* register.
+	pub fn control(&self) -> u8 {
+		self.control
+	}
+
+	/// Set the *UART Control* register.
+	pub fn set_control(&mut self, bits: u8) -> Result<(), Error> {
+		if (bits & !control::ALL) != 0 || (bits & control::PARITY_MASK) == control::PARITY_MASK {
+			return Err(Error::Invalid);
+		}
+		if bits != self.control {
+			self.control = bits;
+			self.needs_config = true;
+		}
+		Ok(())
+	}
+
+	/// Get the *UART Status* register.
+	pub fn status(&self) -> u8 {
+		if self.tx.is_empty() {
+			self.status | status::TX_EMPTY
+		} else {
+			self.status
+		}
+	}
+
+	/// Clear bits in the *UART Status* register.
+	pub fn clear_status(&mut self, bits: u8) {
+		self.status &= !(bits & status::CLEARABLE);
+	}
+
+	pub fn baud_rate(&self) -> u32 {
+		self.baud_rate
+	}
+
+	/// Set the baud rate, in bits per second.
+	pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error> {
+		if !(MIN_BAUD_RATE..=MAX_BAUD_RATE).contains(&baud_rate) {
+			return Err(Error::Invalid);
+		}
+		self.baud_rate = baud_rate;
+		self.needs_config = true;
+		Ok(())
+	}
+
+	/// Get what the host has sent.
+	pub fn history(&self) -> &History {
+		&self.history
+	}
+
+	/// Get what the host has sent, so it can be read or started afresh.
+	pub fn history_mut(&mut self) -> &mut History {
+		&mut self.history
+	}
+
+	/// Do the settings need applying to the hardware? Clears the flag.
+	pub fn take_needs_config(&mut self) -> bool {
+		core::mem::replace(&mut self.needs_config, false)
+	}
+
+	/// Apply our settings to USART1.
+	///
+	/// The HAL can only set the baud rate when it creates the driver, so we
+	/// use the registers. The caller must hold the lock on the serial driver.
+	pub fn configure(&self, pclk: u32) {
+		let parity = self.control & control::PARITY_MASK;
+		let mut cr1_bits = 0;
+		if parity != 0 {
+			// A parity bit makes it a nine bit word
+			cr1_bits |= reg::CR1_M0 | reg::CR1_PCE;
+		}
+		if parity == control::PARITY_ODD {
+			cr1_bits |= reg::CR1_PS;
+		}
+		let cr2_bits = if (self.control & control::TWO_STOP_BITS) != 0 {
+			reg::CR2_STOP_TWO
+		} else {
+			0
+		};
+		// Safety: we only change the framing, with the caller holding the
+		// lock on the only other user of USART1.
+		let usart = unsafe { &*pac::USART1::ptr() };
+		// The framing can only be changed whilst the USART is disabled
+		usart
+			.cr1
+			.modify(|r, w| unsafe { w.bits(r.bits() & !reg::CR1_UE) });
+		usart
+			.brr
+			.write(|w| unsafe { w.bits(pclk / self.baud_rate) });
+		usart
+			.cr2
+			.modify(|r, w| unsafe { w.bits((r.bits() & !reg::CR2_STOP_MASK) | cr2_bits) });
+		usart.cr1.modify(|r, w| unsafe {
+			w.bits((r.bits() & !reg::CR1_FRAMING) | cr1_bits | reg::CR1_UE)
+		});
+	}
+}
+
+/// The last few bytes the host sent, since it last started and before that.
+#[derive(Debug, Default)]
+pub struct History {
+	/// Sent since the host last started
+	current: heapless::Deque<u8, HISTORY_LEN>,
+	/// Sent before the host last started
+	previous: heapless::Deque<u8, HISTORY_LEN>,
+	/// Is the *UART History* register reading the previous history?
+	reading_previous: bool,
+	/// How far through the selected history the *UART History* register is
+	read_pos: usize,
+}
+
+impl History {
+	/// Note a byte the host sent, dropping the oldest if we're full.
+	fn push(&mut self, byte: u8) {
+		if self.current.is_full() {
+			let _ = self.current.pop_front();
+			if !self.reading_previous {
+				// Stay on the same byte
+				self.read_pos = self.read_pos.saturating_sub(1);
+			}
+		}
+		let _ = self.current.push_back(byte);
+	}
+
+	/// The host has been started (or reset), so the current history becomes
+	/// the previous history.
+	pub fn host_started(&mut self) {
+		self.previous = core::mem::take(&mut self.current);
+		self.read_pos = 0;
+	}
+
+	/// Get the current (`false`) or previous (`true`) history.
+	pub fn get(&self, previous: bool) -> &heapless::Deque<u8, HISTORY_LEN> {
+		if previous {
+			&self.previous
+		} else {
+			&self.current
+		}
+	}
+
+	/// Select which history the *UART History* register reads (see
+	/// [`history`]), and start from the oldest byte.
+	pub fn select(&mut self, which: u8) -> Result<(), Error> {
+		self.reading_previous = match which {
+			history::CURRENT => false,
+			history::PREVIOUS => true,
+			_ => return Err(Error::Invalid),
+		};
+		self.read_pos = 0;
+		Ok(())
+	}
+
+	/// How many bytes of the selected history are left to read?
+	pub fn unread(&self) -> usize {
+		self.get(self.reading_previous)
+			.len()
+			.saturating_sub(self.read_pos)
+	}
+
+	/// Read the next byte of the selected history.
+	pub fn read(&mut self) -> Option<u8> {
+		let byte = self
+			.get(self.reading_previous)
+			.iter()
+			.nth(self.read_pos)
+			.copied();
+		if byte.is_some() {
+			self.read_pos += 1;
+		}
+		byte
+	}
+}
+
+/// Register bits, from RM0360 Section 23.7 (the PAC names vary between
+/// versions, so we use the bits).
+mod reg {
+	/// CR1: USART enable
+	pub const CR1_UE: u32 = 1 << 0;
+	/// CR1: Odd parity
+	pub const CR1_PS: u32 = 1 << 9;
+	/// CR1: Parity control enable
+	pub const CR1_PCE: u32 = 1 << 10;
+	/// CR1: Nine bit words
+	pub const CR1_M0: u32 = 1 << 12;
+	/// CR1: The bits we set from the *UART Control* register
+	pub const CR1_FRAMING: u32 = CR1_PS | CR1_PCE | CR1_M0;
+	/// CR2: Two stop bits
+	pub const CR2_STOP_TWO: u32 = 0b10 << 12;
+	/// CR2: The stop bits field
+	pub const CR2_STOP_MASK: u32 = 0b11 << 12;
+}
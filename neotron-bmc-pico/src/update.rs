@@ -0,0 +1,256 @@
+//! # Firmware Update
+//!
+//! Receives a new firmware image from the host into the staging area (see
+//! `memory.x`). Once it has been verified, the bootloader copies it over the
+//! running application the next time the BMC resets.
+//!
+//! The host writes to the registers, and the slow flash work happens later,
+//! in [`Updater::poll`], so we don't hold up the SPI response.
+
+use neotron_bmc_protocol::image;
+
+use stm32f0xx_hal::pac::{RCC, SYSCFG};
+
+use crate::flash::{self, Flash, PAGE_SIZE};
+
+/// Where the application (i.e. this firmware) lives, after the bootloader.
+/// Must match the `FLASH` region in `memory.x`.
+const APP_START: usize = 0x0800_0800;
+/// How many words are in our vector table (16 exceptions plus 32 interrupts)
+const VECTOR_TABLE_WORDS: usize = 48;
+/// Where new images are stored. Must match the `STAGING` region in
+/// `memory.x`.
+const STAGING_START: usize = 0x0800_4000;
+/// How big the staging area is (header included)
+const STAGING_SIZE: usize = 14 * 1024;
+/// The longest chunk of image we accept in one write
+pub const MAX_CHUNK: usize = 64;
+
+/// What the host can ask us to do, through the *Firmware Update Control*
+/// register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Operation {
+	/// Erase the staging area, ready for a new image
+	Start = 1,
+	/// Check the image in the staging area
+	Verify = 2,
+	/// Reset, so the bootloader installs the image
+	Install = 3,
+}
+
+impl Operation {
+	/// Convert from the *Firmware Update Control* register.
+	pub fn from_bits(bits: u8) -> Option<Operation> {
+		match bits {
+			1 => Some(Operation::Start),
+			2 => Some(Operation::Verify),
+			3 => Some(Operation::Install),
+			_ => None,
+		}
+	}
+}
+
+/// Where we've got to, as read from the *Firmware Update Control* register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+	/// No update in progress
+	Idle = 0,
+	/// Working on it (e.g. erasing)
+	Busy = 1,
+	/// Ready for (more) image data
+	Receiving = 2,
+	/// The image is good, and can be installed
+	Verified = 3,
+	/// Something went wrong - start again
+	Failed = 4,
+}
+
+/// What [`Updater::poll`] wants the caller to do next.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Next {
+	/// Nothing
+	Nothing,
+	/// Reset the BMC, so the bootloader installs the new image
+	Reset,
+}
+
+/// Make the CPU use our vector table, rather than the bootloader's.
+///
+/// The Cortex-M0 has no VTOR, so we copy our vector table to the start of
+/// RAM (which `memory.x` keeps free), and map RAM to address zero. Call this
+/// first thing in `init`, before any interrupts are enabled.
+pub fn use_ram_vector_table(rcc: &RCC, syscfg: &SYSCFG) {
+	let flash_table = APP_START as *const u32;
+	let ram_table = 0x2000_0000 as *mut u32;
+	for idx in 0..VECTOR_TABLE_WORDS {
+		// Safety: both tables are 48 words long, and nothing else uses the
+		// first 192 bytes of RAM.
+		unsafe {
+			ram_table
+				.add(idx)
+				.write_volatile(flash_table.add(idx).read_volatile())
+		};
+	}
+	rcc.apb2enr.modify(|_r, w| w.syscfgen().set_bit());
+	// Safety: 0b11 is "Embedded SRAM mapped at 0x0000_0000"
+	syscfg
+		.cfgr1
+		.modify(|_r, w| unsafe { w.mem_mode().bits(0b11) });
+}
+
+/// Handles the *Firmware Update* registers.
+#[derive(Debug)]
+pub struct Updater {
+	status: Status,
+	/// Checks the image as it arrives
+	receiver: image::Receiver,
+	/// The next page to erase, if we're erasing
+	erase_next: Option<usize>,
+	/// A chunk the host sent, which needs writing to flash
+	chunk: heapless::Vec<u8, MAX_CHUNK>,
+	/// An operation the host asked for, which we haven't done yet
+	pending: Option<Operation>,
+}
+
+impl Default for Updater {
+	fn default() -> Self {
+		Updater::new()
+	}
+}
+
+impl Updater {
+	/// Make a new updater, with no update in progress.
+	pub const fn new() -> Updater {
+		Updater {
+			status: Status::Idle,
+			receiver: image::Receiver::new(STAGING_SIZE),
+			erase_next: None,
+			chunk: heapless::Vec::new(),
+			pending: None,
+		}
+	}
+
+	/// Get the *Firmware Update Control* register value.
+	pub fn status(&self) -> Status {
+		if self.pending.is_some() || self.erase_next.is_some() || !self.chunk.is_empty() {
+			Status::Busy
+		} else {
+			self.status
+		}
+	}
+
+	/// How many bytes of image (header included) we've had. This is the
+	/// *Firmware Update Progress* register.
+	pub fn received(&self) -> u32 {
+		self.receiver.received() as u32
+	}
+
+	/// The host wrote to the *Firmware Update Control* register.
+	pub fn request(&mut self, op: Operation) {
+		self.pending = Some(op);
+	}
+
+	/// The host wrote to the *Firmware Update Data* register.
+	///
+	/// The chunk is checked and queued for writing. Every chunk except the
+	/// last must be an even number of bytes, as we write flash a half-word
+	/// at a time.
+	pub fn write(&mut self, data: &[u8]) -> Result<(), image::Error> {
+		if self.status() != Status::Receiving || (self.receiver.received() % 2) != 0 {
+			// Not expecting data, or the last chunk was odd-sized
+			self.fail();
+			return Err(image::Error::BadLength);
+		}
+		let offset = self.receiver.received();
+		if let Err(e) = self.receiver.feed(data) {
+			defmt::warn!("Bad image data at {}: {:?}", offset, e);
+			self.fail();
+			return Err(e);
+		}
+		// Can't fail - MAX_CHUNK is the longest Long Write we accept
+		let _ = self.chunk.extend_from_slice(data);
+		Ok(())
+	}
+
+	fn fail(&mut self) {
+		self.status = Status::Failed;
+		self.erase_next = None;
+		self.chunk.clear();
+	}
+
+	/// Do the next bit of slow flash work. Call this often.
+	pub fn poll(&mut self, flash: &mut Flash) -> Next {
+		if let Some(page) = self.erase_next {
+			// Erase one page at a time, so we keep up with everything else
+			match flash.erase_page(STAGING_START + (page * PAGE_SIZE)) {
+				Ok(()) if (page + 1) * PAGE_SIZE < STAGING_SIZE => {
+					self.erase_next = Some(page + 1);
+				}
+				Ok(()) => {
+					defmt::info!("Staging area erased");
+					self.erase_next = None;
+					self.status = Status::Receiving;
+				}
+				Err(e) => self.flash_failed(e),
+			}
+		} else if !self.chunk.is_empty() {
+			// `received` has already moved on past this chunk
+			let offset = self.receiver.received() - self.chunk.len();
+			if (self.chunk.len() % 2) != 0 {
+				// Pad the last byte, as erased flash would be
+				let _ = self.chunk.push(0xFF);
+			}
+			let result = flash.program(STAGING_START + offset, &self.chunk);
+			self.chunk.clear();
+			if let Err(e) = result {
+				self.flash_failed(e);
+			}
+		} else if let Some(op) = self.pending.take() {
+			defmt::info!("Firmware update {:?}", op);
+			match op {
+				Operation::Start => {
+					self.receiver = image::Receiver::new(STAGING_SIZE);
+					self.status = Status::Busy;
+					self.erase_next = Some(0);
+				}
+				Operation::Verify => {
+					self.status = match self.verify() {
+						Ok(header) => {
+							defmt::info!("Image OK: {:?}", header);
+							Status::Verified
+						}
+						Err(e) => {
+							defmt::warn!("Image bad: {:?}", e);
+							Status::Failed
+						}
+					};
+				}
+				Operation::Install if self.status == Status::Verified => {
+					return Next::Reset;
+				}
+				Operation::Install => {
+					defmt::warn!("Can't install an unverified image");
+				}
+			}
+		}
+		Next::Nothing
+	}
+
+	fn flash_failed(&mut self, e: flash::Error) {
+		defmt::warn!("Firmware update flash error: {:?}", e);
+		self.fail();
+	}
+
+	/// Check both what we were sent, and what ended up in flash.
+	fn verify(&self) -> Result<image::Header, image::Error> {
+		let header = self.receiver.check()?;
+		// Safety: this is flash, reserved for us in `memory.x`, and we only
+		// change it through `&mut self` methods.
+		let stored = unsafe { Flash::read(STAGING_START, STAGING_SIZE) };
+		let stored_header = image::verify(stored)?;
+		if stored_header != header {
+			return Err(image::Error::BadCrc);
+		}
+		Ok(header)
+	}
+}
@@ -0,0 +1,307 @@
+//! Framing for running the protocol over a serial link.
+//!
+//! SPI has a chip select line to mark where each *Request*, *Long Write
+//! Payload* and *Response* starts and ends. A UART doesn't, so each one is
+//! sent as a frame, encoded with [Consistent Overhead Byte
+//! Stuffing](https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing)
+//! (COBS) and followed by a zero byte. COBS never produces a zero byte, so
+//! the receiver can always find the end of a frame, even if it missed the
+//! start.
+//!
+//! The contents of each frame are exactly what would have been sent over SPI.
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// Marks the end of each frame.
+pub const DELIMITER: u8 = 0x00;
+
+// ============================================================================
+// Enums
+// ============================================================================
+
+/// The ways framing can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Error {
+	/// There isn't room for the encoded frame
+	BufferTooSmall,
+	/// The frame was longer than the [`Decoder`] can hold
+	TooLong,
+	/// The frame isn't valid COBS
+	Malformed,
+}
+
+// ============================================================================
+// Structs and Impls
+// ============================================================================
+
+/// Collects received bytes into frames, and decodes them.
+///
+/// `N` is the longest encoded frame it can hold (not counting the
+/// [`DELIMITER`]) - see [`max_encoded_len`].
+#[derive(Debug, Clone)]
+pub struct Decoder<const N: usize> {
+	buffer: [u8; N],
+	len: usize,
+	/// The length of the frame we just decoded (it's at the start of `buffer`)
+	decoded: usize,
+	overflowed: bool,
+}
+
+impl<const N: usize> Decoder<N> {
+	/// Make a new decoder, with nothing received.
+	pub const fn new() -> Decoder<N> {
+		Decoder {
+			buffer: [0u8; N],
+			len: 0,
+			decoded: 0,
+			overflowed: false,
+		}
+	}
+
+	/// Forget anything received since the last [`DELIMITER`].
+	pub fn reset(&mut self) {
+		self.len = 0;
+		self.decoded = 0;
+		self.overflowed = false;
+	}
+
+	/// Is the decoder part way through a frame?
+	pub fn is_empty(&self) -> bool {
+		self.len == 0 && !self.overflowed
+	}
+
+	/// Handle a received byte.
+	///
+	/// Once a whole frame has been received, you get its decoded contents.
+	/// Empty frames are ignored.
+	///
+	/// ```
+	/// use neotron_bmc_protocol::cobs::Decoder;
+	/// let mut decoder = Decoder::<8>::new();
+	/// assert_eq!(decoder.push(0x02), None);
+	/// assert_eq!(decoder.push(0xC0), None);
+	/// assert_eq!(decoder.push(0x01), None);
+	/// assert_eq!(decoder.push(0x00), Some(Ok(&[0xC0, 0x00][..])));
+	/// ```
+	pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], Error>> {
+		// We're about to overwrite it
+		self.decoded = 0;
+		if byte != DELIMITER {
+			if self.len < N {
+				self.buffer[self.len] = byte;
+				self.len += 1;
+			} else {
+				self.overflowed = true;
+			}
+			return None;
+		}
+		let len = core::mem::replace(&mut self.len, 0);
+		if core::mem::replace(&mut self.overflowed, false) {
+			return Some(Err(Error::TooLong));
+		}
+		if len == 0 {
+			return None;
+		}
+		match decode_in_place(&mut self.buffer[0..len]) {
+			Ok(n) => {
+				self.decoded = n;
+				Some(Ok(&self.buffer[0..n]))
+			}
+			Err(e) => Some(Err(e)),
+		}
+	}
+
+	/// The frame [`Decoder::push`] just gave you, if it gave you one.
+	///
+	/// It's gone once another byte is pushed.
+	pub fn frame(&self) -> &[u8] {
+		&self.buffer[0..self.decoded]
+	}
+}
+
+impl<const N: usize> Default for Decoder<N> {
+	fn default() -> Self {
+		Decoder::new()
+	}
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// The most bytes [`encode`] can produce from `len` bytes, including the
+/// [`DELIMITER`].
+pub const fn max_encoded_len(len: usize) -> usize {
+	len + (len / 254) + 2
+}
+
+/// Encode `data` as a frame, including the trailing [`DELIMITER`].
+///
+/// You get the number of bytes written to `buffer`, which must have room
+/// for [`max_encoded_len`] bytes.
+///
+/// ```
+/// use neotron_bmc_protocol::cobs::encode;
+/// let mut buffer = [0u8; 8];
+/// let len = encode(&[0xC0, 0x00, 0x05], &mut buffer).unwrap();
+/// assert_eq!(&buffer[0..len], &[0x02, 0xC0, 0x02, 0x05, 0x00]);
+/// ```
+pub fn encode(data: &[u8], buffer: &mut [u8]) -> Result<usize, Error> {
+	if buffer.len() < max_encoded_len(data.len()) {
+		return Err(Error::BufferTooSmall);
+	}
+	// Where the code byte for the current block goes
+	let mut code_idx = 0;
+	let mut out_idx = 1;
+	let mut code = 1u8;
+	for &byte in data {
+		if byte != 0 {
+			buffer[out_idx] = byte;
+			out_idx += 1;
+			code += 1;
+		}
+		if byte == 0 || code == 0xFF {
+			// End this block, and start another
+			buffer[code_idx] = code;
+			code_idx = out_idx;
+			out_idx += 1;
+			code = 1;
+		}
+	}
+	buffer[code_idx] = code;
+	buffer[out_idx] = DELIMITER;
+	Ok(out_idx + 1)
+}
+
+/// Decode a frame (without its [`DELIMITER`]) where it is.
+///
+/// You get the length of the decoded data, which is at the start of
+/// `buffer`.
+pub fn decode_in_place(buffer: &mut [u8]) -> Result<usize, Error> {
+	let mut in_idx = 0;
+	let mut out_idx = 0;
+	while in_idx < buffer.len() {
+		let code = buffer[in_idx];
+		if code == 0 {
+			return Err(Error::Malformed);
+		}
+		in_idx += 1;
+		let block_len = usize::from(code) - 1;
+		if in_idx + block_len > buffer.len() {
+			return Err(Error::Malformed);
+		}
+		buffer.copy_within(in_idx..in_idx + block_len, out_idx);
+		in_idx += block_len;
+		out_idx += block_len;
+		// Every block but the last, and the long ones, ended with a zero
+		if code != 0xFF && in_idx < buffer.len() {
+			buffer[out_idx] = 0;
+			out_idx += 1;
+		}
+	}
+	Ok(out_idx)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn round_trip(data: &[u8]) {
+		let mut encoded = vec![0u8; max_encoded_len(data.len())];
+		let len = encode(data, &mut encoded).unwrap();
+		assert_eq!(encoded[len - 1], DELIMITER);
+		assert!(!encoded[0..len - 1].contains(&DELIMITER));
+		let mut decoder = Decoder::<300>::new();
+		for b in &encoded[0..len - 1] {
+			assert_eq!(decoder.push(*b), None);
+		}
+		let frame = decoder.push(DELIMITER);
+		if data.is_empty() {
+			// The encoding of nothing is a frame with just a code byte
+			assert_eq!(frame, Some(Ok(&[][..])));
+		} else {
+			assert_eq!(frame, Some(Ok(data)));
+		}
+	}
+
+	#[test]
+	fn known_encodings() {
+		let mut buffer = [0u8; 16];
+		let len = encode(&[], &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], &[0x01, 0x00]);
+		let len = encode(&[0x00], &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], &[0x01, 0x01, 0x00]);
+		let len = encode(&[0x00, 0x00], &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], &[0x01, 0x01, 0x01, 0x00]);
+		let len = encode(&[0x11, 0x22, 0x00, 0x33], &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], &[0x03, 0x11, 0x22, 0x02, 0x33, 0x00]);
+		let len = encode(&[0x11, 0x22, 0x33, 0x44], &mut buffer).unwrap();
+		assert_eq!(&buffer[0..len], &[0x05, 0x11, 0x22, 0x33, 0x44, 0x00]);
+	}
+
+	#[test]
+	fn round_trips() {
+		round_trip(&[]);
+		round_trip(&[0x00]);
+		round_trip(&[0xC0, 0x00, 0x03, 0x7F]);
+		round_trip(&[0xA0, 0x00, 0x01, 0x01, 0x00]);
+		let ramp: Vec<u8> = (0..=255).collect();
+		round_trip(&ramp);
+		let no_zeros: Vec<u8> = (1..=255).chain(1..=20).collect();
+		round_trip(&no_zeros);
+		round_trip(&no_zeros[0..254]);
+		round_trip(&no_zeros[0..253]);
+	}
+
+	#[test]
+	fn buffer_too_small() {
+		let mut buffer = [0u8; 5];
+		assert_eq!(
+			encode(&[1, 2, 3, 4], &mut buffer),
+			Err(Error::BufferTooSmall)
+		);
+	}
+
+	#[test]
+	fn decoder_recovers() {
+		let mut decoder = Decoder::<4>::new();
+		// Too long for the decoder
+		for b in [0x06, 1, 2, 3, 4, 5] {
+			assert_eq!(decoder.push(b), None);
+		}
+		assert_eq!(decoder.push(DELIMITER), Some(Err(Error::TooLong)));
+		// A block which runs past the end
+		for b in [0x04, 1] {
+			assert_eq!(decoder.push(b), None);
+		}
+		assert_eq!(decoder.push(DELIMITER), Some(Err(Error::Malformed)));
+		// Extra delimiters are ignored, and then we're fine again
+		assert_eq!(decoder.push(DELIMITER), None);
+		for b in [0x02, 9, 0x01] {
+			assert_eq!(decoder.push(b), None);
+		}
+		assert_eq!(decoder.push(DELIMITER), Some(Ok(&[9, 0][..])));
+		assert_eq!(decoder.frame(), &[9, 0]);
+		assert!(decoder.is_empty());
+		assert_eq!(decoder.push(0x01), None);
+		assert_eq!(decoder.frame(), &[]);
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
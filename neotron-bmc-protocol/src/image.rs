@@ -0,0 +1,365 @@
+//! Framing for NBMC firmware images.
+//!
+//! A firmware image is sent to the NBMC as a 16-byte [`Header`], followed by
+//! the raw application binary. The NBMC stores the whole thing (header
+//! included) in its staging area, and its bootloader only installs the image
+//! if the length and CRC-32 in the header match what was stored.
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// Marks the start of a firmware image (`NBMI` in ASCII).
+pub const MAGIC: u32 = 0x494D_424E;
+
+/// The length of a [`Header`], in bytes.
+pub const HEADER_LEN: usize = 16;
+
+// ============================================================================
+// Enums
+// ============================================================================
+
+/// The ways an image can be bad
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Error {
+	/// The header doesn't start with [`MAGIC`]
+	BadMagic,
+	/// The image is empty, or won't fit where it's going
+	BadLength,
+	/// The CRC-32 of the image doesn't match the header
+	BadCrc,
+	/// More data was given than the header said there would be
+	TooMuchData,
+}
+
+// ============================================================================
+// Structs and Impls
+// ============================================================================
+
+/// Describes the application binary which follows it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Header {
+	/// The length of the application binary, in bytes
+	pub length: u32,
+	/// The CRC-32 of the application binary
+	pub crc: u32,
+}
+
+impl Header {
+	/// Make a header for the given application binary.
+	///
+	/// ```
+	/// # use neotron_bmc_protocol::image::Header;
+	/// let header = Header::new(b"123456789");
+	/// assert_eq!(header.length, 9);
+	/// assert_eq!(header.crc, 0xCBF4_3926);
+	/// ```
+	pub fn new(image: &[u8]) -> Header {
+		Header {
+			length: image.len() as u32,
+			crc: crc32(image),
+		}
+	}
+
+	/// Convert to bytes, for sending before the image.
+	pub fn as_bytes(&self) -> [u8; HEADER_LEN] {
+		let mut result = [0xFF; HEADER_LEN];
+		result[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+		result[4..8].copy_from_slice(&self.length.to_le_bytes());
+		result[8..12].copy_from_slice(&self.crc.to_le_bytes());
+		result
+	}
+
+	/// Convert from bytes. The length is not checked.
+	pub fn from_bytes(data: &[u8; HEADER_LEN]) -> Result<Header, Error> {
+		let word = |n: usize| u32::from_le_bytes([data[n], data[n + 1], data[n + 2], data[n + 3]]);
+		if word(0) != MAGIC {
+			return Err(Error::BadMagic);
+		}
+		Ok(Header {
+			length: word(4),
+			crc: word(8),
+		})
+	}
+}
+
+/// Checks a stream of image bytes (header first) as they arrive, and works
+/// out where each chunk should be stored.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Receiver {
+	/// How big the header plus image can be
+	capacity: usize,
+	/// How many bytes we've had so far
+	received: usize,
+	/// The header, as it arrives
+	header_bytes: [u8; HEADER_LEN],
+	/// The decoded header, once we've got it all
+	header: Option<Header>,
+	/// The CRC of the image bytes so far
+	crc: Crc32,
+}
+
+impl Receiver {
+	/// Make a new receiver, for an area which can hold `capacity` bytes
+	/// (including the header).
+	pub const fn new(capacity: usize) -> Receiver {
+		Receiver {
+			capacity,
+			received: 0,
+			header_bytes: [0u8; HEADER_LEN],
+			header: None,
+			crc: Crc32::new(),
+		}
+	}
+
+	/// How many bytes have been received, which is also the offset at which
+	/// the next chunk should be stored.
+	pub fn received(&self) -> usize {
+		self.received
+	}
+
+	/// Handle the next chunk of data.
+	///
+	/// If this returns `Ok`, store `chunk` at the offset given by
+	/// [`Self::received`] *before* this call.
+	pub fn feed(&mut self, chunk: &[u8]) -> Result<(), Error> {
+		let mut image_part = chunk;
+		if self.received < HEADER_LEN {
+			let needed = (HEADER_LEN - self.received).min(chunk.len());
+			self.header_bytes[self.received..self.received + needed]
+				.copy_from_slice(&chunk[0..needed]);
+			image_part = &chunk[needed..];
+			if self.received + needed == HEADER_LEN {
+				let header = Header::from_bytes(&self.header_bytes)?;
+				if header.length == 0
+					|| (header.length as usize) > self.capacity.saturating_sub(HEADER_LEN)
+				{
+					return Err(Error::BadLength);
+				}
+				self.header = Some(header);
+			}
+		}
+		if let Some(header) = self.header {
+			let image_received = (self.received + chunk.len()).saturating_sub(HEADER_LEN);
+			if image_received > header.length as usize {
+				return Err(Error::TooMuchData);
+			}
+		}
+		self.crc.add(image_part);
+		self.received += chunk.len();
+		Ok(())
+	}
+
+	/// Have we had the whole image, with a good CRC?
+	pub fn check(&self) -> Result<Header, Error> {
+		let header = self.header.ok_or(Error::BadLength)?;
+		if self.received != HEADER_LEN + header.length as usize {
+			return Err(Error::BadLength);
+		}
+		if self.crc.get() != header.crc {
+			return Err(Error::BadCrc);
+		}
+		Ok(header)
+	}
+}
+
+/// An object for calculating CRC-32 values on-the-fly.
+///
+/// This is the common IEEE 802.3 CRC-32, as used by zlib. It is calculated
+/// bit-by-bit, so the code stays small enough for a bootloader.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+	fn default() -> Self {
+		Crc32::new()
+	}
+}
+
+impl Crc32 {
+	/// The bit-reversed polynomial
+	const POLY: u32 = 0xEDB8_8320;
+
+	/// Make a new CRC calculator
+	pub const fn new() -> Crc32 {
+		Crc32(0xFFFF_FFFF)
+	}
+
+	/// Add several bytes to the CRC calculator
+	pub fn add(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			self.0 ^= u32::from(*byte);
+			for _ in 0..8 {
+				let mask = (self.0 & 1).wrapping_neg();
+				self.0 = (self.0 >> 1) ^ (Self::POLY & mask);
+			}
+		}
+	}
+
+	/// Get the CRC
+	pub fn get(&self) -> u32 {
+		!self.0
+	}
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Calculates the CRC-32 of the given bytes.
+///
+/// ```
+/// # use neotron_bmc_protocol::image::crc32;
+/// assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+/// assert_eq!(crc32(&[]), 0x0000_0000);
+/// ```
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut crc = Crc32::new();
+	crc.add(data);
+	crc.get()
+}
+
+/// Check a stored image (header first), as found in the staging area.
+///
+/// Any bytes after the image are ignored.
+pub fn verify(stored: &[u8]) -> Result<Header, Error> {
+	if stored.len() < HEADER_LEN {
+		return Err(Error::BadLength);
+	}
+	let mut header_bytes = [0u8; HEADER_LEN];
+	header_bytes.copy_from_slice(&stored[0..HEADER_LEN]);
+	let header = Header::from_bytes(&header_bytes)?;
+	if header.length == 0 {
+		return Err(Error::BadLength);
+	}
+	let end = HEADER_LEN
+		.checked_add(header.length as usize)
+		.ok_or(Error::BadLength)?;
+	let image = stored.get(HEADER_LEN..end).ok_or(Error::BadLength)?;
+	if crc32(image) != header.crc {
+		return Err(Error::BadCrc);
+	}
+	Ok(header)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Make a staging area holding the given image.
+	fn stage(image: &[u8]) -> Vec<u8> {
+		let mut stored = Header::new(image).as_bytes().to_vec();
+		stored.extend_from_slice(image);
+		stored
+	}
+
+	#[test]
+	fn header_round_trip() {
+		let header = Header {
+			length: 0x1234,
+			crc: 0xDEAD_BEEF,
+		};
+		let bytes = header.as_bytes();
+		assert_eq!(
+			bytes,
+			[
+				0x4E, 0x42, 0x4D, 0x49, 0x34, 0x12, 0x00, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0xFF, 0xFF,
+				0xFF, 0xFF
+			]
+		);
+		assert_eq!(Header::from_bytes(&bytes), Ok(header));
+	}
+
+	#[test]
+	fn header_bad_magic() {
+		let mut bytes = Header::new(b"hello").as_bytes();
+		bytes[0] = 0x00;
+		assert_eq!(Header::from_bytes(&bytes), Err(Error::BadMagic));
+	}
+
+	#[test]
+	fn verify_good() {
+		let mut stored = stage(b"Hello, world!");
+		// Erased flash after the image doesn't matter
+		stored.extend_from_slice(&[0xFF; 32]);
+		assert_eq!(verify(&stored), Ok(Header::new(b"Hello, world!")));
+	}
+
+	#[test]
+	fn verify_bad() {
+		let mut stored = stage(b"Hello, world!");
+		stored[HEADER_LEN] = b'J';
+		assert_eq!(verify(&stored), Err(Error::BadCrc));
+		// Truncated
+		let stored = stage(b"Hello, world!");
+		assert_eq!(verify(&stored[0..20]), Err(Error::BadLength));
+		// Erased flash
+		assert_eq!(verify(&[0xFF; 64]), Err(Error::BadMagic));
+		assert_eq!(verify(&[0xFF; 4]), Err(Error::BadLength));
+	}
+
+	#[test]
+	fn receive_in_chunks() {
+		let image: Vec<u8> = (0..200u32).map(|x| x as u8).collect();
+		let stored = stage(&image);
+		let mut receiver = Receiver::new(256);
+		// Odd sized chunks, so the header gets split
+		for chunk in stored.chunks(7) {
+			assert_eq!(receiver.feed(chunk), Ok(()));
+		}
+		assert_eq!(receiver.received(), stored.len());
+		assert_eq!(receiver.check(), Ok(Header::new(&image)));
+	}
+
+	#[test]
+	fn receive_incomplete() {
+		let stored = stage(b"Hello, world!");
+		let mut receiver = Receiver::new(256);
+		assert_eq!(receiver.check(), Err(Error::BadLength));
+		receiver.feed(&stored[0..20]).unwrap();
+		assert_eq!(receiver.check(), Err(Error::BadLength));
+	}
+
+	#[test]
+	fn receive_corrupt() {
+		let mut stored = stage(b"Hello, world!");
+		stored[HEADER_LEN + 1] ^= 0x01;
+		let mut receiver = Receiver::new(256);
+		receiver.feed(&stored).unwrap();
+		assert_eq!(receiver.check(), Err(Error::BadCrc));
+	}
+
+	#[test]
+	fn receive_too_much() {
+		let mut stored = stage(b"Hello, world!");
+		stored.push(0x00);
+		let mut receiver = Receiver::new(256);
+		assert_eq!(receiver.feed(&stored), Err(Error::TooMuchData));
+	}
+
+	#[test]
+	fn receive_too_big() {
+		let stored = stage(&[0u8; 100]);
+		let mut receiver = Receiver::new(64);
+		assert_eq!(receiver.feed(&stored[0..HEADER_LEN]), Err(Error::BadLength));
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -8,7 +8,9 @@
 #[cfg(feature = "defmt")]
 use defmt::Format;
 
+pub mod cobs;
 mod crc;
+pub mod image;
 
 // ============================================================================
 // Traits
@@ -452,6 +454,12 @@ impl Sendable for ProtocolVersion {
 /// An object for calculating CRC8 values on-the-fly.
 pub struct CrcCalc(u8);
 
+impl Default for CrcCalc {
+	fn default() -> Self {
+		CrcCalc::new()
+	}
+}
+
 impl CrcCalc {
 	/// Make a new CRC calculator
 	pub const fn new() -> CrcCalc {
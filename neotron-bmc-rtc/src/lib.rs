@@ -0,0 +1,337 @@
+#![doc = include_str!("../README.md")]
+#![cfg_attr(not(test), no_std)]
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A calendar date and time, between 2000-01-01 and 2099-12-31.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct DateTime {
+	/// Years since 2000
+	pub year: u8,
+	/// Month of the year (1 to 12)
+	pub month: u8,
+	/// Day of the month (1 to 31)
+	pub day: u8,
+	/// Hours (0 to 23)
+	pub hours: u8,
+	/// Minutes (0 to 59)
+	pub minutes: u8,
+	/// Seconds (0 to 59)
+	pub seconds: u8,
+}
+
+// ============================================================================
+// Impls
+// ============================================================================
+
+impl DateTime {
+	/// How long a date/time is when sent over SPI
+	pub const LENGTH: usize = 6;
+
+	/// Decode from the register format, checking the fields are in range.
+	pub fn from_bytes(bytes: &[u8]) -> Option<DateTime> {
+		if bytes.len() != Self::LENGTH {
+			return None;
+		}
+		let dt = DateTime {
+			year: bytes[0],
+			month: bytes[1],
+			day: bytes[2],
+			hours: bytes[3],
+			minutes: bytes[4],
+			seconds: bytes[5],
+		};
+		if dt.year > 99
+			|| !(1..=12).contains(&dt.month)
+			|| dt.day < 1
+			|| dt.day > Self::days_in_month(dt.year, dt.month)
+			|| dt.hours > 23
+			|| dt.minutes > 59
+			|| dt.seconds > 59
+		{
+			return None;
+		}
+		Some(dt)
+	}
+
+	/// How many days the month has. The year is 2000 - 2099, so every
+	/// fourth year is a leap year.
+	pub fn days_in_month(year: u8, month: u8) -> u8 {
+		match month {
+			2 if year.is_multiple_of(4) => 29,
+			2 => 28,
+			4 | 6 | 9 | 11 => 30,
+			_ => 31,
+		}
+	}
+
+	/// Encode into the register format.
+	pub fn as_bytes(&self) -> [u8; Self::LENGTH] {
+		[
+			self.year,
+			self.month,
+			self.day,
+			self.hours,
+			self.minutes,
+			self.seconds,
+		]
+	}
+
+	/// Day of the week, 1 (Monday) to 7 (Sunday), as the RTC wants it.
+	pub fn weekday(&self) -> u8 {
+		// 2000-01-01 was a Saturday. Count the days since then.
+		const DAYS_BEFORE_MONTH: [u16; 12] =
+			[0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+		let year = u16::from(self.year);
+		let mut days = year * 365 + year.div_ceil(4);
+		days += DAYS_BEFORE_MONTH[usize::from(self.month - 1)];
+		if self.month > 2 && year.is_multiple_of(4) {
+			days += 1;
+		}
+		days += u16::from(self.day - 1);
+		(((days + 5) % 7) + 1) as u8
+	}
+
+	/// Convert from the RTC's BCD time and date registers.
+	pub fn from_registers(tr: u32, dr: u32) -> DateTime {
+		DateTime {
+			year: from_bcd(dr >> 16),
+			month: from_bcd((dr >> 8) & 0x1F),
+			day: from_bcd(dr & 0x3F),
+			hours: from_bcd((tr >> 16) & 0x3F),
+			minutes: from_bcd((tr >> 8) & 0x7F),
+			seconds: from_bcd(tr & 0x7F),
+		}
+	}
+
+	/// Convert to the RTC's BCD time register format.
+	pub fn time_register(&self) -> u32 {
+		(to_bcd(self.hours) << 16) | (to_bcd(self.minutes) << 8) | to_bcd(self.seconds)
+	}
+
+	/// Convert to the RTC's BCD date register format.
+	pub fn date_register(&self) -> u32 {
+		(to_bcd(self.year) << 16)
+			| (u32::from(self.weekday()) << 13)
+			| (to_bcd(self.month) << 8)
+			| to_bcd(self.day)
+	}
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Convert a binary-coded-decimal value to binary.
+pub fn from_bcd(bcd: u32) -> u8 {
+	(((bcd >> 4) & 0x0F) * 10 + (bcd & 0x0F)) as u8
+}
+
+/// Convert a binary value to binary-coded-decimal.
+pub fn to_bcd(value: u8) -> u32 {
+	let value = u32::from(value);
+	((value / 10) << 4) | (value % 10)
+}
+
+/// Pick RTC prescalers which divide the given clock down to 1 Hz.
+///
+/// You get the values for the `PREDIV_A` and `PREDIV_S` fields, which are
+/// one less than the divisors. We want the asynchronous prescaler as large as
+/// possible (to save power), but we care more about being accurate.
+pub fn prescalers(clock_hz: u32) -> (u8, u16) {
+	let mut best = (127, 0, u32::MAX);
+	for div_a in (1..=128u32).rev() {
+		let div_s = (clock_hz + div_a / 2) / div_a;
+		if div_s == 0 || div_s > 0x8000 {
+			continue;
+		}
+		let error = (div_a * div_s).abs_diff(clock_hz);
+		if error < best.2 {
+			best = ((div_a - 1) as u8, (div_s - 1) as u16, error);
+		}
+		if error == 0 {
+			break;
+		}
+	}
+	(best.0, best.1)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn date(year: u8, month: u8, day: u8) -> DateTime {
+		DateTime {
+			year,
+			month,
+			day,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn from_bytes() {
+		let dt = DateTime::from_bytes(&[23, 10, 18, 17, 25, 53]).unwrap();
+		assert_eq!(
+			dt,
+			DateTime {
+				year: 23,
+				month: 10,
+				day: 18,
+				hours: 17,
+				minutes: 25,
+				seconds: 53,
+			}
+		);
+		assert_eq!(dt.as_bytes(), [23, 10, 18, 17, 25, 53]);
+		assert!(DateTime::from_bytes(&[0, 1, 1, 0, 0, 0]).is_some());
+		assert!(DateTime::from_bytes(&[99, 12, 31, 23, 59, 59]).is_some());
+	}
+
+	#[test]
+	fn from_bytes_out_of_range() {
+		assert_eq!(DateTime::from_bytes(&[23, 10, 18, 17, 25]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 10, 18, 17, 25, 53, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[100, 1, 1, 0, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 0, 1, 0, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 13, 1, 0, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 1, 0, 0, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 1, 32, 0, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 4, 31, 0, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 1, 1, 24, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 1, 1, 0, 60, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[23, 1, 1, 0, 0, 60]), None);
+	}
+
+	#[test]
+	fn leap_years() {
+		// 2000 was a leap year (divisible by 400)
+		assert_eq!(DateTime::days_in_month(0, 2), 29);
+		assert_eq!(DateTime::days_in_month(24, 2), 29);
+		assert_eq!(DateTime::days_in_month(23, 2), 28);
+		assert_eq!(DateTime::days_in_month(99, 2), 28);
+		assert!(DateTime::from_bytes(&[24, 2, 29, 0, 0, 0]).is_some());
+		assert!(DateTime::from_bytes(&[23, 2, 29, 0, 0, 0]).is_none());
+		assert!(DateTime::from_bytes(&[0, 2, 29, 0, 0, 0]).is_some());
+	}
+
+	#[test]
+	fn days_in_month() {
+		let days: Vec<u8> = (1..=12).map(|m| DateTime::days_in_month(23, m)).collect();
+		assert_eq!(days, [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31]);
+	}
+
+	#[test]
+	fn weekday() {
+		// Saturday
+		assert_eq!(date(0, 1, 1).weekday(), 6);
+		// Tuesday, then Wednesday after the leap day
+		assert_eq!(date(0, 2, 29).weekday(), 2);
+		assert_eq!(date(0, 3, 1).weekday(), 3);
+		// Thursday, then Friday
+		assert_eq!(date(24, 2, 29).weekday(), 4);
+		assert_eq!(date(24, 3, 1).weekday(), 5);
+		// Wednesday
+		assert_eq!(date(23, 10, 18).weekday(), 3);
+		// Thursday, the last day we can hold
+		assert_eq!(date(99, 12, 31).weekday(), 4);
+	}
+
+	#[test]
+	fn weekday_counts_up() {
+		// Every day is the one after the day before, all century
+		let mut last = date(0, 1, 1).weekday();
+		for year in 0..=99 {
+			for month in 1..=12 {
+				for day in 1..=DateTime::days_in_month(year, month) {
+					if (year, month, day) == (0, 1, 1) {
+						continue;
+					}
+					let weekday = date(year, month, day).weekday();
+					assert_eq!(weekday, (last % 7) + 1, "{}-{}-{}", year, month, day);
+					last = weekday;
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn bcd() {
+		assert_eq!(to_bcd(0), 0x00);
+		assert_eq!(to_bcd(9), 0x09);
+		assert_eq!(to_bcd(10), 0x10);
+		assert_eq!(to_bcd(59), 0x59);
+		assert_eq!(to_bcd(99), 0x99);
+		assert_eq!(from_bcd(0x59), 59);
+		for value in 0..=99 {
+			assert_eq!(from_bcd(to_bcd(value)), value);
+		}
+	}
+
+	#[test]
+	fn registers() {
+		let dt = DateTime::from_bytes(&[24, 2, 29, 23, 59, 58]).unwrap();
+		assert_eq!(dt.time_register(), 0x0023_5958);
+		// Thursday is 4, in bits 15-13
+		assert_eq!(dt.date_register(), 0x0024_0000 | (4 << 13) | 0x0229);
+		assert_eq!(
+			DateTime::from_registers(dt.time_register(), dt.date_register()),
+			dt
+		);
+	}
+
+	#[test]
+	fn prescalers_for_crystal() {
+		// The usual values for a 32.768 kHz crystal
+		assert_eq!(prescalers(32_768), (127, 255));
+	}
+
+	#[test]
+	fn prescalers_exact() {
+		// 128 doesn't divide 40 kHz, but 125 does
+		assert_eq!(prescalers(40_000), (124, 319));
+	}
+
+	#[test]
+	fn prescalers_close() {
+		// The LSI is somewhere between 30 and 50 kHz
+		for clock_hz in (30_000..=50_000).step_by(7) {
+			let (a, s) = prescalers(clock_hz);
+			let div_a = u32::from(a) + 1;
+			let div_s = u32::from(s) + 1;
+			// Rounding div_s is as close as we can get with that div_a
+			assert!(
+				(div_a * div_s).abs_diff(clock_hz) <= div_a / 2,
+				"{} Hz gave {}/{}",
+				clock_hz,
+				a,
+				s
+			);
+		}
+	}
+
+	#[test]
+	fn prescalers_slow_clock() {
+		// With a very slow clock, div_s can't be zero
+		let (a, s) = prescalers(100);
+		assert_eq!((u32::from(a) + 1) * (u32::from(s) + 1), 100);
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================
@@ -0,0 +1,336 @@
+#![doc = include_str!("../README.md")]
+#![cfg_attr(not(test), no_std)]
+
+// ============================================================================
+// Modules and Imports
+// ============================================================================
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use neotron_bmc_commands::{host_watchdog, speaker::is_valid_frequency};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// The lines printed by the `help` command
+pub const HELP: &[&str] = &[
+	"help",
+	"status",
+	"power on | power off",
+	"reset",
+	"rails",
+	"log",
+	"history [previous]",
+	"version",
+	"frames",
+	"exit",
+	"config get [<name>]",
+	"config set <name> <value>",
+	"config save",
+];
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// The commands the shell understands
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Command {
+	/// List the commands
+	Help,
+	/// Show the power state, and why it last changed
+	Status,
+	/// Turn the host on
+	PowerOn,
+	/// Turn the host off
+	PowerOff,
+	/// Reset the host
+	Reset,
+	/// Show the power rail voltages
+	Rails,
+	/// Show the event log
+	Log,
+	/// Show what the host sent since it last started (`false`), or before
+	/// that (`true`)
+	History(bool),
+	/// Show the BMC firmware version
+	Version,
+	/// Carry NBMC protocol frames on the UART, instead of the console
+	Frames,
+	/// Go back to the host's console
+	Exit,
+	/// Show one setting, or all of them
+	ConfigGet(Option<Setting>),
+	/// Change a setting
+	ConfigSet(Setting, u16),
+	/// Save the settings to flash
+	ConfigSave,
+}
+
+/// The settings the `config` command can get and set.
+///
+/// Each holds the raw value of the NBMC register with the same name.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Setting {
+	/// The *PS/2 Keyboard Shortcuts* register
+	KbShortcuts,
+	/// The *Power Policy* register
+	PowerPolicy,
+	/// The *Wake Key* register
+	WakeKey,
+	/// The *Interrupt Control* register
+	InterruptControl,
+	/// The *Host Watchdog Control* register
+	WatchdogAction,
+	/// The *Speaker Frequency* register
+	SpeakerFrequency,
+	/// The *Speaker Duty Cycle* register
+	SpeakerDutyCycle,
+}
+
+/// Why a command line couldn't be parsed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Error {
+	/// We don't know that command
+	UnknownCommand,
+	/// The command needs different arguments
+	BadArguments,
+	/// We don't know that setting
+	UnknownSetting,
+	/// The value isn't a number, or is too big for the setting
+	BadValue,
+}
+
+// ============================================================================
+// Functions
+// ============================================================================
+
+/// Parse a command line.
+///
+/// Words are separated by any amount of whitespace.
+///
+/// ```rust
+/// use neotron_bmc_shell::{parse, Command, Setting};
+/// assert_eq!(parse("power on"), Ok(Command::PowerOn));
+/// let command = parse("config set wake-key 0x5a");
+/// assert_eq!(command, Ok(Command::ConfigSet(Setting::WakeKey, 0x5A)));
+/// ```
+pub fn parse(line: &str) -> Result<Command, Error> {
+	let mut words = line.split_ascii_whitespace();
+	let command = match words.next() {
+		Some("help") => Command::Help,
+		Some("status") => Command::Status,
+		Some("power") => match words.next() {
+			Some("on") => Command::PowerOn,
+			Some("off") => Command::PowerOff,
+			_ => return Err(Error::BadArguments),
+		},
+		Some("reset") => Command::Reset,
+		Some("rails") => Command::Rails,
+		Some("log") => Command::Log,
+		Some("history") => match words.next() {
+			None => Command::History(false),
+			Some("previous") => Command::History(true),
+			_ => return Err(Error::BadArguments),
+		},
+		Some("version") => Command::Version,
+		Some("frames") => Command::Frames,
+		Some("exit") => Command::Exit,
+		Some("config") => match words.next() {
+			Some("get") => Command::ConfigGet(words.next().map(Setting::from_name).transpose()?),
+			Some("set") => {
+				let setting = Setting::from_name(words.next().ok_or(Error::BadArguments)?)?;
+				let value = parse_number(words.next().ok_or(Error::BadArguments)?)?;
+				if !setting.is_valid(value) {
+					return Err(Error::BadValue);
+				}
+				Command::ConfigSet(setting, value)
+			}
+			Some("save") => Command::ConfigSave,
+			_ => return Err(Error::BadArguments),
+		},
+		_ => return Err(Error::UnknownCommand),
+	};
+	if words.next().is_some() {
+		return Err(Error::BadArguments);
+	}
+	Ok(command)
+}
+
+/// Parse a number, in decimal or (with a `0x` prefix) hex.
+fn parse_number(word: &str) -> Result<u16, Error> {
+	let result = match word.strip_prefix("0x") {
+		Some(hex) => u16::from_str_radix(hex, 16),
+		None => word.parse(),
+	};
+	result.map_err(|_| Error::BadValue)
+}
+
+// ============================================================================
+// Impls
+// ============================================================================
+
+impl Setting {
+	/// All the settings, in the order `config get` shows them.
+	pub const ALL: [Setting; 7] = [
+		Setting::KbShortcuts,
+		Setting::PowerPolicy,
+		Setting::WakeKey,
+		Setting::InterruptControl,
+		Setting::WatchdogAction,
+		Setting::SpeakerFrequency,
+		Setting::SpeakerDutyCycle,
+	];
+
+	/// The name typed into the shell.
+	pub fn name(self) -> &'static str {
+		match self {
+			Setting::KbShortcuts => "kb-shortcuts",
+			Setting::PowerPolicy => "power-policy",
+			Setting::WakeKey => "wake-key",
+			Setting::InterruptControl => "interrupt-control",
+			Setting::WatchdogAction => "watchdog-action",
+			Setting::SpeakerFrequency => "speaker-frequency",
+			Setting::SpeakerDutyCycle => "speaker-duty-cycle",
+		}
+	}
+
+	/// Find a setting by the name typed into the shell.
+	pub fn from_name(name: &str) -> Result<Setting, Error> {
+		Setting::ALL
+			.iter()
+			.find(|s| s.name() == name)
+			.copied()
+			.ok_or(Error::UnknownSetting)
+	}
+
+	/// Does the register accept this value?
+	pub fn is_valid(self, value: u16) -> bool {
+		match self {
+			Setting::InterruptControl => true,
+			Setting::SpeakerFrequency => is_valid_frequency(value),
+			Setting::WatchdogAction => value <= u16::from(host_watchdog::POWER_CYCLE),
+			_ => value <= u16::from(u8::MAX),
+		}
+	}
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn simple_commands() {
+		assert_eq!(parse("help"), Ok(Command::Help));
+		assert_eq!(parse("status"), Ok(Command::Status));
+		assert_eq!(parse("power on"), Ok(Command::PowerOn));
+		assert_eq!(parse("power off"), Ok(Command::PowerOff));
+		assert_eq!(parse("reset"), Ok(Command::Reset));
+		assert_eq!(parse("rails"), Ok(Command::Rails));
+		assert_eq!(parse("log"), Ok(Command::Log));
+		assert_eq!(parse("history"), Ok(Command::History(false)));
+		assert_eq!(parse("history previous"), Ok(Command::History(true)));
+		assert_eq!(parse("version"), Ok(Command::Version));
+		assert_eq!(parse("frames"), Ok(Command::Frames));
+		assert_eq!(parse("exit"), Ok(Command::Exit));
+	}
+
+	#[test]
+	fn whitespace() {
+		assert_eq!(parse("  power \t on  "), Ok(Command::PowerOn));
+		assert_eq!(parse(""), Err(Error::UnknownCommand));
+		assert_eq!(parse("   "), Err(Error::UnknownCommand));
+	}
+
+	#[test]
+	fn bad_commands() {
+		assert_eq!(parse("reboot"), Err(Error::UnknownCommand));
+		assert_eq!(parse("POWER ON"), Err(Error::UnknownCommand));
+		assert_eq!(parse("power"), Err(Error::BadArguments));
+		assert_eq!(parse("power up"), Err(Error::BadArguments));
+		assert_eq!(parse("power on now"), Err(Error::BadArguments));
+		assert_eq!(parse("log 5"), Err(Error::BadArguments));
+		assert_eq!(parse("history next"), Err(Error::BadArguments));
+		assert_eq!(parse("history previous 2"), Err(Error::BadArguments));
+		assert_eq!(parse("config"), Err(Error::BadArguments));
+		assert_eq!(parse("config list"), Err(Error::BadArguments));
+	}
+
+	#[test]
+	fn config_get() {
+		assert_eq!(parse("config get"), Ok(Command::ConfigGet(None)));
+		assert_eq!(
+			parse("config get power-policy"),
+			Ok(Command::ConfigGet(Some(Setting::PowerPolicy)))
+		);
+		assert_eq!(parse("config get colour"), Err(Error::UnknownSetting));
+		assert_eq!(parse("config get wake-key 1"), Err(Error::BadArguments));
+	}
+
+	#[test]
+	fn config_set() {
+		assert_eq!(
+			parse("config set speaker-frequency 20000"),
+			Ok(Command::ConfigSet(Setting::SpeakerFrequency, 20000))
+		);
+		assert_eq!(
+			parse("config set speaker-frequency 0"),
+			Ok(Command::ConfigSet(Setting::SpeakerFrequency, 0))
+		);
+		assert_eq!(
+			parse("config set speaker-frequency 20001"),
+			Err(Error::BadValue)
+		);
+		for hz in 1..=19 {
+			assert_eq!(
+				parse(&format!("config set speaker-frequency {}", hz)),
+				Err(Error::BadValue)
+			);
+		}
+		assert_eq!(
+			parse("config set interrupt-control 0xFFFF"),
+			Ok(Command::ConfigSet(Setting::InterruptControl, 0xFFFF))
+		);
+		assert_eq!(
+			parse("config set kb-shortcuts 255"),
+			Ok(Command::ConfigSet(Setting::KbShortcuts, 255))
+		);
+		assert_eq!(parse("config set kb-shortcuts 256"), Err(Error::BadValue));
+		for action in 0..=2 {
+			assert_eq!(
+				parse(&format!("config set watchdog-action {}", action)),
+				Ok(Command::ConfigSet(Setting::WatchdogAction, action))
+			);
+		}
+		assert_eq!(parse("config set watchdog-action 3"), Err(Error::BadValue));
+		assert_eq!(
+			parse("config set watchdog-action 255"),
+			Err(Error::BadValue)
+		);
+		assert_eq!(parse("config set wake-key 0x"), Err(Error::BadValue));
+		assert_eq!(parse("config set wake-key -1"), Err(Error::BadValue));
+		assert_eq!(parse("config set wake-key"), Err(Error::BadArguments));
+		assert_eq!(parse("config set"), Err(Error::BadArguments));
+		assert_eq!(parse("config set volume 3"), Err(Error::UnknownSetting));
+		assert_eq!(parse("config save"), Ok(Command::ConfigSave));
+	}
+
+	#[test]
+	fn setting_names() {
+		for setting in Setting::ALL {
+			assert_eq!(Setting::from_name(setting.name()), Ok(setting));
+		}
+	}
+}
+
+// ============================================================================
+// End of File
+// ============================================================================